@@ -0,0 +1,15 @@
+CREATE TABLE chain_state
+(
+    currency_id TEXT PRIMARY KEY,
+    payouts_paused BOOLEAN NOT NULL DEFAULT FALSE,
+    paused_reason TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE TRIGGER SET_UPDATED_TIMESTAMP 
+	BEFORE
+	UPDATE
+	    ON chain_state FOR EACH ROW
+	EXECUTE
+	    PROCEDURE trigger_set_timestamp();
@@ -0,0 +1,20 @@
+ALTER TABLE cashbacks ADD COLUMN status TEXT NOT NULL DEFAULT 'pending';
+ALTER TABLE cashbacks ADD COLUMN attempts INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE cashbacks ADD COLUMN last_error TEXT;
+ALTER TABLE cashbacks ADD COLUMN skip_reason TEXT;
+
+-- Without the trigger, so `updated_at` of paid rows keeps the time their txid was stored.
+ALTER TABLE cashbacks DISABLE TRIGGER set_updated_timestamp;
+UPDATE cashbacks SET status = 'paid' WHERE txid IS NOT NULL;
+ALTER TABLE cashbacks ENABLE TRIGGER set_updated_timestamp;
+
+CREATE TABLE audit_log
+(
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v7(),
+    actor TEXT NOT NULL,
+    action TEXT NOT NULL,
+    currency_id TEXT,
+    cashback_id UUID,
+    detail TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
@@ -0,0 +1,16 @@
+-- Replayed or reorged blocks could store an identity more than once before this index existed.
+-- Keep the row that was paid, or else the oldest one.
+DELETE FROM cashbacks
+WHERE id IN (
+    SELECT id
+    FROM (
+        SELECT id, ROW_NUMBER() OVER (
+            PARTITION BY currency_id, name_id
+            ORDER BY (status = 'paid') DESC, created_at, id
+        ) AS rank
+        FROM cashbacks
+    ) AS ranked
+    WHERE rank > 1
+);
+
+CREATE UNIQUE INDEX cashbacks_currency_id_name_id_idx ON cashbacks (currency_id, name_id);
@@ -0,0 +1 @@
+ALTER TABLE chain_state ADD COLUMN last_scanned_height BIGINT;
@@ -0,0 +1,14 @@
+ALTER TABLE cashbacks ADD COLUMN amount BIGINT;
+ALTER TABLE cashbacks ADD COLUMN fee BIGINT;
+ALTER TABLE cashbacks ADD COLUMN payout_currency TEXT;
+ALTER TABLE cashbacks ADD COLUMN registration_txid TEXT;
+ALTER TABLE cashbacks ADD COLUMN detected_block_height BIGINT;
+ALTER TABLE cashbacks ADD COLUMN detected_block_hash TEXT;
+ALTER TABLE cashbacks ADD COLUMN paid_at TIMESTAMPTZ;
+ALTER TABLE cashbacks ADD COLUMN payout_block_height BIGINT;
+
+-- Rows paid before this were last updated when their txid was stored, which is when they were
+-- paid. Without the trigger, so backfilling doesn't move `updated_at` to now.
+ALTER TABLE cashbacks DISABLE TRIGGER set_updated_timestamp;
+UPDATE cashbacks SET paid_at = updated_at WHERE status = 'paid';
+ALTER TABLE cashbacks ENABLE TRIGGER set_updated_timestamp;
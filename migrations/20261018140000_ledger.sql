@@ -0,0 +1,28 @@
+CREATE TABLE ledger_entries
+(
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v7(),
+    currency_id TEXT NOT NULL,
+    -- Entries that are posted together share a transfer id and sum to zero.
+    transfer_id UUID NOT NULL,
+    kind TEXT NOT NULL,
+    account TEXT NOT NULL,
+    amount BIGINT NOT NULL,
+    cashback_id UUID,
+    txid TEXT,
+    note TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX ledger_entries_currency_id_account_idx ON ledger_entries (currency_id, account);
+
+CREATE TABLE reconciliations
+(
+    id UUID PRIMARY KEY DEFAULT uuid_generate_v7(),
+    currency_id TEXT NOT NULL,
+    ledger_balance BIGINT NOT NULL,
+    wallet_balance BIGINT NOT NULL,
+    difference BIGINT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX reconciliations_currency_id_idx ON reconciliations (currency_id, created_at);
@@ -0,0 +1 @@
+ALTER TABLE cashbacks ADD COLUMN opid TEXT;
@@ -0,0 +1,3 @@
+-- The referral identity a registration used. Rows stored before this keep NULL, which referral
+-- they used is no longer known once the config changed.
+ALTER TABLE cashbacks ADD COLUMN referral_id TEXT;
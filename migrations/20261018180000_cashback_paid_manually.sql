@@ -0,0 +1,2 @@
+-- Payouts an operator marked paid by hand, there is no amount, fee or block for them.
+ALTER TABLE cashbacks ADD COLUMN paid_manually BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,76 @@
+-- The schema of the Postgres migrations up to 20261018140000, for the sqlite backend. Ids are
+-- generated by the service and timestamps are written by it, as RFC 3339 text.
+
+CREATE TABLE cashbacks
+(
+    id BLOB PRIMARY KEY,
+    currency_id TEXT NOT NULL,
+    name_str TEXT NOT NULL,
+    name_id TEXT NOT NULL,
+    txid TEXT,
+    status TEXT NOT NULL DEFAULT 'pending',
+    attempts INTEGER NOT NULL DEFAULT 0,
+    last_error TEXT,
+    skip_reason TEXT,
+    amount INTEGER,
+    fee INTEGER,
+    payout_currency TEXT,
+    registration_txid TEXT,
+    detected_block_height INTEGER,
+    detected_block_hash TEXT,
+    paid_at TEXT,
+    payout_block_height INTEGER,
+    created_at TEXT NOT NULL,
+    updated_at TEXT NOT NULL
+);
+
+CREATE UNIQUE INDEX cashbacks_currency_id_name_id_idx ON cashbacks (currency_id, name_id);
+
+CREATE TABLE chain_state
+(
+    currency_id TEXT PRIMARY KEY,
+    payouts_paused BOOLEAN NOT NULL DEFAULT FALSE,
+    paused_reason TEXT,
+    last_scanned_height INTEGER,
+    created_at TEXT NOT NULL,
+    updated_at TEXT NOT NULL
+);
+
+CREATE TABLE audit_log
+(
+    id BLOB PRIMARY KEY,
+    actor TEXT NOT NULL,
+    action TEXT NOT NULL,
+    currency_id TEXT,
+    cashback_id BLOB,
+    detail TEXT,
+    created_at TEXT NOT NULL
+);
+
+CREATE TABLE ledger_entries
+(
+    id BLOB PRIMARY KEY,
+    currency_id TEXT NOT NULL,
+    transfer_id BLOB NOT NULL,
+    kind TEXT NOT NULL,
+    account TEXT NOT NULL,
+    amount INTEGER NOT NULL,
+    cashback_id BLOB,
+    txid TEXT,
+    note TEXT,
+    created_at TEXT NOT NULL
+);
+
+CREATE INDEX ledger_entries_currency_id_account_idx ON ledger_entries (currency_id, account);
+
+CREATE TABLE reconciliations
+(
+    id BLOB PRIMARY KEY,
+    currency_id TEXT NOT NULL,
+    ledger_balance INTEGER NOT NULL,
+    wallet_balance INTEGER NOT NULL,
+    difference INTEGER NOT NULL,
+    created_at TEXT NOT NULL
+);
+
+CREATE INDEX reconciliations_currency_id_idx ON reconciliations (currency_id, created_at);
@@ -0,0 +1,7 @@
+-- When the payout of a cashback was started, to tell its send apart from earlier sends to the
+-- same identity. `updated_at` moves on while the payout is sent, e.g. when its opid is stored.
+ALTER TABLE cashbacks ADD COLUMN sending_at TEXT;
+
+-- When the payouts being sent now started is no longer known, but not before their cashback was
+-- stored.
+UPDATE cashbacks SET sending_at = created_at WHERE status = 'sending';
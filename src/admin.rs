@@ -0,0 +1,446 @@
+//! Operator actions, shared by the Discord commands, the CLI and the admin API. Every action is
+//! written to the audit log together with the actor that performed it.
+
+use std::{fmt, str::FromStr};
+
+use anyhow::{anyhow, Result};
+use tokio_util::sync::CancellationToken;
+use tracing::*;
+use uuid::Uuid;
+use vrsc_rpc::{bitcoin::Txid, json::vrsc::Address};
+
+use crate::{
+    config::pbaas,
+    constants::{AuditAction, Cashback, CashbackStatus},
+    ledger,
+    rescan::{self, RescanReport},
+    storage::Storage,
+};
+
+/// Why an action was refused. Any other error an action returns, e.g. from the database, is not
+/// the operator's doing.
+#[derive(Debug)]
+pub enum Refusal {
+    /// Nothing matches the cashback the operator referred to.
+    NotFound(String),
+    /// The action does not apply to the cashback or chain in the state it is in.
+    Rejected(String),
+}
+
+impl fmt::Display for Refusal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotFound(message) | Self::Rejected(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for Refusal {}
+
+macro_rules! reject {
+    ($($arg:tt)*) => {
+        return Err(Refusal::Rejected(format!($($arg)*)).into())
+    };
+}
+
+/// Refers to a single cashback, either by its row id or by the identity that registered.
+#[derive(Debug, Clone)]
+pub enum CashbackRef {
+    Id(Uuid),
+    NameId(Address),
+}
+
+impl FromStr for CashbackRef {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(id) = Uuid::parse_str(s) {
+            return Ok(Self::Id(id));
+        }
+
+        Address::from_str(s)
+            .map(Self::NameId)
+            .map_err(|_| anyhow!("{} is neither a cashback id nor an identity address", s))
+    }
+}
+
+/// An identity can have a cashback on every chain, it only refers to one when it has a single
+/// cashback.
+pub async fn find_cashback(storage: &dyn Storage, cashback: &CashbackRef) -> Result<Cashback> {
+    let found = match cashback {
+        CashbackRef::Id(id) => storage.get_cashback(id).await?,
+        CashbackRef::NameId(name_id) => {
+            let mut found = storage.get_cashbacks_by_name_id(name_id).await?;
+            if found.len() > 1 {
+                let ids = found
+                    .iter()
+                    .map(|cashback| format!("{} ({})", cashback.id, cashback.currency_id))
+                    .collect::<Vec<_>>();
+                reject!(
+                    "{name_id} has a cashback on more than one chain, use the id of one of {}",
+                    ids.join(", ")
+                );
+            }
+
+            found.pop()
+        }
+    };
+
+    found.ok_or_else(|| Refusal::NotFound(format!("no cashback found for {:?}", cashback)).into())
+}
+
+/// Stops payouts for a chain. Referrals keep being detected and stored, they are paid out once
+/// the chain is resumed.
+pub async fn pause_payouts(
+    storage: &dyn Storage,
+    currency_id: &Address,
+    reason: Option<&str>,
+    actor: &str,
+) -> Result<()> {
+    storage
+        .set_payouts_paused(currency_id, true, reason)
+        .await?;
+    storage
+        .insert_audit(actor, AuditAction::Pause, Some(currency_id), None, reason)
+        .await?;
+    info!("payouts paused for {currency_id} by {actor} (reason: {reason:?})");
+
+    Ok(())
+}
+
+pub async fn resume_payouts(
+    storage: &dyn Storage,
+    currency_id: &Address,
+    actor: &str,
+) -> Result<()> {
+    storage.set_payouts_paused(currency_id, false, None).await?;
+    storage
+        .insert_audit(actor, AuditAction::Resume, Some(currency_id), None, None)
+        .await?;
+    info!("payouts resumed for {currency_id} by {actor}");
+
+    Ok(())
+}
+
+/// Puts a failed, skipped or historical cashback back in the queue, the checker pays it on the
+/// next block.
+pub async fn retry_cashback(
+    storage: &dyn Storage,
+    cashback: &CashbackRef,
+    actor: &str,
+) -> Result<Cashback> {
+    let cashback = find_cashback(storage, cashback).await?;
+
+    if !storage.retry_cashback(&cashback.id).await? {
+        reject!(
+            "cashback {} is {}, only failed, skipped or historical cashbacks can be retried",
+            cashback.id,
+            cashback.status.as_str()
+        );
+    }
+
+    storage
+        .insert_audit(
+            actor,
+            AuditAction::Retry,
+            Some(&cashback.currency_id),
+            Some(&cashback.id),
+            cashback.last_error.as_deref(),
+        )
+        .await?;
+    info!("cashback {} queued for retry by {actor}", cashback.id);
+
+    Ok(cashback)
+}
+
+/// Releases a cashback that a rescan found in blocks from before the service was running for
+/// payout.
+pub async fn approve_cashback(
+    storage: &dyn Storage,
+    cashback: &CashbackRef,
+    actor: &str,
+) -> Result<Cashback> {
+    let cashback = find_cashback(storage, cashback).await?;
+
+    if !storage.approve_cashback(&cashback.id).await? {
+        reject!(
+            "cashback {} is {}, only historical cashbacks can be approved",
+            cashback.id,
+            cashback.status.as_str()
+        );
+    }
+
+    storage
+        .insert_audit(
+            actor,
+            AuditAction::Approve,
+            Some(&cashback.currency_id),
+            Some(&cashback.id),
+            None,
+        )
+        .await?;
+    info!("cashback {} approved by {actor}", cashback.id);
+
+    Ok(cashback)
+}
+
+/// Looks for missed referrals in `from..=to`, see [`rescan::rescan`].
+pub async fn rescan(
+    storage: &dyn Storage,
+    config: pbaas::Config,
+    from: u64,
+    to: u64,
+    historical: bool,
+    actor: &str,
+    shutdown: &CancellationToken,
+) -> Result<RescanReport> {
+    let currency_id = config.currency_id.clone();
+    let detail = format!("blocks {from} to {to}, historical: {historical}");
+
+    storage
+        .insert_audit(
+            actor,
+            AuditAction::Rescan,
+            Some(&currency_id),
+            None,
+            Some(&detail),
+        )
+        .await?;
+    info!("rescan of {currency_id} ({detail}) started by {actor}");
+
+    rescan::rescan(storage, config, from, to, historical, shutdown).await
+}
+
+/// Records funds that were sent to the wallet of a chain from outside, so the ledger keeps
+/// matching the wallet balance.
+pub async fn record_top_up(
+    storage: &dyn Storage,
+    currency_id: &Address,
+    amount: u64,
+    txid: Option<&Txid>,
+    note: Option<&str>,
+    actor: &str,
+) -> Result<Uuid> {
+    if amount == 0 {
+        reject!("a top-up must be more than 0");
+    }
+
+    let transfer_id = ledger::record_top_up(storage, currency_id, amount, txid, note).await?;
+
+    let detail = match txid {
+        Some(txid) => format!("{amount} sats in {txid}"),
+        None => format!("{amount} sats"),
+    };
+    storage
+        .insert_audit(
+            actor,
+            AuditAction::TopUp,
+            Some(currency_id),
+            None,
+            Some(&detail),
+        )
+        .await?;
+    info!("top-up of {detail} to {currency_id} recorded by {actor}");
+
+    Ok(transfer_id)
+}
+
+/// Marks a cashback as paid with a txid of a payout that was made by hand.
+pub async fn mark_paid(
+    storage: &dyn Storage,
+    cashback: &CashbackRef,
+    txid: &Txid,
+    actor: &str,
+) -> Result<Cashback> {
+    let cashback = find_cashback(storage, cashback).await?;
+
+    if !storage.mark_cashback_paid(&cashback.id, txid).await? {
+        // The row may have moved on since it was read, report the state it is in now.
+        let status = storage
+            .get_cashback(&cashback.id)
+            .await?
+            .map_or(cashback.status, |cashback| cashback.status);
+        if status == CashbackStatus::Paid {
+            reject!("cashback {} is already paid", cashback.id);
+        }
+        reject!(
+            "cashback {} is {}, its payout is in flight and can't be marked paid until it is \
+            confirmed or failed",
+            cashback.id,
+            status.as_str()
+        );
+    }
+
+    storage
+        .insert_audit(
+            actor,
+            AuditAction::MarkPaid,
+            Some(&cashback.currency_id),
+            Some(&cashback.id),
+            Some(&txid.to_string()),
+        )
+        .await?;
+    info!("cashback {} marked paid ({txid}) by {actor}", cashback.id);
+
+    Ok(cashback)
+}
+
+pub async fn skip_cashback(
+    storage: &dyn Storage,
+    cashback: &CashbackRef,
+    reason: &str,
+    actor: &str,
+) -> Result<Cashback> {
+    let cashback = find_cashback(storage, cashback).await?;
+
+    if !storage.skip_cashback(&cashback.id, reason).await? {
+        reject!(
+            "cashback {} is {}, paid, sending or broadcast cashbacks can't be skipped",
+            cashback.id,
+            cashback.status.as_str()
+        );
+    }
+
+    storage
+        .insert_audit(
+            actor,
+            AuditAction::Skip,
+            Some(&cashback.currency_id),
+            Some(&cashback.id),
+            Some(reason),
+        )
+        .await?;
+    info!(
+        "cashback {} skipped by {actor} (reason: {reason})",
+        cashback.id
+    );
+
+    Ok(cashback)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        fixtures::{self, alice},
+        mock_rpc,
+        sqlite::Sqlite,
+        storage::Registration,
+    };
+
+    /// Marks the cashback of `alice` paid and returns the refusal.
+    async fn refuse_mark_paid(storage: &Sqlite) -> String {
+        let error = mark_paid(
+            storage,
+            &CashbackRef::NameId(alice()),
+            &mock_rpc::txid(3),
+            "operator",
+        )
+        .await
+        .unwrap_err();
+
+        match error.downcast::<Refusal>().unwrap() {
+            Refusal::Rejected(message) => message,
+            refusal => panic!("unexpected refusal: {refusal:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_to_mark_a_payout_that_is_being_sent_paid() {
+        let storage = Sqlite::in_memory().await.unwrap();
+        let sending = fixtures::sending(&storage).await;
+
+        let message = refuse_mark_paid(&storage).await;
+        assert!(message.contains("is sending"), "{message}");
+
+        let cashback = fixtures::cashback(&storage, &alice()).await;
+        assert_eq!(cashback.status, CashbackStatus::Sending);
+        assert_eq!(cashback.amount, sending.amount);
+        assert!(!cashback.paid_manually);
+    }
+
+    #[tokio::test]
+    async fn refuses_to_mark_a_broadcast_payout_paid() {
+        let storage = Sqlite::in_memory().await.unwrap();
+        let broadcast = fixtures::broadcast(&storage, 2).await;
+
+        let message = refuse_mark_paid(&storage).await;
+        assert!(message.contains("is broadcast"), "{message}");
+
+        let cashback = fixtures::cashback(&storage, &alice()).await;
+        assert_eq!(cashback.status, CashbackStatus::Broadcast);
+        assert_eq!(cashback.txid, broadcast.txid);
+        assert!(!cashback.paid_manually);
+    }
+
+    #[tokio::test]
+    async fn marks_a_failed_payout_paid() {
+        let storage = Sqlite::in_memory().await.unwrap();
+        let sending = fixtures::sending(&storage).await;
+        storage
+            .mark_cashback_failed(&sending.id, "rejected")
+            .await
+            .unwrap();
+
+        mark_paid(
+            &storage,
+            &CashbackRef::NameId(alice()),
+            &mock_rpc::txid(3),
+            "operator",
+        )
+        .await
+        .unwrap();
+
+        let cashback = fixtures::cashback(&storage, &alice()).await;
+        assert_eq!(cashback.status, CashbackStatus::Paid);
+        assert_eq!(cashback.txid, Some(mock_rpc::txid(3)));
+        assert!(cashback.paid_manually);
+
+        let message = refuse_mark_paid(&storage).await;
+        assert!(message.contains("already paid"), "{message}");
+    }
+
+    #[tokio::test]
+    async fn refuses_an_identity_with_a_cashback_on_more_than_one_chain() {
+        let storage = Sqlite::in_memory().await.unwrap();
+        fixtures::store(&storage, &alice(), 1, CashbackStatus::Pending, Some(100)).await;
+
+        let found = find_cashback(&storage, &CashbackRef::NameId(alice()))
+            .await
+            .unwrap();
+        assert_eq!(found.currency_id, fixtures::chain_id());
+
+        let other_chain = fixtures::other_referral_id();
+        storage
+            .store_cashback(
+                &other_chain,
+                &alice(),
+                "alice",
+                CashbackStatus::Pending,
+                Registration {
+                    txid: &mock_rpc::txid(2),
+                    referral_id: &fixtures::referral_id(),
+                    block: None,
+                },
+                &[],
+            )
+            .await
+            .unwrap();
+
+        let error = find_cashback(&storage, &CashbackRef::NameId(alice()))
+            .await
+            .unwrap_err();
+        let Ok(Refusal::Rejected(message)) = error.downcast::<Refusal>() else {
+            panic!("not refused as ambiguous");
+        };
+        for cashback in storage.get_cashbacks(None).await.unwrap() {
+            assert!(message.contains(&cashback.id.to_string()), "{message}");
+        }
+
+        // The id still refers to a single cashback.
+        let by_id = find_cashback(&storage, &CashbackRef::Id(found.id))
+            .await
+            .unwrap();
+        assert_eq!(by_id.currency_id, fixtures::chain_id());
+    }
+}
@@ -0,0 +1,410 @@
+//! REST API for the operator actions in [`admin`], authenticated with the bearer tokens from
+//! `[http.admin_tokens]`.
+
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    extract::{Path, Query, Request, State},
+    http::{
+        header::{AUTHORIZATION, CONTENT_TYPE},
+        StatusCode,
+    },
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Extension, Json, Router,
+};
+use chrono::{DateTime, Utc};
+use secrecy::{ExposeSecret, Secret};
+use serde::{Deserialize, Serialize};
+use tracing::*;
+use uuid::Uuid;
+use vrsc_rpc::{bitcoin::Txid, json::vrsc::Address};
+
+use crate::{
+    admin::{self, CashbackRef, Refusal},
+    api::{page_bounds, parse_currency_id, ApiError, Page},
+    config::pbaas::pbaas_chain_config,
+    constants::{AuditEntry, Cashback},
+    export::{self, Format},
+    http::AppState,
+    storage::PaidRange,
+};
+
+/// Name of the token a request was made with, audited as `api:<name>`.
+#[derive(Debug, Clone)]
+struct Actor(String);
+
+/// Chains with a rescan running, so a chain is never rescanned twice at the same time.
+#[derive(Debug, Clone, Default)]
+pub struct Rescans(Arc<Mutex<HashSet<Address>>>);
+
+impl Rescans {
+    /// Claims `currency_id` until the guard is dropped, or None while it is being rescanned.
+    fn claim(&self, currency_id: &Address) -> Option<RescanGuard> {
+        self.0
+            .lock()
+            .unwrap()
+            .insert(currency_id.clone())
+            .then(|| RescanGuard {
+                rescans: self.clone(),
+                currency_id: currency_id.clone(),
+            })
+    }
+}
+
+struct RescanGuard {
+    rescans: Rescans,
+    currency_id: Address,
+}
+
+impl Drop for RescanGuard {
+    fn drop(&mut self) {
+        self.rescans.0.lock().unwrap().remove(&self.currency_id);
+    }
+}
+
+pub fn router(tokens: HashMap<String, Secret<String>>) -> Router<AppState> {
+    Router::new()
+        .route("/chains/:currency_id/pause", post(pause))
+        .route("/chains/:currency_id/resume", post(resume))
+        .route("/chains/:currency_id/rescan", post(rescan))
+        .route("/chains/:currency_id/top-up", post(top_up))
+        .route("/cashbacks/:cashback/retry", post(retry))
+        .route("/cashbacks/:cashback/skip", post(skip))
+        .route("/cashbacks/:cashback/approve", post(approve))
+        .route("/cashbacks/:cashback/mark-paid", post(mark_paid))
+        .route("/audit", get(audit_log))
+        .route("/export", get(export))
+        .layer(middleware::from_fn_with_state(
+            Arc::new(tokens),
+            authenticate,
+        ))
+}
+
+async fn authenticate(
+    State(tokens): State<Arc<HashMap<String, Secret<String>>>>,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let presented = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or(ApiError::Unauthorized)?;
+
+    let name = tokens
+        .iter()
+        .find(|(_, token)| constant_time_eq(token.expose_secret().as_bytes(), presented.as_bytes()))
+        .map(|(name, _)| name.clone())
+        .ok_or(ApiError::Unauthorized)?;
+
+    request
+        .extensions_mut()
+        .insert(Actor(format!("api:{name}")));
+
+    Ok(next.run(request).await)
+}
+
+/// Compares without returning early, so the time taken does not tell how much of a token
+/// matched.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct PauseBody {
+    reason: Option<String>,
+}
+
+async fn pause(
+    State(state): State<AppState>,
+    Extension(Actor(actor)): Extension<Actor>,
+    Path(currency_id): Path<String>,
+    body: Option<Json<PauseBody>>,
+) -> Result<StatusCode, ApiError> {
+    let currency_id = parse_currency_id(&currency_id)?;
+    let Json(body) = body.unwrap_or_default();
+
+    admin::pause_payouts(
+        state.storage.as_ref(),
+        &currency_id,
+        body.reason.as_deref(),
+        &actor,
+    )
+    .await
+    .map_err(refused)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn resume(
+    State(state): State<AppState>,
+    Extension(Actor(actor)): Extension<Actor>,
+    Path(currency_id): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    let currency_id = parse_currency_id(&currency_id)?;
+
+    admin::resume_payouts(state.storage.as_ref(), &currency_id, &actor)
+        .await
+        .map_err(refused)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Deserialize)]
+struct RescanBody {
+    from: u64,
+    to: u64,
+    #[serde(default)]
+    historical: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct RescanStarted {
+    currency_id: Address,
+    from: u64,
+    to: u64,
+}
+
+/// Starts a rescan in the background, it can take long. The result ends up in the log. Answers
+/// 409 while the chain is being rescanned already.
+async fn rescan(
+    State(state): State<AppState>,
+    Extension(Actor(actor)): Extension<Actor>,
+    Path(currency_id): Path<String>,
+    Json(body): Json<RescanBody>,
+) -> Result<(StatusCode, Json<RescanStarted>), ApiError> {
+    let currency_id = parse_currency_id(&currency_id)?;
+    if body.from > body.to {
+        return Err(ApiError::BadRequest(format!(
+            "from ({}) must not be above to ({})",
+            body.from, body.to
+        )));
+    }
+    let config =
+        pbaas_chain_config(&currency_id).map_err(|e| ApiError::NotFound(format!("{e:#}")))?;
+
+    let guard = state
+        .rescans
+        .claim(&currency_id)
+        .ok_or_else(|| ApiError::Conflict(format!("{currency_id} is being rescanned already")))?;
+
+    let storage = state.storage.clone();
+    let shutdown = state.shutdown.clone();
+    let chain = currency_id.clone();
+    tokio::spawn(async move {
+        let _guard = guard;
+        let result = admin::rescan(
+            storage.as_ref(),
+            config,
+            body.from,
+            body.to,
+            body.historical,
+            &actor,
+            &shutdown,
+        )
+        .await;
+
+        match result {
+            Ok(report) => info!(
+                "rescan of {chain} finished: scanned {} blocks, found {} referral(s), stored {} new",
+                report.blocks, report.found, report.stored
+            ),
+            Err(e) => error!("rescan of {chain} failed: {e:?}"),
+        }
+    });
+
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(RescanStarted {
+            currency_id,
+            from: body.from,
+            to: body.to,
+        }),
+    ))
+}
+
+#[derive(Debug, Deserialize)]
+struct TopUpBody {
+    /// In satoshis.
+    amount: u64,
+    txid: Option<Txid>,
+    note: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct TopUpRecorded {
+    transfer_id: Uuid,
+}
+
+async fn top_up(
+    State(state): State<AppState>,
+    Extension(Actor(actor)): Extension<Actor>,
+    Path(currency_id): Path<String>,
+    Json(body): Json<TopUpBody>,
+) -> Result<(StatusCode, Json<TopUpRecorded>), ApiError> {
+    let currency_id = parse_currency_id(&currency_id)?;
+
+    let transfer_id = admin::record_top_up(
+        state.storage.as_ref(),
+        &currency_id,
+        body.amount,
+        body.txid.as_ref(),
+        body.note.as_deref(),
+        &actor,
+    )
+    .await
+    .map_err(refused)?;
+
+    Ok((StatusCode::CREATED, Json(TopUpRecorded { transfer_id })))
+}
+
+async fn retry(
+    State(state): State<AppState>,
+    Extension(Actor(actor)): Extension<Actor>,
+    Path(cashback): Path<String>,
+) -> Result<Json<Cashback>, ApiError> {
+    let cashback = parse_cashback_ref(&cashback)?;
+
+    admin::retry_cashback(state.storage.as_ref(), &cashback, &actor)
+        .await
+        .map(Json)
+        .map_err(refused)
+}
+
+#[derive(Debug, Deserialize)]
+struct SkipBody {
+    reason: String,
+}
+
+async fn skip(
+    State(state): State<AppState>,
+    Extension(Actor(actor)): Extension<Actor>,
+    Path(cashback): Path<String>,
+    Json(body): Json<SkipBody>,
+) -> Result<Json<Cashback>, ApiError> {
+    let cashback = parse_cashback_ref(&cashback)?;
+
+    admin::skip_cashback(state.storage.as_ref(), &cashback, &body.reason, &actor)
+        .await
+        .map(Json)
+        .map_err(refused)
+}
+
+async fn approve(
+    State(state): State<AppState>,
+    Extension(Actor(actor)): Extension<Actor>,
+    Path(cashback): Path<String>,
+) -> Result<Json<Cashback>, ApiError> {
+    let cashback = parse_cashback_ref(&cashback)?;
+
+    admin::approve_cashback(state.storage.as_ref(), &cashback, &actor)
+        .await
+        .map(Json)
+        .map_err(refused)
+}
+
+#[derive(Debug, Deserialize)]
+struct MarkPaidBody {
+    txid: Txid,
+}
+
+async fn mark_paid(
+    State(state): State<AppState>,
+    Extension(Actor(actor)): Extension<Actor>,
+    Path(cashback): Path<String>,
+    Json(body): Json<MarkPaidBody>,
+) -> Result<Json<Cashback>, ApiError> {
+    let cashback = parse_cashback_ref(&cashback)?;
+
+    admin::mark_paid(state.storage.as_ref(), &cashback, &body.txid, &actor)
+        .await
+        .map(Json)
+        .map_err(refused)
+}
+
+#[derive(Debug, Deserialize)]
+struct AuditParams {
+    currency_id: Option<Address>,
+    /// Starts at 1.
+    page: Option<u32>,
+    per_page: Option<u32>,
+}
+
+async fn audit_log(
+    State(state): State<AppState>,
+    Query(params): Query<AuditParams>,
+) -> Result<Json<Page<AuditEntry>>, ApiError> {
+    let (page, per_page, offset) = page_bounds(params.page, params.per_page);
+
+    let (items, total) = state
+        .storage
+        .list_audit_log(params.currency_id.as_ref(), per_page.into(), offset)
+        .await?;
+
+    Ok(Json(Page {
+        items,
+        page,
+        per_page,
+        total,
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+struct ExportParams {
+    chain: Option<Address>,
+    #[serde(default)]
+    format: Format,
+    /// Only the totals per chain and per referral identity.
+    #[serde(default)]
+    totals: bool,
+    /// Paid at or after this time.
+    from_date: Option<DateTime<Utc>>,
+    /// Paid before this time.
+    to_date: Option<DateTime<Utc>>,
+    /// Paid in this block or later.
+    from_height: Option<u64>,
+    /// Paid before this block.
+    to_height: Option<u64>,
+}
+
+/// Paid cashbacks for accounting, as CSV or JSON.
+async fn export(
+    State(state): State<AppState>,
+    Query(params): Query<ExportParams>,
+) -> Result<Response, ApiError> {
+    let range = PaidRange {
+        from_date: params.from_date,
+        to_date: params.to_date,
+        from_height: params.from_height,
+        to_height: params.to_height,
+    };
+    let rows = export::rows(state.storage.as_ref(), params.chain.as_ref(), &range).await?;
+    let body = export::render(params.format, &rows, params.totals)?;
+    let content_type = match params.format {
+        Format::Csv => "text/csv",
+        Format::Json => "application/json",
+    };
+
+    Ok(([(CONTENT_TYPE, content_type)], body).into_response())
+}
+
+/// Answers the operator's mistakes with 404 or 422, and anything else, e.g. a database that is
+/// down, with 500.
+fn refused(e: anyhow::Error) -> ApiError {
+    match e.downcast::<Refusal>() {
+        Ok(Refusal::NotFound(message)) => ApiError::NotFound(message),
+        Ok(refusal @ Refusal::Rejected(_)) => ApiError::Rejected(refusal.into()),
+        Err(e) => ApiError::Internal(e),
+    }
+}
+
+fn parse_cashback_ref(cashback: &str) -> Result<CashbackRef, ApiError> {
+    CashbackRef::from_str(cashback).map_err(|e| ApiError::BadRequest(format!("{e:#}")))
+}
@@ -0,0 +1,238 @@
+//! Read-only REST API for looking up cashbacks, and the pieces it shares with the admin API.
+
+use std::{collections::BTreeMap, str::FromStr};
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::*;
+use uuid::Uuid;
+use vrsc_rpc::{
+    bitcoin::{BlockHash, Txid},
+    json::vrsc::Address,
+};
+
+use crate::{
+    constants::{Cashback, CashbackStatus},
+    http::AppState,
+    storage::CashbackFilter,
+};
+
+const DEFAULT_PER_PAGE: u32 = 50;
+const MAX_PER_PAGE: u32 = 500;
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/chains/:currency_id/cashbacks", get(list_cashbacks))
+        .route(
+            "/chains/:currency_id/cashbacks/:identity",
+            get(get_cashback),
+        )
+        .route("/stats", get(stats))
+}
+
+/// A cashback as the public API shows it, without the errors and notes that are meant for
+/// operators. The admin API returns the whole [`Cashback`].
+#[derive(Debug, Serialize)]
+struct PublicCashback {
+    id: Uuid,
+    currency_id: Address,
+    name_id: Address,
+    name: String,
+    referral_id: Option<Address>,
+    txid: Option<Txid>,
+    status: CashbackStatus,
+    amount: Option<u64>,
+    fee: Option<u64>,
+    payout_currency: Option<Address>,
+    registration_txid: Option<Txid>,
+    detected_block_height: Option<u64>,
+    detected_block_hash: Option<BlockHash>,
+    paid_at: Option<DateTime<Utc>>,
+    payout_block_height: Option<u64>,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+}
+
+impl From<Cashback> for PublicCashback {
+    fn from(cashback: Cashback) -> Self {
+        Self {
+            id: cashback.id,
+            currency_id: cashback.currency_id,
+            name_id: cashback.name_id,
+            name: cashback.name,
+            referral_id: cashback.referral_id,
+            txid: cashback.txid,
+            status: cashback.status,
+            amount: cashback.amount,
+            fee: cashback.fee,
+            payout_currency: cashback.payout_currency,
+            registration_txid: cashback.registration_txid,
+            detected_block_height: cashback.detected_block_height,
+            detected_block_hash: cashback.detected_block_hash,
+            paid_at: cashback.paid_at,
+            payout_block_height: cashback.payout_block_height,
+            created_at: cashback.created_at,
+            updated_at: cashback.updated_at,
+        }
+    }
+}
+
+/// Looks up a cashback by the identity address or the name of the identity, with or without
+/// the trailing `@`.
+async fn get_cashback(
+    State(state): State<AppState>,
+    Path((currency_id, identity)): Path<(String, String)>,
+) -> Result<Json<PublicCashback>, ApiError> {
+    let currency_id = parse_currency_id(&currency_id)?;
+
+    let cashback = match Address::from_str(&identity) {
+        Ok(name_id) => {
+            state
+                .storage
+                .get_chain_cashback_by_name_id(&currency_id, &name_id)
+                .await?
+        }
+        Err(_) => {
+            let name = identity.strip_suffix('@').unwrap_or(&identity);
+            state
+                .storage
+                .get_cashback_by_name(&currency_id, name)
+                .await?
+        }
+    };
+
+    cashback
+        .map(|cashback| Json(cashback.into()))
+        .ok_or_else(|| ApiError::NotFound(format!("no cashback for {identity} on {currency_id}")))
+}
+
+#[derive(Debug, Deserialize)]
+struct ListParams {
+    status: Option<CashbackStatus>,
+    /// Only cashbacks created at or after this time, RFC 3339.
+    from: Option<DateTime<Utc>>,
+    /// Only cashbacks created before this time, RFC 3339.
+    to: Option<DateTime<Utc>>,
+    /// Starts at 1.
+    page: Option<u32>,
+    per_page: Option<u32>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub page: u32,
+    pub per_page: u32,
+    pub total: i64,
+}
+
+/// The page, page size and offset for the requested page, with defaults and limits applied.
+pub fn page_bounds(page: Option<u32>, per_page: Option<u32>) -> (u32, u32, i64) {
+    let page = page.unwrap_or(1).max(1);
+    let per_page = per_page.unwrap_or(DEFAULT_PER_PAGE).clamp(1, MAX_PER_PAGE);
+
+    (page, per_page, i64::from(page - 1) * i64::from(per_page))
+}
+
+async fn list_cashbacks(
+    State(state): State<AppState>,
+    Path(currency_id): Path<String>,
+    Query(params): Query<ListParams>,
+) -> Result<Json<Page<PublicCashback>>, ApiError> {
+    let currency_id = parse_currency_id(&currency_id)?;
+    let (page, per_page, offset) = page_bounds(params.page, params.per_page);
+
+    let filter = CashbackFilter {
+        status: params.status,
+        from: params.from,
+        to: params.to,
+    };
+
+    let (items, total) = state
+        .storage
+        .list_cashbacks(&currency_id, &filter, per_page.into(), offset)
+        .await?;
+
+    Ok(Json(Page {
+        items: items.into_iter().map(PublicCashback::from).collect(),
+        page,
+        per_page,
+        total,
+    }))
+}
+
+#[derive(Debug, Default, Serialize)]
+struct ChainStats {
+    total: i64,
+    by_status: BTreeMap<&'static str, i64>,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct Stats {
+    total: i64,
+    chains: BTreeMap<String, ChainStats>,
+}
+
+/// Number of cashbacks per chain and status.
+async fn stats(State(state): State<AppState>) -> Result<Json<Stats>, ApiError> {
+    let mut stats = Stats::default();
+
+    for (currency_id, status, count) in state.storage.count_cashbacks_by_status().await? {
+        let chain = stats.chains.entry(currency_id.to_string()).or_default();
+        chain.by_status.insert(status.as_str(), count);
+        chain.total += count;
+        stats.total += count;
+    }
+
+    Ok(Json(stats))
+}
+
+pub fn parse_currency_id(currency_id: &str) -> Result<Address, ApiError> {
+    Address::from_str(currency_id)
+        .map_err(|_| ApiError::BadRequest(format!("{currency_id} is not a valid currency id")))
+}
+
+pub enum ApiError {
+    BadRequest(String),
+    Unauthorized,
+    NotFound(String),
+    /// The request clashes with work that is still running, e.g. a second rescan of a chain.
+    Conflict(String),
+    /// An operator action that was refused, e.g. retrying a cashback that is already paid.
+    Rejected(anyhow::Error),
+    Internal(anyhow::Error),
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(e: anyhow::Error) -> Self {
+        ApiError::Internal(e)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let (status, message) = match self {
+            ApiError::BadRequest(message) => (StatusCode::BAD_REQUEST, message),
+            ApiError::Unauthorized => (StatusCode::UNAUTHORIZED, "invalid token".to_owned()),
+            ApiError::NotFound(message) => (StatusCode::NOT_FOUND, message),
+            ApiError::Conflict(message) => (StatusCode::CONFLICT, message),
+            ApiError::Rejected(e) => (StatusCode::UNPROCESSABLE_ENTITY, format!("{e:#}")),
+            ApiError::Internal(e) => {
+                error!("api: {e:?}");
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "internal error".to_owned(),
+                )
+            }
+        };
+
+        (status, Json(serde_json::json!({ "error": message }))).into_response()
+    }
+}
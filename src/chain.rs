@@ -0,0 +1,112 @@
+//! The calls made to the daemon of a chain, behind a trait so the checker can run against a
+//! scripted chain. [`rpc::Client`](crate::rpc::Client) implements it against a daemon.
+
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use vrsc_rpc::{
+    bitcoin::{BlockHash, Txid},
+    json::vrsc::{Address, Amount},
+};
+
+#[derive(Debug, Clone, Copy)]
+pub struct ChainInfo {
+    pub blocks: u64,
+    pub headers: u64,
+}
+
+/// A transaction, reduced to the identity reservations in its outputs.
+#[derive(Debug, Clone)]
+pub struct Transaction {
+    pub txid: Txid,
+    pub reservations: Vec<IdentityReservation>,
+}
+
+#[derive(Debug, Clone)]
+pub struct IdentityReservation {
+    pub name: String,
+    pub name_id: Address,
+    /// The identity that referred the registration, if any.
+    pub referral: Option<Address>,
+}
+
+/// An output of a `sendcurrency`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Output {
+    pub address: Address,
+    pub amount: Amount,
+}
+
+/// Where an asynchronous wallet operation such as `sendcurrency` is at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Operation {
+    Executing,
+    Success(Txid),
+    /// Failed, with the status the daemon reported.
+    Failed(String),
+}
+
+/// The part of a `gettransaction` result that tells how far a payout is confirmed.
+#[derive(Debug, Clone, Deserialize)]
+pub struct WalletTransaction {
+    pub txid: Txid,
+    /// Negative when the transaction conflicts with one in the chain.
+    pub confirmations: i64,
+    /// The network fee paid by the wallet, in coins. Negative for a send.
+    pub fee: Option<f64>,
+}
+
+/// A transaction of the wallet that sent to an address.
+#[derive(Debug, Clone)]
+pub struct WalletSend {
+    pub txid: Txid,
+    /// Sent to the address.
+    pub amount: Amount,
+    /// When the wallet made the transaction.
+    pub time: DateTime<Utc>,
+}
+
+#[async_trait]
+pub trait ChainRpc: std::fmt::Debug + Send + Sync {
+    async fn blockchain_info(&self) -> Result<ChainInfo>;
+
+    async fn best_block_hash(&self) -> Result<BlockHash>;
+
+    async fn block_hash(&self, height: u64) -> Result<BlockHash>;
+
+    /// The transactions of a block, in order.
+    async fn block(&self, hash: &BlockHash) -> Result<Vec<Transaction>>;
+
+    async fn decode_raw_transaction(&self, raw_tx: &[u8]) -> Result<Transaction>;
+
+    /// Height of the block the identity was last updated in.
+    async fn identity_height(&self, name_id: &Address) -> Result<u64>;
+
+    /// Starts a `sendcurrency` from `from`, an address or `*` for any address of the wallet.
+    /// Returns the id of the operation.
+    async fn send_currency(&self, from: &str, outputs: &[Output]) -> Result<String>;
+
+    /// `None` when the daemon does not know the operation.
+    async fn operation_status(&self, opid: &str) -> Result<Option<Operation>>;
+
+    async fn wallet_transaction(&self, txid: &Txid) -> Result<WalletTransaction>;
+
+    /// Transactions of the wallet that sent to `address`, leaving out the ones that were
+    /// abandoned or conflicted.
+    async fn wallet_sends(&self, address: &Address) -> Result<Vec<WalletSend>>;
+
+    /// Whether the wallet holds the keys to spend the funds of `identity`.
+    async fn can_spend_for(&self, identity: &Address) -> Result<bool>;
+
+    /// Marks a wallet transaction that is neither in a block nor in the mempool as abandoned, so
+    /// the wallet can spend its inputs again. Fails for any other transaction.
+    async fn abandon_transaction(&self, txid: &Txid) -> Result<()>;
+
+    /// Whether the daemon knows the transaction, in a block or in the mempool.
+    async fn has_transaction(&self, txid: &Txid) -> Result<bool>;
+
+    /// Balance of `address`, or of the whole wallet, including unconfirmed transactions, in
+    /// satoshis.
+    async fn wallet_balance(&self, address: Option<&str>) -> Result<i64>;
+}
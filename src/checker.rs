@@ -0,0 +1,1364 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use chrono::Utc;
+use tokio::{
+    sync::{mpsc, watch},
+    task::JoinSet,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::*;
+use vrsc_rpc::{
+    bitcoin::{BlockHash, Txid},
+    json::vrsc::{Address, Amount},
+};
+
+use crate::{
+    chain::{ChainRpc, IdentityReservation, Operation, Output, WalletTransaction},
+    config::pbaas,
+    constants::{Cashback, CashbackStatus},
+    discord::DiscordMessage,
+    health::{DaemonHealth, DaemonStatus},
+    ledger,
+    readiness::Readiness,
+    rpc,
+    storage::{Payout, Registration, Storage},
+    telemetry,
+    zmq::{self, BlockSource, ZMQMessage},
+};
+
+/// How often the ledger is compared with the wallet balance.
+const RECONCILE_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Transactions expire from the mempool 20 blocks after they were made by default, a registration
+/// that was not mined within this many blocks after it was seen is not going to be.
+const MEMPOOL_EXPIRY_BLOCKS: u64 = 40;
+
+/// How long a `sendcurrency` gets to finish before its outcome counts as unknown.
+pub const SEND_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// How long a payout whose outcome is unknown is looked for in the wallet before it counts as
+/// not sent. A `sendcurrency` that timed out may still be queued on the daemon.
+const SEND_GRACE: Duration = Duration::from_secs(600);
+
+#[allow(unused)]
+#[derive(Debug)]
+pub struct CashbackChecker {
+    storage: Arc<dyn Storage>,
+    currency_id: Address,
+    rpc: Arc<dyn ChainRpc>,
+    referral_id: Address,
+    explorer_url: String,
+    /// Confirmations a payout needs before it is paid and announced.
+    payout_confirmations: u64,
+    /// Address the payouts are sent from, any address of the wallet when not set.
+    payout_address: Option<String>,
+    /// When the ledger was last reconciled with the wallet.
+    reconciled_at: Option<Instant>,
+    /// The config of the chain, which is updated when it is reloaded. Only `referral_amount` and
+    /// `fee` are read from it, other changes restart the checker.
+    config: watch::Receiver<pbaas::Config>,
+    rx: mpsc::Receiver<ZMQMessage>,
+    tx: mpsc::Sender<DiscordMessage>,
+    health: DaemonHealth,
+    /// Height of the last block that was scanned for referrals.
+    cursor: Option<u64>,
+    /// Set when registrations should be detected while they are in the mempool.
+    raw_tx_url: Option<String>,
+    /// After how long a registration that was seen in the mempool but not mined is forgotten.
+    mempool_expiry: Duration,
+    /// Cancelled on shutdown, the checker stops after the payout it is busy with.
+    shutdown: CancellationToken,
+    readiness: Readiness,
+}
+
+impl CashbackChecker {
+    pub fn new(
+        storage: Arc<dyn Storage>,
+        rpc: Arc<dyn ChainRpc>,
+        config: watch::Receiver<pbaas::Config>,
+        rx: mpsc::Receiver<ZMQMessage>,
+        tx: mpsc::Sender<DiscordMessage>,
+        readiness: Readiness,
+    ) -> Self {
+        let current = config.borrow().clone();
+
+        let raw_tx_url = current
+            .mempool_detection
+            .then(|| current.zmq_raw_tx_url.clone())
+            .flatten();
+
+        Self {
+            storage,
+            currency_id: current.currency_id.clone(),
+            rpc,
+            referral_id: current.referral_currency_id,
+            explorer_url: current.explorer_url,
+            payout_confirmations: current.payout_confirmations,
+            payout_address: current.payout_address.clone(),
+            reconciled_at: None,
+            config,
+            rx,
+            tx,
+            health: DaemonHealth::new(current.currency_id),
+            cursor: None,
+            raw_tx_url,
+            mempool_expiry: Duration::from_secs(current.block_time_secs * MEMPOOL_EXPIRY_BLOCKS),
+            shutdown: CancellationToken::new(),
+            readiness,
+        }
+    }
+
+    /// Runs until the checker or one of its listeners fails, or until `shutdown` is cancelled.
+    /// The listeners are stopped when this returns, so a restarted checker comes with fresh
+    /// listeners.
+    #[instrument(level = "trace", skip(self, tx, source, shutdown), fields(chain = self.currency_id.to_string()))]
+    pub async fn run(
+        mut self,
+        tx: mpsc::Sender<ZMQMessage>,
+        source: BlockSource,
+        shutdown: CancellationToken,
+    ) -> Result<()> {
+        self.shutdown = shutdown.clone();
+        let mut listeners = JoinSet::new();
+
+        // Spawn a listener for block notifications
+        listeners.spawn(zmq::listen(
+            tx.clone(),
+            source,
+            self.currency_id.clone(),
+            self.rpc.clone(),
+            shutdown.clone(),
+        ));
+
+        if let Some(url) = self.raw_tx_url.clone() {
+            let chain = self.currency_id.clone();
+            let shutdown = shutdown.clone();
+            listeners.spawn(async move {
+                zmq::listen_tx_notifications(tx, &url, chain, shutdown).await
+            });
+        }
+
+        self.cursor = self.storage.get_scan_cursor(&self.currency_id).await?;
+
+        // Catch up with the blocks that were mined while the service was down
+        self.on_new_tip().await;
+
+        // Receive messages from ZMQ. Messages are handled to completion, so a payout in flight
+        // is finished and recorded before a shutdown is noticed.
+        loop {
+            tokio::select! {
+                biased;
+
+                _ = shutdown.cancelled() => {
+                    info!("shutting down");
+                    return Ok(());
+                }
+                message = self.rx.recv() => match message {
+                    Some(message) => self.handle_messages(message).await,
+                    None => bail!("all listeners stopped"),
+                },
+                Some(listener) = listeners.join_next() => {
+                    listener?.context("listener failed")?;
+                    bail!("listener stopped");
+                }
+            }
+        }
+    }
+
+    async fn handle_messages(&mut self, message: ZMQMessage) {
+        telemetry::set_zmq_queue_depth(&self.currency_id, self.rx.len());
+
+        // Only the tip matters for block notifications, the cursor backfills the blocks in
+        // between, so everything that queued up is handled by a single catch up.
+        let mut new_tip = false;
+        let mut next = Some(message);
+
+        while let Some(message) = next {
+            match message {
+                ZMQMessage::NewBlock(block_hash) => {
+                    debug!("new block {block_hash}");
+                    self.readiness.zmq_message(&self.currency_id);
+                    new_tip = true;
+                }
+                ZMQMessage::Stale => {
+                    debug!("block notifications stalled, checking the tip");
+                    new_tip = true;
+                }
+                ZMQMessage::RawTx(raw_tx) => {
+                    self.readiness.zmq_message(&self.currency_id);
+
+                    // Best effort, the registration is picked up again once it is mined.
+                    if let Err(e) = self.scan_mempool_tx(raw_tx).await {
+                        debug!("failed to check mempool transaction: {e:#}");
+                    }
+                }
+            }
+
+            next = self.rx.try_recv().ok();
+        }
+
+        if new_tip {
+            self.on_new_tip().await;
+        }
+    }
+
+    async fn on_new_tip(&mut self) {
+        if !self.health.allows_request() {
+            trace!("backing off, not checking the tip");
+            return;
+        }
+
+        match self.catch_up().await {
+            Ok(()) => self.health.record_success(),
+            Err(e) => self.health.record_failure(&e),
+        }
+
+        self.readiness.set_daemon(
+            &self.currency_id,
+            self.health.status(),
+            !self.health.allows_request(),
+        );
+    }
+
+    /// Scans every block between the cursor and the daemon's tip, then pays out what is due.
+    #[instrument(level = "trace", skip(self))]
+    async fn catch_up(&mut self) -> Result<()> {
+        let tip = match self.health.check(self.rpc.as_ref()).await? {
+            DaemonStatus::Synced { blocks } | DaemonStatus::Syncing { blocks, .. } => blocks,
+            DaemonStatus::Unreachable => bail!("daemon is unreachable"),
+            DaemonStatus::WarmingUp => return Ok(()),
+        };
+
+        // Without a cursor, start at the tip; older blocks can be rescanned from the CLI.
+        let from = self.cursor.map_or(tip, |cursor| cursor + 1);
+
+        for height in from..=tip {
+            if self.shutdown.is_cancelled() {
+                return Ok(());
+            }
+
+            let block_hash = self.rpc.block_hash(height).await?;
+
+            self.scan_block(height, block_hash).await?;
+
+            self.storage
+                .set_scan_cursor(&self.currency_id, height)
+                .await?;
+            self.cursor = Some(height);
+            telemetry::block_scanned(&self.currency_id, height);
+        }
+
+        self.expire_mempool_cashbacks().await?;
+
+        // Confirmations are counted from the daemon's height, which is meaningless while it is
+        // still syncing.
+        match self.health.status() {
+            DaemonStatus::Synced { blocks } => {
+                self.track_sending().await?;
+                self.track_payouts(blocks).await?;
+                self.reconcile_if_due().await;
+                self.process_pending(blocks).await
+            }
+            status => {
+                debug!("not processing pending cashbacks, daemon is {status:?}");
+                Ok(())
+            }
+        }
+    }
+
+    #[instrument(level = "trace", skip(self))]
+    async fn scan_block(&self, height: u64, block_hash: BlockHash) -> Result<()> {
+        debug!("getting block for blockhash {}", block_hash);
+
+        for tx in self.rpc.block(&block_hash).await? {
+            let registration = Registration {
+                txid: &tx.txid,
+                referral_id: &self.referral_id,
+                block: Some((height, &block_hash)),
+            };
+
+            for reservation in &tx.reservations {
+                if self.tx_has_referral(reservation, registration).await? {
+                    // store tx in database
+                    // send message to discord
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Forgets registrations that were seen in the mempool but were dropped or replaced instead of
+    /// mined. If one is mined after all, it is stored again when its block is scanned.
+    async fn expire_mempool_cashbacks(&self) -> Result<()> {
+        let seen_before = Utc::now() - chrono::Duration::from_std(self.mempool_expiry)?;
+        let expired = self
+            .storage
+            .delete_stale_mempool_cashbacks(&self.currency_id, seen_before)
+            .await?;
+
+        if expired > 0 {
+            info!("forgot {expired} registrations that left the mempool without being mined");
+        }
+
+        Ok(())
+    }
+
+    /// Announces registrations that use our referral as soon as they enter the mempool. They are
+    /// stored as `mempool` and only become payable once a block with them is scanned.
+    #[instrument(level = "trace", skip(self, raw_tx))]
+    async fn scan_mempool_tx(&self, raw_tx: Vec<u8>) -> Result<()> {
+        let tx = self.rpc.decode_raw_transaction(&raw_tx).await?;
+
+        for reservation in &tx.reservations {
+            if let Some((name, name_id)) = find_referral(reservation, &self.referral_id) {
+                let stored = self
+                    .storage
+                    .store_cashback(
+                        &self.currency_id,
+                        &name_id,
+                        &name,
+                        CashbackStatus::Mempool,
+                        Registration {
+                            txid: &tx.txid,
+                            referral_id: &self.referral_id,
+                            block: None,
+                        },
+                        // The referral reward is counted once the registration is mined.
+                        &[],
+                    )
+                    .await?;
+
+                if stored {
+                    debug!(
+                        "registration of {name}@ ({name_id}) seen in mempool tx {}",
+                        tx.txid
+                    );
+
+                    self.notify(DiscordMessage::RegistrationSeen(
+                        self.currency_id.clone(),
+                        (name, name_id),
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    #[instrument(level = "trace", skip(self, reservation))]
+    async fn tx_has_referral(
+        &self,
+        reservation: &IdentityReservation,
+        registration: Registration<'_>,
+    ) -> Result<bool> {
+        if let Some((name, name_id)) = find_referral(reservation, &self.referral_id) {
+            let referral_amount = self.config.borrow().referral_amount;
+            let entries =
+                ledger::referral_reward_entries(referral_amount, self.referral_in_wallet().await?);
+
+            let stored = self
+                .storage
+                .store_cashback(
+                    &self.currency_id,
+                    &name_id,
+                    &name,
+                    CashbackStatus::Pending,
+                    registration,
+                    &entries,
+                )
+                .await?;
+
+            if !stored {
+                debug!("{name}@ ({name_id}) is already known");
+                return Ok(false);
+            }
+
+            telemetry::referral_detected(&self.currency_id);
+
+            self.notify(DiscordMessage::CashbackInitiated(
+                self.currency_id.clone(),
+                (name, name_id),
+            ));
+
+            return Ok(true);
+        }
+
+        Ok(false)
+    }
+
+    /// Finds out whether the payouts that were left in `sending`, because their outcome was not
+    /// known or the service stopped while sending them, were sent. They can't be retried until
+    /// they are known not to have been, the wallet may still broadcast them.
+    #[instrument(level = "trace", skip(self))]
+    async fn track_sending(&self) -> Result<()> {
+        for cashback in self.storage.get_sending_payouts(&self.currency_id).await? {
+            if let Err(e) = self.resolve_sending(&cashback).await {
+                warn!(
+                    "failed to find out whether the payout of cashback {} was sent: {e:#}",
+                    cashback.id
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn resolve_sending(&self, cashback: &Cashback) -> Result<()> {
+        if let Some(opid) = &cashback.opid {
+            match self.rpc.operation_status(opid).await? {
+                Some(Operation::Executing) => return Ok(()),
+                Some(Operation::Success(txid)) => return self.payout_sent(cashback, &txid).await,
+                Some(Operation::Failed(status)) => {
+                    let error = format!("sendcurrency operation {opid} ended with status {status}");
+                    return self.payout_failed(cashback, &error).await;
+                }
+                // The daemon forgets its operations when it restarts, the wallet keeps the
+                // transaction.
+                None => {}
+            }
+        }
+
+        // Earlier sends to the identity, by hand or for an earlier cashback, are not this payout.
+        // The wallet only has the time of a transaction to the second.
+        let since = cashback
+            .sending_at
+            .unwrap_or(cashback.created_at)
+            .timestamp();
+        let sent = self
+            .rpc
+            .wallet_sends(&cashback.name_id)
+            .await?
+            .into_iter()
+            .filter(|send| {
+                send.time.timestamp() >= since && Some(send.amount.to_sat()) == cashback.amount
+            })
+            .max_by_key(|send| send.time);
+        if let Some(send) = sent {
+            return self.payout_sent(cashback, &send.txid).await;
+        }
+
+        let age = Utc::now().signed_duration_since(cashback.updated_at);
+        if age.num_seconds() < SEND_GRACE.as_secs() as i64 {
+            return Ok(());
+        }
+
+        self.payout_failed(cashback, "payout was not found in the wallet")
+            .await
+    }
+
+    /// Follows the payouts that have been sent until they have `payout_confirmations`, then
+    /// marks them paid and announces them. Dropped or conflicted payouts are marked failed so an
+    /// operator can retry them.
+    #[instrument(level = "trace", skip(self))]
+    async fn track_payouts(&self, tip: u64) -> Result<()> {
+        for cashback in self
+            .storage
+            .get_broadcast_payouts(&self.currency_id)
+            .await?
+        {
+            let Some(txid) = cashback.txid else {
+                continue;
+            };
+
+            // A payout that can't be looked up is left alone, it is not known to be dropped.
+            let payout = match self.payout_transaction(&txid).await {
+                Ok(payout) => payout,
+                Err(e) => {
+                    warn!(
+                        "failed to look up payout {txid} of cashback {}: {e:#}",
+                        cashback.id
+                    );
+                    continue;
+                }
+            };
+
+            match payout {
+                // The wallet would still count the inputs of the payout as spent and could
+                // rebroadcast it, so it has to give up on it before the cashback is paid again.
+                None => match self.rpc.abandon_transaction(&txid).await {
+                    Ok(()) => {
+                        self.payout_dropped(&cashback, &txid, "not in a block or the mempool")
+                            .await?
+                    }
+                    Err(e) => warn!(
+                        "payout {txid} of cashback {} is not in a block or the mempool, but the \
+                        wallet did not abandon it: {e:#}",
+                        cashback.id
+                    ),
+                },
+                // The conflicting transaction could still be reorged out, which brings the
+                // payout back.
+                Some(payout)
+                    if payout.confirmations < 0
+                        && payout.confirmations.unsigned_abs() >= self.payout_confirmations =>
+                {
+                    self.payout_dropped(&cashback, &txid, "conflicted").await?
+                }
+                Some(payout) if payout.confirmations < 0 => trace!(
+                    "payout {txid} conflicts with a transaction that has {}/{} confirmations",
+                    -payout.confirmations,
+                    self.payout_confirmations
+                ),
+                Some(payout) if payout.confirmations as u64 >= self.payout_confirmations => {
+                    let height = (tip + 1).saturating_sub(payout.confirmations as u64);
+                    self.payout_confirmed(&cashback, &payout, height).await?;
+                }
+                Some(payout) => trace!(
+                    "payout {txid} has {}/{} confirmations",
+                    payout.confirmations,
+                    self.payout_confirmations
+                ),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Compares the ledger with the wallet every `RECONCILE_INTERVAL` and flags when the
+    /// difference between them changes.
+    async fn reconcile_if_due(&mut self) {
+        if self
+            .reconciled_at
+            .is_some_and(|at| at.elapsed() < RECONCILE_INTERVAL)
+        {
+            return;
+        }
+
+        let reconciliation = match ledger::reconcile(
+            self.storage.as_ref(),
+            self.rpc.as_ref(),
+            &self.currency_id,
+            self.payout_address.as_deref(),
+        )
+        .await
+        {
+            Ok(Some(reconciliation)) => reconciliation,
+            Ok(None) => {
+                trace!("payouts or registrations are in flight, not reconciling");
+                return;
+            }
+            Err(e) => {
+                warn!("failed to reconcile the ledger: {e:#}");
+                return;
+            }
+        };
+        self.reconciled_at = Some(Instant::now());
+
+        telemetry::set_ledger_difference(&self.currency_id, reconciliation.difference);
+        if !reconciliation.changed {
+            return;
+        }
+
+        if reconciliation.difference == 0 {
+            info!("ledger matches the wallet balance");
+        } else {
+            warn!(
+                "ledger ({}) differs from the wallet balance ({}) by {}",
+                reconciliation.ledger_balance,
+                reconciliation.wallet_balance,
+                reconciliation.difference
+            );
+        }
+
+        self.notify(DiscordMessage::LedgerDifference(
+            self.currency_id.clone(),
+            reconciliation.ledger_balance,
+            reconciliation.wallet_balance,
+        ));
+    }
+
+    async fn referral_in_wallet(&self) -> Result<bool> {
+        ledger::referral_in_wallet(
+            self.rpc.as_ref(),
+            &self.referral_id,
+            self.payout_address.as_deref(),
+        )
+        .await
+    }
+
+    /// The wallet transaction of a payout, `None` when the daemon has dropped it.
+    async fn payout_transaction(&self, txid: &Txid) -> Result<Option<WalletTransaction>> {
+        let tx = self.rpc.wallet_transaction(txid).await?;
+
+        if tx.confirmations != 0 {
+            return Ok(Some(tx));
+        }
+
+        // Unconfirmed, the wallet keeps it even when the mempool no longer has it.
+        Ok(self.rpc.has_transaction(txid).await?.then_some(tx))
+    }
+
+    async fn payout_confirmed(
+        &self,
+        cashback: &Cashback,
+        payout: &WalletTransaction,
+        height: u64,
+    ) -> Result<()> {
+        // The fee the wallet reports is negative, for a send.
+        let network_fee = payout
+            .fee
+            .and_then(|fee| Amount::from_btc(-fee).ok())
+            .map_or(ledger::NETWORK_FEE, |fee| fee.to_sat());
+        let entries =
+            ledger::payout_entries(cashback, network_fee, self.referral_in_wallet().await?);
+
+        if !self
+            .storage
+            .confirm_payout(cashback, height, &entries)
+            .await?
+        {
+            return Ok(());
+        }
+
+        let txid = payout.txid;
+        debug!(
+            "payout {txid} of cashback {} confirmed at height {height}",
+            cashback.id
+        );
+        telemetry::cashback_paid(&self.currency_id, cashback.amount.unwrap_or_default());
+
+        self.notify(DiscordMessage::CashbackProcessed(
+            self.currency_id.clone(),
+            (cashback.name.clone(), cashback.name_id.clone()),
+            format!("{}{}", self.explorer_url, txid),
+        ));
+
+        Ok(())
+    }
+
+    async fn payout_dropped(&self, cashback: &Cashback, txid: &Txid, reason: &str) -> Result<()> {
+        let error = format!("payout {txid} was dropped: {reason}");
+        if !self
+            .storage
+            .mark_payout_dropped(&cashback.id, &error)
+            .await?
+        {
+            return Ok(());
+        }
+
+        warn!("cashback {}: {error}", cashback.id);
+        telemetry::payout_failed(&self.currency_id);
+
+        self.notify(DiscordMessage::PayoutDropped(
+            self.currency_id.clone(),
+            (cashback.name.clone(), cashback.name_id.clone()),
+            reason.to_owned(),
+        ));
+
+        Ok(())
+    }
+
+    #[instrument(level = "trace", skip(self))]
+    async fn process_pending(&self, blockheight: u64) -> Result<()> {
+        let pending = self
+            .storage
+            .get_pending_cashbacks(&self.currency_id)
+            .await?;
+        telemetry::set_pending_cashbacks(&self.currency_id, pending.len());
+
+        if self.storage.payouts_paused(&self.currency_id).await? {
+            debug!("payouts are paused, not processing pending cashbacks");
+            return Ok(());
+        }
+
+        debug!("{pending:#?}");
+
+        for cashback in pending {
+            if self.shutdown.is_cancelled() {
+                debug!("shutting down, leaving the remaining cashbacks for the next start");
+                break;
+            }
+
+            // One identity that can't be looked up doesn't hold up the payouts of the others.
+            let identity_height = match self.rpc.identity_height(&cashback.name_id).await {
+                Ok(height) => height,
+                Err(e) => {
+                    warn!(
+                        "failed to look up the identity of cashback {}: {e:#}",
+                        cashback.id
+                    );
+                    continue;
+                }
+            };
+
+            // The tip the checker works from may be behind the height the daemon reports the
+            // identity at.
+            if blockheight.saturating_sub(identity_height) < 10 {
+                // wait 10 confirmations until payment
+                continue;
+            }
+
+            // Failures of the payout itself are recorded on the cashback, what is left are
+            // failures to record them.
+            if let Err(e) = self.pay_cashback(&cashback).await {
+                error!("payout for cashback {} failed: {e:?}", cashback.id);
+                telemetry::payout_failed(&self.currency_id);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sends the payout of a cashback. The cashback is moved to `sending` first, so a payout
+    /// whose outcome is not known is never sent again.
+    #[instrument(level = "trace", skip(self, cashback), fields(cashback = %cashback.id))]
+    async fn pay_cashback(&self, cashback: &Cashback) -> Result<()> {
+        let (referral_amount, fee) = {
+            let config = self.config.borrow();
+            (config.referral_amount, config.fee)
+        };
+
+        let payout = Payout {
+            amount: referral_amount - fee,
+            fee,
+            currency: &self.currency_id,
+        };
+        if !self.storage.start_payout(&cashback.id, payout).await? {
+            return Ok(());
+        }
+
+        let outputs = [
+            Output {
+                address: cashback.name_id.clone(),
+                amount: Amount::from_sat(referral_amount - fee),
+            },
+            Output {
+                address: self.referral_id.clone(),
+                amount: Amount::from_sat(fee - ledger::NETWORK_FEE),
+            },
+        ];
+
+        let from = self.payout_address.as_deref().unwrap_or("*");
+        let opid = match self.rpc.send_currency(from, &outputs).await {
+            Ok(opid) => opid,
+            // The daemon rejected it, nothing was sent.
+            Err(e) if rpc::is_rpc_error(&e) => {
+                return self.payout_failed(cashback, &format!("{e:#}")).await
+            }
+            // The daemon may have taken it before the call failed.
+            Err(e) => return self.payout_unknown(cashback, &format!("{e:#}")).await,
+        };
+        self.storage.set_payout_opid(&cashback.id, &opid).await?;
+
+        match wait_for_sendcurrency_finish(self.rpc.as_ref(), &opid).await {
+            SendOutcome::Sent(txid) => self.payout_sent(cashback, &txid).await,
+            SendOutcome::Failed(error) => self.payout_failed(cashback, &error).await,
+            SendOutcome::Unknown(error) => self.payout_unknown(cashback, &error).await,
+        }
+    }
+
+    async fn payout_sent(&self, cashback: &Cashback, txid: &Txid) -> Result<()> {
+        if self.storage.update_cashback(&cashback.id, txid).await? {
+            debug!("payout {txid} of cashback {} broadcast", cashback.id);
+        }
+
+        Ok(())
+    }
+
+    /// Records a payout that is known not to have been sent, an operator can retry it.
+    async fn payout_failed(&self, cashback: &Cashback, error: &str) -> Result<()> {
+        warn!("payout of cashback {} failed: {error}", cashback.id);
+        telemetry::payout_failed(&self.currency_id);
+
+        self.storage.mark_cashback_failed(&cashback.id, error).await
+    }
+
+    /// Keeps a payout whose outcome is not known in `sending`, until [`Self::track_sending`]
+    /// finds out.
+    async fn payout_unknown(&self, cashback: &Cashback, error: &str) -> Result<()> {
+        warn!(
+            "not known whether the payout of cashback {} was sent: {error}",
+            cashback.id
+        );
+        telemetry::payout_failed(&self.currency_id);
+
+        self.storage
+            .record_payout_error(&cashback.id, error)
+            .await?;
+
+        self.notify(DiscordMessage::PayoutUnknown(
+            self.currency_id.clone(),
+            (cashback.name.clone(), cashback.name_id.clone()),
+            error.to_owned(),
+        ));
+
+        Ok(())
+    }
+
+    /// Queues a message for Discord. When Discord can't keep up the message is dropped, so a
+    /// Discord outage never holds up detection or payouts.
+    fn notify(&self, message: DiscordMessage) {
+        match self.tx.try_send(message) {
+            Ok(()) => {}
+            Err(mpsc::error::TrySendError::Full(message)) => {
+                warn!("discord queue is full, dropping {message:?}");
+                telemetry::discord_message_dropped();
+            }
+            Err(mpsc::error::TrySendError::Closed(message)) => {
+                warn!("discord is not running, dropping {message:?}");
+                telemetry::discord_message_dropped();
+            }
+        }
+    }
+}
+
+/// Returns the name and identity address of an identity reservation that used `referral_id` as
+/// its referral.
+pub fn find_referral(
+    reservation: &IdentityReservation,
+    referral_id: &Address,
+) -> Option<(String, Address)> {
+    debug!("{reservation:#?}");
+
+    if reservation.referral.as_ref() == Some(referral_id) {
+        trace!("referral used");
+
+        return Some((reservation.name.clone(), reservation.name_id.clone()));
+    }
+
+    None
+}
+
+/// How a `sendcurrency` ended.
+#[derive(Debug)]
+enum SendOutcome {
+    Sent(Txid),
+    /// The operation failed, nothing was sent.
+    Failed(String),
+    /// The status of the operation could not be read, it may still have been sent.
+    Unknown(String),
+}
+
+/// Polls the operation of a `sendcurrency` until it finishes, or until `SEND_TIMEOUT` has
+/// passed.
+async fn wait_for_sendcurrency_finish(rpc: &dyn ChainRpc, opid: &str) -> SendOutcome {
+    let deadline = Instant::now() + SEND_TIMEOUT;
+
+    loop {
+        let delay = match rpc.operation_status(opid).await {
+            Ok(Some(Operation::Executing)) => {
+                trace!("opid still executing");
+                Duration::from_millis(100)
+            }
+            Ok(Some(Operation::Success(txid))) => {
+                trace!("operation {opid} was executed");
+
+                return SendOutcome::Sent(txid);
+            }
+            Ok(Some(Operation::Failed(status))) => {
+                return SendOutcome::Failed(format!(
+                    "sendcurrency operation {opid} ended with status {status}"
+                ))
+            }
+            Ok(None) => {
+                trace!("operation {opid} is not known to the daemon");
+                Duration::from_secs(1)
+            }
+            Err(e) => {
+                return SendOutcome::Unknown(format!(
+                    "failed to read the status of operation {opid}: {e:#}"
+                ))
+            }
+        };
+
+        if Instant::now() + delay > deadline {
+            return SendOutcome::Unknown(format!(
+                "operation {opid} did not finish within {SEND_TIMEOUT:?}"
+            ));
+        }
+
+        tokio::time::sleep(delay).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::DateTime;
+
+    use super::*;
+    use crate::{
+        chain::Transaction,
+        fixtures::{self, chain_id, other_referral_id, referral_id, FEE, REFERRAL_AMOUNT},
+        mock_rpc::{self, MockChain},
+        sqlite::Sqlite,
+    };
+
+    const START: u64 = 1000;
+    const EXPLORER_URL: &str = "https://explorer.example/tx/";
+
+    /// Registers `alice` with our referral.
+    fn alice() -> Transaction {
+        mock_rpc::registration(1, "alice", &fixtures::alice(), Some(&referral_id()))
+    }
+
+    /// A checker on a scripted chain and an in-memory database, with the Discord messages it
+    /// sends.
+    struct Harness {
+        checker: CashbackChecker,
+        chain: Arc<MockChain>,
+        storage: Arc<Sqlite>,
+        discord: mpsc::Receiver<DiscordMessage>,
+    }
+
+    impl Harness {
+        /// Starts at a chain with a single block at `START`, which has been scanned.
+        async fn new() -> Self {
+            let config: pbaas::Config = serde_json::from_value(serde_json::json!({
+                "currency_id": chain_id().to_string(),
+                "referral_currency_id": referral_id().to_string(),
+                "explorer_url": EXPLORER_URL,
+                "referral_amount": REFERRAL_AMOUNT,
+                "fee": FEE,
+                "payout_confirmations": 2,
+            }))
+            .unwrap();
+            let (_, config) = watch::channel(config);
+            let (_, rx) = mpsc::channel(1);
+            let (tx, discord) = mpsc::channel(64);
+
+            let chain = Arc::new(MockChain::new(START));
+            let storage = Arc::new(Sqlite::in_memory().await.unwrap());
+            storage.set_scan_cursor(&chain_id(), START).await.unwrap();
+
+            let mut checker = CashbackChecker::new(
+                storage.clone(),
+                chain.clone(),
+                config,
+                rx,
+                tx,
+                Readiness::default(),
+            );
+            checker.cursor = storage.get_scan_cursor(&chain_id()).await.unwrap();
+
+            let mut harness = Self {
+                checker,
+                chain,
+                storage,
+                discord,
+            };
+            // The first catch up reconciles the empty ledger.
+            harness.catch_up().await;
+            harness.messages();
+
+            harness
+        }
+
+        async fn catch_up(&mut self) {
+            self.checker.catch_up().await.unwrap();
+        }
+
+        async fn mine(&mut self, txs: Vec<Transaction>) -> u64 {
+            let height = self.chain.mine(txs);
+            self.catch_up().await;
+
+            height
+        }
+
+        async fn mine_empty(&mut self, blocks: u64) {
+            for _ in 0..blocks {
+                self.mine(Vec::new()).await;
+            }
+        }
+
+        /// Mines the registration of `alice` and the blocks it needs until it is paid out.
+        async fn pay_alice(&mut self) -> mock_rpc::Sent {
+            self.mine(vec![alice()]).await;
+            self.mine_empty(10).await;
+
+            let mut sends = self.chain.sends();
+            assert_eq!(sends.len(), 1);
+            sends.remove(0)
+        }
+
+        async fn cashback(&self) -> Cashback {
+            let mut cashbacks = self.storage.get_cashbacks(None).await.unwrap();
+            assert_eq!(cashbacks.len(), 1);
+            cashbacks.remove(0)
+        }
+
+        fn messages(&mut self) -> Vec<DiscordMessage> {
+            let mut messages = Vec::new();
+            while let Ok(message) = self.discord.try_recv() {
+                messages.push(message);
+            }
+
+            messages
+        }
+    }
+
+    #[tokio::test]
+    async fn detects_registrations_with_our_referral() {
+        let mut harness = Harness::new().await;
+
+        let height = harness
+            .mine(vec![
+                alice(),
+                mock_rpc::registration(2, "bob", &chain_id(), Some(&other_referral_id())),
+                mock_rpc::registration(3, "carol", &other_referral_id(), None),
+            ])
+            .await;
+
+        let cashback = harness.cashback().await;
+        assert_eq!(cashback.name, "alice");
+        assert_eq!(cashback.name_id, fixtures::alice());
+        assert_eq!(cashback.status, CashbackStatus::Pending);
+        assert_eq!(cashback.registration_txid, Some(mock_rpc::txid(1)));
+        assert_eq!(cashback.detected_block_height, Some(height));
+
+        let messages = harness.messages();
+        assert_eq!(messages.len(), 1);
+        assert!(matches!(
+            &messages[0],
+            DiscordMessage::CashbackInitiated(chain, (name, id))
+                if *chain == chain_id() && name == "alice" && *id == fixtures::alice()
+        ));
+    }
+
+    #[tokio::test]
+    async fn announces_registrations_in_the_mempool() {
+        let mut harness = Harness::new().await;
+
+        let raw_tx = harness.chain.add_to_mempool(alice());
+        harness
+            .checker
+            .handle_messages(ZMQMessage::RawTx(raw_tx))
+            .await;
+
+        assert_eq!(harness.cashback().await.status, CashbackStatus::Mempool);
+        let messages = harness.messages();
+        assert_eq!(messages.len(), 1);
+        assert!(matches!(
+            &messages[0],
+            DiscordMessage::RegistrationSeen(_, (name, _)) if name == "alice"
+        ));
+
+        // Once mined it becomes payable.
+        let height = harness.mine(vec![alice()]).await;
+
+        let cashback = harness.cashback().await;
+        assert_eq!(cashback.status, CashbackStatus::Pending);
+        assert_eq!(cashback.detected_block_height, Some(height));
+        assert!(matches!(
+            harness.messages().as_slice(),
+            [DiscordMessage::CashbackInitiated(..)]
+        ));
+    }
+
+    #[tokio::test]
+    async fn forgets_registrations_that_are_not_mined() {
+        let mut harness = Harness::new().await;
+
+        let raw_tx = harness.chain.add_to_mempool(alice());
+        harness
+            .checker
+            .handle_messages(ZMQMessage::RawTx(raw_tx))
+            .await;
+        harness.messages();
+
+        harness.mine_empty(1).await;
+        assert_eq!(harness.cashback().await.status, CashbackStatus::Mempool);
+
+        harness.checker.mempool_expiry = Duration::ZERO;
+        harness.mine_empty(1).await;
+        assert!(harness
+            .storage
+            .get_cashbacks(None)
+            .await
+            .unwrap()
+            .is_empty());
+
+        // Mined after all, it is paid like any other.
+        harness.mine(vec![alice()]).await;
+        assert_eq!(harness.cashback().await.status, CashbackStatus::Pending);
+        assert!(matches!(
+            harness.messages().as_slice(),
+            [DiscordMessage::CashbackInitiated(..)]
+        ));
+    }
+
+    #[tokio::test]
+    async fn waits_ten_blocks_before_paying_out() {
+        let mut harness = Harness::new().await;
+
+        harness.mine(vec![alice()]).await;
+        harness.mine_empty(9).await;
+        assert!(harness.chain.sends().is_empty());
+        assert_eq!(harness.cashback().await.status, CashbackStatus::Pending);
+
+        harness.mine_empty(1).await;
+        assert_eq!(harness.chain.sends().len(), 1);
+        assert_eq!(harness.cashback().await.status, CashbackStatus::Broadcast);
+    }
+
+    #[tokio::test]
+    async fn pays_the_identity_and_the_referral() {
+        let mut harness = Harness::new().await;
+
+        let sent = harness.pay_alice().await;
+
+        assert_eq!(sent.from, "*");
+        assert_eq!(
+            sent.outputs,
+            vec![
+                Output {
+                    address: fixtures::alice(),
+                    amount: Amount::from_sat(REFERRAL_AMOUNT - FEE),
+                },
+                Output {
+                    address: referral_id(),
+                    amount: Amount::from_sat(FEE - ledger::NETWORK_FEE),
+                },
+            ]
+        );
+
+        let cashback = harness.cashback().await;
+        assert_eq!(cashback.txid, Some(sent.txid));
+        assert_eq!(cashback.amount, Some(REFERRAL_AMOUNT - FEE));
+        assert_eq!(cashback.fee, Some(FEE));
+        assert_eq!(cashback.payout_currency, Some(chain_id()));
+    }
+
+    #[tokio::test]
+    async fn announces_payouts_once_they_are_confirmed() {
+        let mut harness = Harness::new().await;
+        let sent = harness.pay_alice().await;
+        harness.messages();
+
+        // Mined, but one confirmation short.
+        let payout_height = harness.mine(Vec::new()).await;
+        assert_eq!(harness.cashback().await.status, CashbackStatus::Broadcast);
+        assert!(harness.messages().is_empty());
+
+        harness.mine_empty(1).await;
+
+        let cashback = harness.cashback().await;
+        assert_eq!(cashback.status, CashbackStatus::Paid);
+        assert_eq!(cashback.payout_block_height, Some(payout_height));
+        assert!(cashback.paid_at.is_some());
+
+        let messages = harness.messages();
+        assert_eq!(messages.len(), 1);
+        assert!(matches!(
+            &messages[0],
+            DiscordMessage::CashbackProcessed(_, (name, _), url)
+                if name == "alice" && *url == format!("{EXPLORER_URL}{}", sent.txid)
+        ));
+
+        // The payout, the fee output and the network fee the wallet reported left the wallet.
+        let network_fee = Amount::from_btc(-mock_rpc::PAYOUT_FEE).unwrap().to_sat();
+        let expected = (REFERRAL_AMOUNT - ledger::NETWORK_FEE + network_fee) as i64;
+        let balance = harness
+            .storage
+            .ledger_balance(&chain_id(), ledger::Account::Wallet)
+            .await
+            .unwrap();
+        assert_eq!(balance, -expected);
+
+        // Nothing is paid twice.
+        harness.mine_empty(10).await;
+        assert_eq!(harness.chain.sends().len(), 1);
+        assert!(harness.messages().is_empty());
+    }
+
+    #[tokio::test]
+    async fn counts_referral_rewards_when_the_referral_is_in_the_wallet() {
+        let mut harness = Harness::new().await;
+        harness.chain.add_wallet_identity(&referral_id());
+
+        let storage = harness.storage.clone();
+        let balance = move |account| {
+            let storage = storage.clone();
+            async move { storage.ledger_balance(&chain_id(), account).await.unwrap() }
+        };
+
+        harness.mine(vec![alice()]).await;
+        assert_eq!(
+            balance(ledger::Account::Wallet).await,
+            REFERRAL_AMOUNT as i64
+        );
+
+        harness.mine_empty(10).await;
+        harness.mine_empty(2).await;
+        assert_eq!(harness.cashback().await.status, CashbackStatus::Paid);
+
+        // The reward came in and the payout and the network fee left, the fee output to the
+        // referral never left the wallet.
+        let network_fee = Amount::from_btc(-mock_rpc::PAYOUT_FEE).unwrap().to_sat();
+        assert_eq!(
+            balance(ledger::Account::Wallet).await,
+            (FEE - network_fee) as i64
+        );
+        assert_eq!(balance(ledger::Account::Referral).await, 0);
+    }
+
+    #[tokio::test]
+    async fn fails_payouts_that_were_dropped() {
+        let mut harness = Harness::new().await;
+        let sent = harness.pay_alice().await;
+        harness.messages();
+
+        harness.chain.drop_unconfirmed();
+        harness.mine_empty(1).await;
+
+        let cashback = harness.cashback().await;
+        assert_eq!(cashback.status, CashbackStatus::Failed);
+        assert_eq!(cashback.attempts, 1);
+        assert!(harness.chain.abandoned().contains(&sent.txid));
+
+        let messages = harness.messages();
+        assert_eq!(messages.len(), 1);
+        assert!(matches!(
+            &messages[0],
+            DiscordMessage::PayoutDropped(_, (name, _), _) if name == "alice"
+        ));
+
+        // Failed cashbacks wait for an operator.
+        harness.mine_empty(10).await;
+        assert_eq!(harness.chain.sends().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn fails_conflicted_payouts_once_the_conflict_is_confirmed() {
+        let mut harness = Harness::new().await;
+        harness.pay_alice().await;
+        harness.messages();
+
+        harness.chain.conflict_unconfirmed();
+        harness.catch_up().await;
+        assert_eq!(harness.cashback().await.status, CashbackStatus::Broadcast);
+
+        harness.mine_empty(1).await;
+        let cashback = harness.cashback().await;
+        assert_eq!(cashback.status, CashbackStatus::Failed);
+        assert!(harness.chain.abandoned().is_empty());
+        assert!(matches!(
+            harness.messages().as_slice(),
+            [DiscordMessage::PayoutDropped(_, (name, _), reason)]
+                if name == "alice" && reason == "conflicted"
+        ));
+    }
+
+    #[tokio::test]
+    async fn does_not_send_again_when_a_send_times_out() {
+        let mut harness = Harness::new().await;
+        harness.chain.time_out_sends();
+
+        let sent = harness.pay_alice().await;
+
+        let cashback = harness.cashback().await;
+        assert_eq!(cashback.status, CashbackStatus::Sending);
+        assert_eq!(
+            cashback.last_error.as_deref(),
+            Some("sendcurrency timed out")
+        );
+        assert!(matches!(
+            harness.messages().as_slice(),
+            [.., DiscordMessage::PayoutUnknown(_, (name, _), _)] if name == "alice"
+        ));
+
+        // The wallet has it, so it is followed like any other payout.
+        harness.mine_empty(1).await;
+        let cashback = harness.cashback().await;
+        assert_eq!(cashback.status, CashbackStatus::Broadcast);
+        assert_eq!(cashback.txid, Some(sent.txid));
+
+        harness.mine_empty(10).await;
+        assert_eq!(harness.cashback().await.status, CashbackStatus::Paid);
+        assert_eq!(harness.chain.sends().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn does_not_take_another_send_to_the_identity_for_a_payout_that_timed_out() {
+        let mut harness = Harness::new().await;
+        let hours_ago = |hours: i64| {
+            DateTime::from_timestamp(Utc::now().timestamp() - hours * 3600, 0).unwrap()
+        };
+        // Of the same amount, but before the payout started.
+        let earlier = harness.chain.send_by_hand(
+            &fixtures::alice(),
+            Amount::from_sat(REFERRAL_AMOUNT - FEE),
+            hours_ago(1),
+        );
+        // Of another amount.
+        let other =
+            harness
+                .chain
+                .send_by_hand(&fixtures::alice(), Amount::from_sat(FEE), hours_ago(-1));
+        harness.chain.time_out_sends();
+
+        harness.mine(vec![alice()]).await;
+        harness.mine_empty(10).await;
+        let sent = harness.chain.sends().pop().unwrap();
+        assert!(sent.txid != earlier && sent.txid != other);
+        assert_eq!(harness.cashback().await.status, CashbackStatus::Sending);
+
+        harness.mine_empty(1).await;
+        let cashback = harness.cashback().await;
+        assert_eq!(cashback.status, CashbackStatus::Broadcast);
+        assert_eq!(cashback.txid, Some(sent.txid));
+    }
+
+    #[tokio::test]
+    async fn pays_out_the_others_when_an_identity_is_not_found() {
+        let mut harness = Harness::new().await;
+        // Stored, but the daemon doesn't know the identity.
+        fixtures::store(
+            &*harness.storage,
+            &fixtures::bob(),
+            2,
+            CashbackStatus::Pending,
+            Some(START),
+        )
+        .await;
+
+        harness.mine(vec![alice()]).await;
+        harness.mine_empty(10).await;
+
+        let sends = harness.chain.sends();
+        assert_eq!(sends.len(), 1);
+        assert_eq!(sends[0].outputs[0].address, fixtures::alice());
+        assert_eq!(
+            fixtures::cashback(&*harness.storage, &fixtures::bob())
+                .await
+                .status,
+            CashbackStatus::Pending
+        );
+    }
+
+    #[tokio::test]
+    async fn does_not_pay_out_while_paused() {
+        let mut harness = Harness::new().await;
+        harness
+            .storage
+            .set_payouts_paused(&chain_id(), true, None)
+            .await
+            .unwrap();
+
+        harness.mine(vec![alice()]).await;
+        harness.mine_empty(10).await;
+
+        assert!(harness.chain.sends().is_empty());
+        assert_eq!(harness.cashback().await.status, CashbackStatus::Pending);
+    }
+
+    #[tokio::test]
+    async fn flags_a_difference_with_the_wallet_balance() {
+        let mut harness = Harness::new().await;
+
+        harness.chain.set_wallet_balance(5000);
+        harness.checker.reconciled_at = None;
+        harness.mine_empty(1).await;
+
+        let messages = harness.messages();
+        assert_eq!(messages.len(), 1);
+        assert!(matches!(
+            &messages[0],
+            DiscordMessage::LedgerDifference(_, 0, 5000)
+        ));
+    }
+}
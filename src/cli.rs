@@ -0,0 +1,243 @@
+use anyhow::{bail, Result};
+use chrono::{DateTime, Utc};
+use clap::{Parser, Subcommand};
+use vrsc_rpc::{bitcoin::Txid, client::RpcApi, json::vrsc::Address};
+
+use crate::{
+    admin::CashbackRef,
+    config::{
+        pbaas::{pbaas_chain_config, pbaas_chain_configs},
+        Config,
+    },
+    export, ledger,
+    rpc::Client,
+    storage::Storage,
+};
+
+#[derive(Debug, Parser)]
+#[command(version, about = "Pays out VerusID referral cashbacks")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Start the cashback daemon (default)
+    Run,
+    /// Apply the database migrations
+    Migrate,
+    /// Look for missed referrals in a range of blocks
+    Rescan {
+        /// Currency id of the chain
+        #[arg(long)]
+        chain: Address,
+        /// First block height to scan
+        #[arg(long)]
+        from: u64,
+        /// Last block height to scan
+        #[arg(long)]
+        to: u64,
+        /// Store found referrals as ineligible instead of paying them out
+        #[arg(long)]
+        historical: bool,
+    },
+    /// List cashbacks that are waiting to be paid
+    ListPending {
+        /// Only list cashbacks of this chain
+        #[arg(long)]
+        chain: Option<Address>,
+    },
+    /// Print all cashbacks as JSON, or with --accounting the paid ones for accounting
+    Export {
+        /// Only export cashbacks of this chain
+        #[arg(long)]
+        chain: Option<Address>,
+        /// Only the paid cashbacks, with what was paid and when, and totals
+        #[arg(long)]
+        accounting: bool,
+        #[arg(long, value_enum, default_value_t, requires = "accounting")]
+        format: export::Format,
+        /// Only the totals per chain and per referral identity
+        #[arg(long, requires = "accounting")]
+        totals: bool,
+        /// Paid at or after this time (RFC 3339)
+        #[arg(long, requires = "accounting")]
+        from_date: Option<DateTime<Utc>>,
+        /// Paid before this time (RFC 3339)
+        #[arg(long, requires = "accounting")]
+        to_date: Option<DateTime<Utc>>,
+        /// Paid in this block or later
+        #[arg(long, requires = "accounting")]
+        from_height: Option<u64>,
+        /// Paid before this block
+        #[arg(long, requires = "accounting")]
+        to_height: Option<u64>,
+    },
+    /// Check the configuration and the connections to the database and daemons
+    CheckConfig,
+    /// Pause payouts for a chain; referrals are still detected
+    Pause {
+        /// Currency id of the chain
+        #[arg(long)]
+        chain: Address,
+        #[arg(long)]
+        reason: Option<String>,
+    },
+    /// Resume payouts for a chain
+    Resume {
+        /// Currency id of the chain
+        #[arg(long)]
+        chain: Address,
+    },
+    /// Queue a failed, skipped or historical cashback for another payout attempt
+    Retry {
+        /// Cashback id or identity address
+        cashback: CashbackRef,
+    },
+    /// Mark a cashback as paid by a payout that was made outside of this service
+    MarkPaid {
+        /// Cashback id or identity address
+        cashback: CashbackRef,
+        #[arg(long)]
+        txid: Txid,
+    },
+    /// Record funds sent to the wallet of a chain in the ledger. Record the wallet balance as a
+    /// top-up once to open the ledger
+    TopUp {
+        #[arg(long)]
+        chain: Address,
+        /// In satoshis
+        #[arg(long)]
+        amount: u64,
+        #[arg(long)]
+        txid: Option<Txid>,
+        #[arg(long)]
+        note: Option<String>,
+    },
+    /// Compare the ledger of a chain with the wallet balance
+    Reconcile {
+        #[arg(long)]
+        chain: Address,
+    },
+    /// Release a cashback that a rescan stored as historical for payout
+    Approve {
+        /// Cashback id or identity address
+        cashback: CashbackRef,
+    },
+    /// Never pay out a cashback
+    Skip {
+        /// Cashback id or identity address
+        cashback: CashbackRef,
+        #[arg(long)]
+        reason: String,
+    },
+}
+
+/// Name under which CLI actions are written to the audit log.
+pub fn actor() -> String {
+    format!(
+        "cli:{}",
+        std::env::var("USER").unwrap_or_else(|_| "unknown".into())
+    )
+}
+
+pub async fn list_pending(storage: &dyn Storage, chain: Option<Address>) -> Result<()> {
+    let chains = match chain {
+        Some(chain) => vec![chain],
+        None => pbaas_chain_configs()?
+            .into_iter()
+            .map(|config| config.currency_id)
+            .collect(),
+    };
+
+    for currency_id in chains {
+        let pending = storage.get_pending_cashbacks(&currency_id).await?;
+        println!("{currency_id}: {} pending", pending.len());
+
+        for cashback in pending {
+            println!(
+                "  {}  {}@ ({})",
+                cashback.id, cashback.name, cashback.name_id
+            );
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn export(storage: &dyn Storage, chain: Option<Address>) -> Result<()> {
+    let cashbacks = storage.get_cashbacks(chain.as_ref()).await?;
+    println!("{}", serde_json::to_string_pretty(&cashbacks)?);
+
+    Ok(())
+}
+
+pub async fn reconcile(storage: &dyn Storage, chain: &Address) -> Result<()> {
+    let config = pbaas_chain_config(chain)?;
+    let payout_address = config.payout_address.clone();
+    let client: Client = config.try_into()?;
+
+    match ledger::reconcile(storage, &client, chain, payout_address.as_deref()).await? {
+        Some(reconciliation) => {
+            println!("ledger: {} sats", reconciliation.ledger_balance);
+            println!("wallet: {} sats", reconciliation.wallet_balance);
+            println!("difference: {} sats", reconciliation.difference);
+        }
+        None => println!(
+            "{chain} has payouts or registrations waiting for confirmations, try again later"
+        ),
+    }
+
+    Ok(())
+}
+
+pub async fn check_config(config: &Config, storage: &dyn Storage) -> Result<()> {
+    let mut failures = 0;
+
+    match storage.ping().await {
+        Ok(()) => println!("database {}: ok", config.database.describe()),
+        Err(e) => {
+            println!("database {}: {e:#}", config.database.describe());
+            failures += 1;
+        }
+    }
+
+    if let Err(e) = config.check_shutdown_timeout() {
+        println!("{e}");
+        failures += 1;
+    }
+
+    let chains = pbaas_chain_configs()?;
+    if chains.is_empty() {
+        println!("no pbaas chains configured");
+        failures += 1;
+    }
+
+    for chain in chains {
+        let currency_id = chain.currency_id.clone();
+
+        if let Err(e) = chain.check_rewards() {
+            println!("{e}");
+            failures += 1;
+        }
+
+        let client: Client = chain.try_into()?;
+        match client
+            .call("getblockchaininfo", |client| client.get_blockchain_info())
+            .await
+        {
+            Ok(info) => println!("{currency_id}: ok, at height {}", info.blocks),
+            Err(e) => {
+                println!("{currency_id}: {e:#}");
+                failures += 1;
+            }
+        }
+    }
+
+    if failures > 0 {
+        bail!("configuration has {failures} problem(s)");
+    }
+
+    Ok(())
+}
@@ -1,12 +1,48 @@
-use anyhow::{anyhow, Context, Result};
+use std::{collections::HashMap, net::SocketAddr, path::PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
 use secrecy::{ExposeSecret, Secret};
 use serde::Deserialize;
-use serde_aux::field_attributes::deserialize_number_from_string;
+use serde_aux::field_attributes::{
+    deserialize_number_from_string, deserialize_option_number_from_string,
+};
+
+use crate::checker::SEND_TIMEOUT;
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Config {
     pub discord: DiscordConfig,
     pub database: DbConfig,
+    #[serde(default)]
+    pub http: HttpConfig,
+    /// How long payouts in flight and queued Discord messages get to finish on shutdown.
+    #[serde(
+        default = "default_shutdown_timeout_secs",
+        deserialize_with = "deserialize_number_from_string"
+    )]
+    pub shutdown_timeout_secs: u64,
+}
+
+/// Leaves a payout that is being sent time to record its outcome after its `sendcurrency` timed
+/// out.
+fn default_shutdown_timeout_secs() -> u64 {
+    SEND_TIMEOUT.as_secs() + 60
+}
+
+impl Config {
+    /// A shorter deadline cuts off payouts in flight before their outcome is known, they are
+    /// then looked for in the wallet on the next start.
+    pub fn check_shutdown_timeout(&self) -> Result<()> {
+        if self.shutdown_timeout_secs <= SEND_TIMEOUT.as_secs() {
+            bail!(
+                "shutdown_timeout_secs ({}) must be above the {}s a payout gets to be sent",
+                self.shutdown_timeout_secs,
+                SEND_TIMEOUT.as_secs()
+            );
+        }
+
+        Ok(())
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -15,21 +51,107 @@ pub struct DiscordConfig {
     // pub channels: Map<String, u64>, // TODO
 }
 
+#[derive(Debug, Deserialize, Clone)]
+pub struct HttpConfig {
+    /// Address the http server for `/metrics`, `/healthz`, `/readyz` and the `/api` listens on.
+    #[serde(default = "default_listen_address")]
+    pub listen_address: SocketAddr,
+    /// Bearer tokens for the admin API, by the name they are written to the audit log with.
+    /// The admin API is off when there are none.
+    #[serde(default)]
+    pub admin_tokens: HashMap<String, Secret<String>>,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        Self {
+            listen_address: default_listen_address(),
+            admin_tokens: HashMap::new(),
+        }
+    }
+}
+
+fn default_listen_address() -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], 9100))
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct DbConfig {
-    #[serde(rename = "name")]
+    #[serde(default)]
+    pub backend: DbBackend,
+    #[serde(rename = "name", default)]
     pub db_name: String,
-    #[serde(rename = "password")]
+    #[serde(rename = "password", default = "empty_secret")]
     pub db_pass: Secret<String>,
-    #[serde(rename = "user")]
+    #[serde(rename = "user", default)]
     pub db_user: String,
-    #[serde(rename = "host")]
+    #[serde(rename = "host", default)]
     pub db_host: String,
-    #[serde(rename = "port")]
+    #[serde(rename = "port", default)]
     pub db_port: u16,
+    /// Database file of the `sqlite` backend, created when it does not exist.
+    pub path: Option<PathBuf>,
+    /// Apply the embedded migrations when the daemon starts.
+    #[serde(default)]
+    pub migrate_on_startup: bool,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DbBackend {
+    #[default]
+    Postgres,
+    /// An embedded database in a single file, for setups without a Postgres server.
+    Sqlite,
+}
+
+fn empty_secret() -> Secret<String> {
+    Secret::new(String::new())
 }
 
 impl DbConfig {
+    /// The database, for messages.
+    pub fn describe(&self) -> String {
+        match self.backend {
+            DbBackend::Postgres => self.db_name.clone(),
+            DbBackend::Sqlite => self
+                .path
+                .as_ref()
+                .map_or_else(|| "<no path>".to_owned(), |path| path.display().to_string()),
+        }
+    }
+
+    /// Fails when a setting the backend needs is missing, rather than when it connects.
+    pub fn validate(&self) -> Result<()> {
+        match self.backend {
+            DbBackend::Postgres => {
+                let missing = [
+                    ("name", self.db_name.is_empty()),
+                    ("user", self.db_user.is_empty()),
+                    ("host", self.db_host.is_empty()),
+                    ("port", self.db_port == 0),
+                ]
+                .into_iter()
+                .filter_map(|(setting, missing)| missing.then_some(setting))
+                .collect::<Vec<_>>();
+
+                if !missing.is_empty() {
+                    bail!(
+                        "[database] {} must be set for the postgres backend",
+                        missing.join(", ")
+                    );
+                }
+            }
+            DbBackend::Sqlite => {
+                if self.path.is_none() {
+                    bail!("[database] path must be set for the sqlite backend");
+                }
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn connection_string(&self) -> String {
         format!(
             "postgres://{}:{}@{}:{}/{}",
@@ -69,9 +191,12 @@ pub fn get_configuration() -> Result<Config> {
         )
         .build()?;
 
-    settings
+    let config = settings
         .try_deserialize::<Config>()
-        .context("failed to serialize into config")
+        .context("failed to serialize into config")?;
+    config.database.validate()?;
+
+    Ok(config)
 }
 
 pub enum Environment {
@@ -105,18 +230,37 @@ impl TryFrom<String> for Environment {
 pub mod pbaas {
     use std::path::PathBuf;
 
-    use tracing::warn;
+    use tracing::{error, warn};
     use vrsc_rpc::json::vrsc::Address;
 
     use super::*;
+    use crate::{ledger::NETWORK_FEE, rpc::DaemonConf};
 
     #[derive(Debug, Deserialize, Clone)]
     pub struct Config {
-        pub rpc_user: String,
-        pub rpc_password: String,
-        #[serde(deserialize_with = "deserialize_number_from_string")]
-        pub rpc_port: u16,
-        pub zmq_block_hash_url: String,
+        /// Full url of the daemon, e.g. `https://node.example.com:27486`. Takes precedence over
+        /// `rpc_port`, which connects to the daemon on localhost.
+        pub rpc_url: Option<String>,
+        pub rpc_user: Option<String>,
+        pub rpc_password: Option<Secret<String>>,
+        #[serde(default, deserialize_with = "deserialize_option_number_from_string")]
+        pub rpc_port: Option<u16>,
+        /// Authenticate with the `.cookie` file of the daemon instead of user and password.
+        pub rpc_cookie_file: Option<PathBuf>,
+        /// PEM encoded CA certificate to verify an https `rpc_url` with.
+        pub rpc_tls_ca: Option<PathBuf>,
+        /// Endpoints that are used, in order, when the previous one is unreachable.
+        #[serde(default)]
+        pub rpc_fallback: Vec<RpcEndpoint>,
+        pub zmq_block_hash_url: Option<String>,
+        /// ZMQ url the daemon publishes `rawtx` on, to see registrations while they are in the
+        /// mempool. Only used when `mempool_detection` is set.
+        pub zmq_raw_tx_url: Option<String>,
+        #[serde(default)]
+        pub mempool_detection: bool,
+        /// Data directory of the daemon. Rpc credentials, rpc port and zmq url that are not set
+        /// in this file are read from the `<dir name>.conf` file in it.
+        pub data_dir: Option<PathBuf>,
         pub currency_id: Address,
         pub referral_currency_id: Address,
         pub explorer_url: String,
@@ -124,6 +268,179 @@ pub mod pbaas {
         pub referral_amount: u64,
         #[serde(deserialize_with = "deserialize_number_from_string")]
         pub fee: u64,
+        #[serde(
+            default = "default_rpc_timeout_secs",
+            deserialize_with = "deserialize_number_from_string"
+        )]
+        pub rpc_timeout_secs: u64,
+        /// Target block time of the chain.
+        #[serde(
+            default = "default_block_time_secs",
+            deserialize_with = "deserialize_number_from_string"
+        )]
+        pub block_time_secs: u64,
+        /// Number of block times without a ZMQ notification after which the socket is
+        /// considered stalled.
+        #[serde(
+            default = "default_zmq_stale_blocks",
+            deserialize_with = "deserialize_number_from_string"
+        )]
+        pub zmq_stale_blocks: u64,
+        /// How often the tip is polled when there is no ZMQ url, or ZMQ has stalled.
+        #[serde(
+            default = "default_poll_interval_secs",
+            deserialize_with = "deserialize_number_from_string"
+        )]
+        pub poll_interval_secs: u64,
+        /// Confirmations a payout needs before the cashback counts as paid and is announced.
+        #[serde(
+            default = "default_payout_confirmations",
+            deserialize_with = "deserialize_number_from_string"
+        )]
+        pub payout_confirmations: u64,
+        /// Address the payouts are sent from and the ledger is reconciled with. Any address
+        /// of the wallet when not set.
+        pub payout_address: Option<String>,
+    }
+
+    #[derive(Debug, Deserialize, Clone)]
+    pub struct RpcEndpoint {
+        pub url: String,
+        pub user: Option<String>,
+        pub password: Option<Secret<String>>,
+        pub cookie_file: Option<PathBuf>,
+        pub tls_ca: Option<PathBuf>,
+    }
+
+    fn default_rpc_timeout_secs() -> u64 {
+        30
+    }
+
+    fn default_block_time_secs() -> u64 {
+        60
+    }
+
+    fn default_zmq_stale_blocks() -> u64 {
+        10
+    }
+
+    fn default_poll_interval_secs() -> u64 {
+        10
+    }
+
+    fn default_payout_confirmations() -> u64 {
+        10
+    }
+
+    impl Config {
+        /// Fills in the settings that are missing from this file with the ones from the conf file
+        /// of the daemon in `data_dir`.
+        pub fn apply_daemon_conf(&mut self) -> Result<()> {
+            let Some(data_dir) = &self.data_dir else {
+                return Ok(());
+            };
+
+            let conf = DaemonConf::from_data_dir(data_dir)?;
+
+            if self.rpc_cookie_file.is_none() {
+                self.rpc_user = self.rpc_user.take().or(conf.rpc_user);
+                self.rpc_password = self.rpc_password.take().or(conf.rpc_password);
+            }
+            self.rpc_port = self.rpc_port.or(conf.rpc_port);
+            self.zmq_block_hash_url = self.zmq_block_hash_url.take().or(conf.zmq_block_hash_url);
+            self.zmq_raw_tx_url = self.zmq_raw_tx_url.take().or(conf.zmq_raw_tx_url);
+
+            Ok(())
+        }
+
+        /// The fee pays for the network fee of a payout and must leave something to pay back.
+        pub fn check_rewards(&self) -> Result<()> {
+            if self.fee <= NETWORK_FEE || self.fee >= self.referral_amount {
+                return Err(anyhow!(
+                    "{}: fee ({}) must be above {NETWORK_FEE} and below referral_amount ({})",
+                    self.currency_id,
+                    self.fee,
+                    self.referral_amount
+                ));
+            }
+
+            Ok(())
+        }
+
+        /// Whether `other` differs from this config in more than `referral_amount` and `fee`,
+        /// which a running checker picks up without being restarted.
+        pub fn differs_beyond_rewards(&self, other: &Self) -> bool {
+            let endpoints = |config: &Self| {
+                config
+                    .rpc_endpoints()
+                    .map(|endpoints| {
+                        endpoints
+                            .into_iter()
+                            .map(|endpoint| {
+                                (
+                                    endpoint.url,
+                                    endpoint.user,
+                                    endpoint
+                                        .password
+                                        .map(|password| password.expose_secret().clone()),
+                                    endpoint.cookie_file,
+                                    endpoint.tls_ca,
+                                )
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                    .ok()
+            };
+
+            endpoints(self) != endpoints(other)
+                || self.zmq_block_hash_url != other.zmq_block_hash_url
+                || self.zmq_raw_tx_url != other.zmq_raw_tx_url
+                || self.mempool_detection != other.mempool_detection
+                || self.data_dir != other.data_dir
+                || self.currency_id != other.currency_id
+                || self.referral_currency_id != other.referral_currency_id
+                || self.explorer_url != other.explorer_url
+                || self.rpc_timeout_secs != other.rpc_timeout_secs
+                || self.block_time_secs != other.block_time_secs
+                || self.zmq_stale_blocks != other.zmq_stale_blocks
+                || self.poll_interval_secs != other.poll_interval_secs
+                || self.payout_confirmations != other.payout_confirmations
+                || self.payout_address != other.payout_address
+        }
+
+        /// The primary endpoint followed by the fallbacks.
+        pub fn rpc_endpoints(&self) -> Result<Vec<RpcEndpoint>> {
+            let url = match (&self.rpc_url, self.rpc_port) {
+                (Some(url), _) => url.clone(),
+                (None, Some(port)) => format!("http://localhost:{}", port),
+                (None, None) => {
+                    return Err(anyhow!(
+                        "{}: either `rpc_url` or `rpc_port` must be set",
+                        self.currency_id
+                    ))
+                }
+            };
+
+            let primary = RpcEndpoint {
+                url,
+                user: self.rpc_user.clone(),
+                password: self.rpc_password.clone(),
+                cookie_file: self.rpc_cookie_file.clone(),
+                tls_ca: self.rpc_tls_ca.clone(),
+            };
+
+            Ok(std::iter::once(primary)
+                .chain(self.rpc_fallback.iter().cloned())
+                .collect())
+        }
+    }
+
+    /// Returns the config of the chain with `currency_id` from the `pbaas` directory.
+    pub fn pbaas_chain_config(currency_id: &Address) -> Result<self::Config> {
+        pbaas_chain_configs()?
+            .into_iter()
+            .find(|config| &config.currency_id == currency_id)
+            .ok_or_else(|| anyhow!("no pbaas config found for {}", currency_id))
     }
 
     pub fn pbaas_chain_configs() -> Result<Vec<self::Config>> {
@@ -139,10 +456,16 @@ pub mod pbaas {
 
                 if let Some(extension) = path.extension() {
                     if extension.eq_ignore_ascii_case("toml") {
-                        let settings = config::Config::builder()
-                            .add_source(config::File::from(config_dir.join(&path)))
+                        let path = config_dir.join(&path);
+                        let mut settings = config::Config::builder()
+                            .add_source(config::File::from(path.as_path()))
                             .build()?
                             .try_deserialize::<self::Config>()?;
+                        // The other chains don't depend on the daemon of this one.
+                        if let Err(e) = settings.apply_daemon_conf() {
+                            error!("{}: {e:?}, skipping this chain", path.display());
+                            continue;
+                        }
 
                         pbaas_configs.push(settings);
                     }
@@ -1,10 +1,135 @@
+use anyhow::anyhow;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use vrsc_rpc::{bitcoin::Txid, json::vrsc::Address};
+use uuid::Uuid;
+use vrsc_rpc::{
+    bitcoin::{BlockHash, Txid},
+    json::vrsc::Address,
+};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Cashback {
+    pub id: Uuid,
     pub currency_id: Address,
     pub name_id: Address,
     pub name: String,
+    /// The identity whose referral was used, not recorded for cashbacks stored before it was.
+    pub referral_id: Option<Address>,
     pub txid: Option<Txid>,
+    /// The wallet operation that sends the payout, once the daemon started it.
+    pub opid: Option<String>,
+    /// When the payout was last started. Sends to the identity from before then are not this
+    /// payout.
+    pub sending_at: Option<DateTime<Utc>>,
+    pub status: CashbackStatus,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    pub skip_reason: Option<String>,
+    /// Sent to the identity, in satoshis.
+    pub amount: Option<u64>,
+    /// Withheld from the referral reward, including the network fee, in satoshis.
+    pub fee: Option<u64>,
+    pub payout_currency: Option<Address>,
+    /// The transaction that registered the identity.
+    pub registration_txid: Option<Txid>,
+    /// The block the registration was mined in, not set while it is in the mempool.
+    pub detected_block_height: Option<u64>,
+    pub detected_block_hash: Option<BlockHash>,
+    pub paid_at: Option<DateTime<Utc>>,
+    /// Paid outside of this service and marked paid by an operator, without an amount or fee.
+    pub paid_manually: bool,
+    /// The block the payout was mined in, once it is seen there.
+    pub payout_block_height: Option<u64>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CashbackStatus {
+    /// The registration has been seen in the mempool, it becomes pending once it is mined.
+    Mempool,
+    Pending,
+    Failed,
+    /// The payout is being sent. It stays here until the checker knows whether it was sent, and
+    /// can't be retried or skipped meanwhile.
+    Sending,
+    /// The payout has been sent, it becomes paid once it has enough confirmations.
+    Broadcast,
+    Paid,
+    Skipped,
+    /// Found by a rescan of blocks from before the service was running, not paid out unless an
+    /// operator retries it.
+    IneligibleHistorical,
+}
+
+impl CashbackStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CashbackStatus::Mempool => "mempool",
+            CashbackStatus::Pending => "pending",
+            CashbackStatus::Failed => "failed",
+            CashbackStatus::Sending => "sending",
+            CashbackStatus::Broadcast => "broadcast",
+            CashbackStatus::Paid => "paid",
+            CashbackStatus::Skipped => "skipped",
+            CashbackStatus::IneligibleHistorical => "ineligible_historical",
+        }
+    }
+}
+
+impl TryFrom<String> for CashbackStatus {
+    type Error = anyhow::Error;
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        match s.as_str() {
+            "mempool" => Ok(Self::Mempool),
+            "pending" => Ok(Self::Pending),
+            "failed" => Ok(Self::Failed),
+            "sending" => Ok(Self::Sending),
+            "broadcast" => Ok(Self::Broadcast),
+            "paid" => Ok(Self::Paid),
+            "skipped" => Ok(Self::Skipped),
+            "ineligible_historical" => Ok(Self::IneligibleHistorical),
+            other => Err(anyhow!("{} is not a valid cashback status", other)),
+        }
+    }
+}
+
+/// Operator actions that end up in the audit log.
+#[derive(Debug, Clone, Copy)]
+pub enum AuditAction {
+    Pause,
+    Resume,
+    Retry,
+    MarkPaid,
+    Skip,
+    Approve,
+    Rescan,
+    TopUp,
+}
+
+impl AuditAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditAction::Pause => "pause",
+            AuditAction::Resume => "resume",
+            AuditAction::Retry => "retry",
+            AuditAction::MarkPaid => "mark_paid",
+            AuditAction::Skip => "skip",
+            AuditAction::Approve => "approve",
+            AuditAction::Rescan => "rescan",
+            AuditAction::TopUp => "top_up",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub id: Uuid,
+    pub actor: String,
+    pub action: String,
+    pub currency_id: Option<Address>,
+    pub cashback_id: Option<Uuid>,
+    pub detail: Option<String>,
+    pub created_at: DateTime<Utc>,
 }
@@ -5,12 +5,22 @@ use std::sync::Arc;
 use anyhow::Result;
 use poise::serenity_prelude as serenity;
 use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
 use tracing::*;
-use vrsc_rpc::json::vrsc::Address;
+use vrsc_rpc::{bitcoin::Txid, json::vrsc::Address};
 
-use crate::config::DiscordConfig;
+use crate::{
+    admin::{self, CashbackRef},
+    config::DiscordConfig,
+    readiness::Readiness,
+    storage::Storage,
+    telemetry,
+};
 
-struct Data {} // User data, which is stored and accessible in all command invocations
+// User data, which is stored and accessible in all command invocations
+struct Data {
+    storage: Arc<dyn Storage>,
+}
 type Error = Box<dyn std::error::Error + Send + Sync>;
 type Context<'a> = poise::Context<'a, Data, Error>;
 
@@ -19,9 +29,134 @@ async fn age(_ctx: Context<'_>) -> Result<(), Error> {
     Ok(())
 }
 
+/// Pause cashback payouts for a chain. Referrals are still detected.
+#[poise::command(slash_command, prefix_command, required_permissions = "ADMINISTRATOR")]
+async fn pause(
+    ctx: Context<'_>,
+    #[description = "Currency id of the chain"] chain: String,
+    #[description = "Why payouts are paused"] reason: Option<String>,
+) -> Result<(), Error> {
+    let currency_id = Address::from_str(&chain)?;
+    admin::pause_payouts(
+        ctx.data().storage.as_ref(),
+        &currency_id,
+        reason.as_deref(),
+        &actor(&ctx),
+    )
+    .await?;
+    ctx.say(format!(":pause_button:  Payouts paused for {currency_id}"))
+        .await?;
+
+    Ok(())
+}
+
+/// Resume cashback payouts for a chain.
+#[poise::command(slash_command, prefix_command, required_permissions = "ADMINISTRATOR")]
+async fn resume(
+    ctx: Context<'_>,
+    #[description = "Currency id of the chain"] chain: String,
+) -> Result<(), Error> {
+    let currency_id = Address::from_str(&chain)?;
+    admin::resume_payouts(ctx.data().storage.as_ref(), &currency_id, &actor(&ctx)).await?;
+    ctx.say(format!(
+        ":arrow_forward:  Payouts resumed for {currency_id}"
+    ))
+    .await?;
+
+    Ok(())
+}
+
+/// Queue a failed, skipped or historical cashback for another payout attempt.
+#[poise::command(slash_command, prefix_command, required_permissions = "ADMINISTRATOR")]
+async fn retry(
+    ctx: Context<'_>,
+    #[description = "Cashback id or identity address"] cashback: String,
+) -> Result<(), Error> {
+    let cashback = CashbackRef::from_str(&cashback)?;
+    let cashback =
+        admin::retry_cashback(ctx.data().storage.as_ref(), &cashback, &actor(&ctx)).await?;
+    ctx.say(format!(
+        ":repeat:  Cashback for **{}@** ({}) queued for retry",
+        cashback.name, cashback.name_id
+    ))
+    .await?;
+
+    Ok(())
+}
+
+/// Mark a cashback as paid by a payout that was made by hand.
+#[poise::command(slash_command, prefix_command, required_permissions = "ADMINISTRATOR")]
+async fn mark_paid(
+    ctx: Context<'_>,
+    #[description = "Cashback id or identity address"] cashback: String,
+    #[description = "Txid of the payout"] txid: String,
+) -> Result<(), Error> {
+    let cashback = CashbackRef::from_str(&cashback)?;
+    let txid = Txid::from_str(&txid)?;
+    let cashback =
+        admin::mark_paid(ctx.data().storage.as_ref(), &cashback, &txid, &actor(&ctx)).await?;
+    ctx.say(format!(
+        ":white_check_mark:  Cashback for **{}@** ({}) marked paid: {txid}",
+        cashback.name, cashback.name_id
+    ))
+    .await?;
+
+    Ok(())
+}
+
+/// Release a cashback that a rescan found in old blocks for payout.
+#[poise::command(slash_command, prefix_command, required_permissions = "ADMINISTRATOR")]
+async fn approve(
+    ctx: Context<'_>,
+    #[description = "Cashback id or identity address"] cashback: String,
+) -> Result<(), Error> {
+    let cashback = CashbackRef::from_str(&cashback)?;
+    let cashback =
+        admin::approve_cashback(ctx.data().storage.as_ref(), &cashback, &actor(&ctx)).await?;
+    ctx.say(format!(
+        ":ballot_box_with_check:  Cashback for **{}@** ({}) approved",
+        cashback.name, cashback.name_id
+    ))
+    .await?;
+
+    Ok(())
+}
+
+/// Never pay out a cashback.
+#[poise::command(slash_command, prefix_command, required_permissions = "ADMINISTRATOR")]
+async fn skip(
+    ctx: Context<'_>,
+    #[description = "Cashback id or identity address"] cashback: String,
+    #[description = "Why the cashback is skipped"] reason: String,
+) -> Result<(), Error> {
+    let cashback = CashbackRef::from_str(&cashback)?;
+    let cashback = admin::skip_cashback(
+        ctx.data().storage.as_ref(),
+        &cashback,
+        &reason,
+        &actor(&ctx),
+    )
+    .await?;
+    ctx.say(format!(
+        ":fast_forward:  Cashback for **{}@** ({}) skipped: {reason}",
+        cashback.name, cashback.name_id
+    ))
+    .await?;
+
+    Ok(())
+}
+
+/// Name under which Discord actions are written to the audit log.
+fn actor(ctx: &Context<'_>) -> String {
+    format!("discord:{}", ctx.author().name)
+}
+
 pub async fn run(
     config: DiscordConfig,
-    mut rx: mpsc::UnboundedReceiver<DiscordMessage>,
+    storage: Arc<dyn Storage>,
+    rx: mpsc::Receiver<DiscordMessage>,
+    shutdown: CancellationToken,
+    readiness: Readiness,
 ) -> Result<()> {
     let token = config.token;
     let mut channels = HashMap::new();
@@ -43,47 +178,21 @@ pub async fn run(
 
     let framework = poise::Framework::builder()
         .options(poise::FrameworkOptions {
-            commands: vec![age()],
+            commands: vec![
+                age(),
+                pause(),
+                resume(),
+                retry(),
+                mark_paid(),
+                approve(),
+                skip(),
+            ],
             ..Default::default()
         })
         .setup(|ctx, _ready, framework| {
-            let http = Arc::clone(&ctx.http);
-            tokio::spawn(async move {
-                while let Some(message) = rx.recv().await {
-                    match message {
-                        DiscordMessage::CashbackInitiated(currency_id, (name, nameid)) => {
-                            info!("got message to send to discord");
-
-                            let channel_id = channels.get(&currency_id).unwrap();
-                            serenity::ChannelId::new(*channel_id)
-                                .send_message(
-                                    &http,
-                                    serenity::CreateMessage::new().content(format!(
-                                        ":sparkles:  **{}@** ({}) initiated cashback",
-                                        name, nameid
-                                    )),
-                                )
-                                .await
-                                .unwrap();
-                        }
-                        DiscordMessage::CashbackProcessed(currency_id, (name, name_id), explorer_link) => {
-                            let channel_id = channels.get(&currency_id).unwrap();
-                            serenity::ChannelId::new(*channel_id)
-                                .send_message(
-                                    &http,
-                                    serenity::CreateMessage::new()
-                                        .content(format!(":moneybag:  Cashback processed for **{name}@** ({name_id}): [{explorer_link}]")),
-                                )
-                                .await
-                                .unwrap();
-                        }
-                    }
-                }
-            });
-
             Box::pin(async move {
                 poise::builtins::register_globally(ctx, &framework.options().commands).await?;
-                Ok(Data {})
+                Ok(Data { storage })
             })
         })
         .build();
@@ -92,16 +201,115 @@ pub async fn run(
         | serenity::GatewayIntents::GUILD_MEMBERS
         | serenity::GatewayIntents::MESSAGE_CONTENT;
 
-    let client = serenity::ClientBuilder::new(token, intents)
+    let mut client = serenity::ClientBuilder::new(token, intents)
         .framework(framework)
-        .await;
+        .await?;
+
+    // Messages go out over http, they don't wait for the gateway to connect.
+    let forwarder = tokio::spawn(forward(Arc::clone(&client.http), channels, rx));
+    let shard_manager = Arc::clone(&client.shard_manager);
+    readiness.set_shard_manager(Arc::clone(&shard_manager));
 
-    client?.start().await?;
+    tokio::select! {
+        result = client.start() => result?,
+        _ = shutdown.cancelled() => {}
+    }
+
+    // The queue closes once every checker has stopped, everything they queued until then is
+    // still sent.
+    info!("flushing discord messages");
+    forwarder.await?;
+    shard_manager.shutdown_all().await;
 
     Ok(())
 }
 
+/// Sends the messages of the checkers to the channel of their chain, until every sender is
+/// dropped.
+async fn forward(
+    http: Arc<serenity::Http>,
+    channels: HashMap<Address, u64>,
+    mut rx: mpsc::Receiver<DiscordMessage>,
+) {
+    while let Some(message) = rx.recv().await {
+        telemetry::set_discord_queue_depth(rx.len());
+
+        let (currency_id, content) = match message {
+            DiscordMessage::RegistrationSeen(currency_id, (name, name_id)) => (
+                currency_id,
+                format!(
+                    ":eyes:  **{name}@** ({name_id}) registration seen, waiting for it to be mined"
+                ),
+            ),
+            DiscordMessage::CashbackInitiated(currency_id, (name, name_id)) => (
+                currency_id,
+                format!(":sparkles:  **{name}@** ({name_id}) initiated cashback"),
+            ),
+            DiscordMessage::CashbackProcessed(currency_id, (name, name_id), explorer_link) => (
+                currency_id,
+                format!(
+                    ":moneybag:  Cashback processed for **{name}@** ({name_id}): [{explorer_link}]"
+                ),
+            ),
+            DiscordMessage::PayoutDropped(currency_id, (name, name_id), reason) => (
+                currency_id,
+                format!(
+                    ":warning:  Cashback payout for **{name}@** ({name_id}) was dropped ({reason}), it can be retried"
+                ),
+            ),
+            DiscordMessage::PayoutUnknown(currency_id, (name, name_id), reason) => (
+                currency_id,
+                format!(
+                    ":warning:  Not known whether the cashback payout for **{name}@** ({name_id}) was sent ({reason}), it is held until the wallet shows it"
+                ),
+            ),
+            DiscordMessage::LedgerDifference(currency_id, ledger, wallet) if ledger == wallet => (
+                currency_id,
+                format!(":white_check_mark:  Ledger matches the wallet balance again ({wallet} sats)"),
+            ),
+            DiscordMessage::LedgerDifference(currency_id, ledger, wallet) => (
+                currency_id,
+                format!(
+                    ":scales:  Wallet balance ({wallet} sats) differs from the ledger ({ledger} sats) by {} sats",
+                    wallet - ledger
+                ),
+            ),
+            DiscordMessage::ChainFailing(currency_id, restarts, error) => (
+                currency_id,
+                format!(
+                    ":rotating_light:  Checker keeps failing ({restarts} restarts so far): {error}"
+                ),
+            ),
+        };
+
+        let Some(channel_id) = channels.get(&currency_id) else {
+            warn!("no discord channel for {currency_id}, dropping: {content}");
+            telemetry::discord_delivery_failed(&currency_id);
+            continue;
+        };
+
+        if let Err(e) = serenity::ChannelId::new(*channel_id)
+            .send_message(&http, serenity::CreateMessage::new().content(&content))
+            .await
+        {
+            error!("failed to send to discord channel of {currency_id}: {e}, dropping: {content}");
+            telemetry::discord_delivery_failed(&currency_id);
+        }
+    }
+}
+
+#[derive(Debug)]
 pub enum DiscordMessage {
+    RegistrationSeen(Address, (String, Address)),
     CashbackInitiated(Address, (String, Address)),
     CashbackProcessed(Address, (String, Address), String),
+    /// A broadcast payout was dropped or conflicted, with the reason.
+    PayoutDropped(Address, (String, Address), String),
+    /// It is not known whether a payout was sent, with the reason. It is held until that is
+    /// known.
+    PayoutUnknown(Address, (String, Address), String),
+    /// The ledger balance and the wallet balance, sent when the difference between them changed.
+    LedgerDifference(Address, i64, i64),
+    /// The checker of a chain failed repeatedly, with the number of restarts and the last error.
+    ChainFailing(Address, u32, String),
 }
@@ -0,0 +1,308 @@
+//! Exports paid cashbacks for accounting, as CSV or JSON, with totals per chain and per referral
+//! identity, in each currency the payouts were made in.
+//!
+//! Amounts, fees and blocks are the ones recorded with each payout. Cashbacks that were paid
+//! before they were recorded, or that were paid by hand, export without them and are totalled
+//! under an unknown currency.
+
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use vrsc_rpc::{bitcoin::Txid, json::vrsc::Address};
+
+use crate::storage::{PaidRange, Storage};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    Csv,
+    Json,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Row {
+    pub currency_id: Address,
+    pub referral_id: Option<Address>,
+    pub name: String,
+    pub name_id: Address,
+    /// Sent to the identity, in satoshis.
+    pub amount: Option<u64>,
+    /// Withheld from the referral reward, including the network fee, in satoshis.
+    pub fee: Option<u64>,
+    pub payout_currency: Option<Address>,
+    pub txid: Option<Txid>,
+    pub registration_txid: Option<Txid>,
+    /// The block the registration was mined in.
+    pub detected_block_height: Option<u64>,
+    /// The block the payout was mined in.
+    pub block_height: Option<u64>,
+    pub detected_at: DateTime<Utc>,
+    pub paid_at: Option<DateTime<Utc>>,
+    /// Paid outside of this service, so there is no amount, fee or block.
+    pub paid_manually: bool,
+}
+
+/// The payout currency of the rows that have none recorded.
+const UNKNOWN_CURRENCY: &str = "unknown";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Total {
+    pub cashbacks: u64,
+    /// Of the cashbacks, the ones that were paid by hand and add nothing to the amount and fee.
+    pub paid_manually: u64,
+    pub amount: u64,
+    pub fee: u64,
+}
+
+impl Total {
+    fn add(&mut self, row: &Row) {
+        self.cashbacks += 1;
+        if row.paid_manually {
+            self.paid_manually += 1;
+        }
+        self.amount += row.amount.unwrap_or_default();
+        self.fee += row.fee.unwrap_or_default();
+    }
+}
+
+/// Totals by chain and by referral, then by payout currency. Amounts in different currencies
+/// are not added up.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Totals {
+    pub chains: BTreeMap<String, BTreeMap<String, Total>>,
+    pub referrals: BTreeMap<String, BTreeMap<String, Total>>,
+}
+
+impl Totals {
+    pub fn of(rows: &[Row]) -> Self {
+        let mut totals = Self::default();
+
+        for row in rows {
+            let payout_currency = row
+                .payout_currency
+                .as_ref()
+                .map_or_else(|| UNKNOWN_CURRENCY.to_owned(), ToString::to_string);
+
+            totals
+                .chains
+                .entry(row.currency_id.to_string())
+                .or_default()
+                .entry(payout_currency.clone())
+                .or_default()
+                .add(row);
+
+            if let Some(referral_id) = &row.referral_id {
+                totals
+                    .referrals
+                    .entry(referral_id.to_string())
+                    .or_default()
+                    .entry(payout_currency)
+                    .or_default()
+                    .add(row);
+            }
+        }
+
+        totals
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct TotalRow<'a> {
+    group: &'static str,
+    key: &'a str,
+    payout_currency: &'a str,
+    cashbacks: u64,
+    paid_manually: u64,
+    amount: u64,
+    fee: u64,
+}
+
+/// Collects the paid cashbacks of `chain`, or of every chain, that were paid in `range`.
+pub async fn rows(
+    storage: &dyn Storage,
+    chain: Option<&Address>,
+    range: &PaidRange,
+) -> Result<Vec<Row>> {
+    Ok(storage
+        .get_paid_cashbacks(chain, range)
+        .await?
+        .into_iter()
+        .map(|cashback| Row {
+            currency_id: cashback.currency_id,
+            referral_id: cashback.referral_id,
+            name: cashback.name,
+            name_id: cashback.name_id,
+            amount: cashback.amount,
+            fee: cashback.fee,
+            payout_currency: cashback.payout_currency,
+            txid: cashback.txid,
+            registration_txid: cashback.registration_txid,
+            detected_block_height: cashback.detected_block_height,
+            block_height: cashback.payout_block_height,
+            detected_at: cashback.created_at,
+            paid_at: cashback.paid_at,
+            paid_manually: cashback.paid_manually,
+        })
+        .collect())
+}
+
+/// Renders the rows, or with `totals` only their totals, in `format`.
+pub fn render(format: Format, rows: &[Row], totals: bool) -> Result<String> {
+    match (format, totals) {
+        (Format::Json, false) => Ok(serde_json::to_string_pretty(&serde_json::json!({
+            "rows": rows,
+            "totals": Totals::of(rows),
+        }))?),
+        (Format::Json, true) => Ok(serde_json::to_string_pretty(&Totals::of(rows))?),
+        (Format::Csv, false) => write_csv(rows),
+        (Format::Csv, true) => {
+            let totals = Totals::of(rows);
+            let total_rows = [("chain", &totals.chains), ("referral", &totals.referrals)]
+                .into_iter()
+                .flat_map(|(group, totals)| {
+                    totals.iter().flat_map(move |(key, by_currency)| {
+                        by_currency
+                            .iter()
+                            .map(move |(payout_currency, total)| TotalRow {
+                                group,
+                                key,
+                                payout_currency,
+                                cashbacks: total.cashbacks,
+                                paid_manually: total.paid_manually,
+                                amount: total.amount,
+                                fee: total.fee,
+                            })
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            write_csv(&total_rows)
+        }
+    }
+}
+
+fn write_csv<T: Serialize>(records: &[T]) -> Result<String> {
+    let mut writer = csv::Writer::from_writer(vec![]);
+    for record in records {
+        writer.serialize(record)?;
+    }
+
+    Ok(String::from_utf8(writer.into_inner()?)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        fixtures::{alice, bob, chain_id, other_referral_id, referral_id},
+        mock_rpc,
+    };
+
+    /// A payout to `name_id` of 9 000 000 with a fee of 1 000 000 in `payout_currency`, or one
+    /// made by hand without them.
+    fn row(name_id: Address, payout_currency: Option<Address>) -> Row {
+        let paid_manually = payout_currency.is_none();
+
+        Row {
+            currency_id: chain_id(),
+            referral_id: Some(referral_id()),
+            name: "alice".to_owned(),
+            name_id,
+            amount: (!paid_manually).then_some(9_000_000),
+            fee: (!paid_manually).then_some(1_000_000),
+            payout_currency,
+            txid: Some(mock_rpc::txid(2)),
+            registration_txid: Some(mock_rpc::txid(1)),
+            detected_block_height: Some(100),
+            block_height: (!paid_manually).then_some(110),
+            detected_at: DateTime::from_timestamp(1_700_000_000, 0).unwrap(),
+            paid_at: DateTime::from_timestamp(1_700_000_600, 0),
+            paid_manually,
+        }
+    }
+
+    /// Two payouts in the currency of the chain, one in another currency and one made by hand.
+    fn rows() -> Vec<Row> {
+        vec![
+            row(alice(), Some(chain_id())),
+            row(bob(), Some(chain_id())),
+            row(alice(), Some(other_referral_id())),
+            row(bob(), None),
+        ]
+    }
+
+    fn total(cashbacks: u64, paid_manually: u64) -> Total {
+        let paid = cashbacks - paid_manually;
+
+        Total {
+            cashbacks,
+            paid_manually,
+            amount: paid * 9_000_000,
+            fee: paid * 1_000_000,
+        }
+    }
+
+    #[test]
+    fn totals_each_payout_currency_apart() {
+        let totals = Totals::of(&rows());
+
+        let expected = BTreeMap::from([
+            (chain_id().to_string(), total(2, 0)),
+            (other_referral_id().to_string(), total(1, 0)),
+            (UNKNOWN_CURRENCY.to_owned(), total(1, 1)),
+        ]);
+        assert_eq!(
+            totals.chains,
+            BTreeMap::from([(chain_id().to_string(), expected.clone())])
+        );
+        assert_eq!(
+            totals.referrals,
+            BTreeMap::from([(referral_id().to_string(), expected)])
+        );
+    }
+
+    #[test]
+    fn renders_csv() {
+        let rows = rows();
+
+        let csv = render(Format::Csv, &rows, false).unwrap();
+        let mut lines = csv.lines();
+        assert!(lines.next().unwrap().ends_with(",paid_at,paid_manually"));
+        assert_eq!(lines.clone().count(), rows.len());
+        assert!(lines.last().unwrap().ends_with(",true"));
+
+        let csv = render(Format::Csv, &rows, true).unwrap();
+        let lines = csv.lines().collect::<Vec<_>>();
+        assert_eq!(
+            lines[0],
+            "group,key,payout_currency,cashbacks,paid_manually,amount,fee"
+        );
+        let paid = format!("chain,{0},{0},2,0,18000000,2000000", chain_id());
+        assert!(lines.contains(&paid.as_str()));
+        let manual = format!("chain,{},unknown,1,1,0,0", chain_id());
+        assert!(lines.contains(&manual.as_str()));
+        // Three payout currencies for the chain and for the referral.
+        assert_eq!(lines.len(), 1 + 2 * 3);
+    }
+
+    #[test]
+    fn renders_json() {
+        let rows = rows();
+
+        let json: serde_json::Value =
+            serde_json::from_str(&render(Format::Json, &rows, false).unwrap()).unwrap();
+        assert_eq!(json["rows"].as_array().unwrap().len(), rows.len());
+        assert_eq!(json["rows"][3]["paid_manually"], true);
+        assert_eq!(json["rows"][3]["amount"], serde_json::Value::Null);
+        let manual = &json["totals"]["chains"][chain_id().to_string()][UNKNOWN_CURRENCY];
+        assert_eq!(manual["cashbacks"], 1);
+        assert_eq!(manual["paid_manually"], 1);
+
+        let totals: serde_json::Value =
+            serde_json::from_str(&render(Format::Json, &rows, true).unwrap()).unwrap();
+        assert_eq!(totals, json["totals"]);
+    }
+}
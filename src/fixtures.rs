@@ -0,0 +1,139 @@
+//! Identities and cashbacks in their various states, shared by the storage and the checker tests.
+
+use std::str::FromStr;
+
+use vrsc_rpc::{bitcoin::BlockHash, json::vrsc::Address};
+
+use crate::{
+    constants::{Cashback, CashbackStatus},
+    ledger::{self, Account, EntryKind},
+    mock_rpc,
+    storage::{Payout, Registration, Storage},
+};
+
+pub const REFERRAL_AMOUNT: u64 = 10_000_000;
+pub const FEE: u64 = 1_000_000;
+
+pub fn address(address: &str) -> Address {
+    Address::from_str(address).unwrap()
+}
+
+pub fn chain_id() -> Address {
+    address("i5w5MuNik5NtLcYmNzcvaoixooEebB6MGV")
+}
+
+/// Our referral.
+pub fn referral_id() -> Address {
+    address("iExBJfZYK7KREDpuhj6PzZBzqMAKaFg7d2")
+}
+
+pub fn other_referral_id() -> Address {
+    address("i9nwxtKuVYX4MSbeULLiK2ttVi6rUEhh4X")
+}
+
+pub fn alice() -> Address {
+    address("iJhCezBExJHvtyH3fGhNnt2NhU4Ztkf2yq")
+}
+
+pub fn bob() -> Address {
+    address("iFJNtZZWkqwdFDWKymMqpsL7H9Puua83Ca")
+}
+
+pub fn block_hash(height: u64) -> BlockHash {
+    BlockHash::from_str(&format!("{height:064x}")).unwrap()
+}
+
+/// Stores the registration of `name_id` with our referral in transaction `n`, mined at `height`
+/// or in the mempool.
+pub async fn store(
+    storage: &dyn Storage,
+    name_id: &Address,
+    n: u64,
+    status: CashbackStatus,
+    height: Option<u64>,
+) -> bool {
+    let hash = height.map(block_hash);
+
+    storage
+        .store_cashback(
+            &chain_id(),
+            name_id,
+            "alice",
+            status,
+            Registration {
+                txid: &mock_rpc::txid(n),
+                referral_id: &referral_id(),
+                block: height.zip(hash.as_ref()),
+            },
+            &[],
+        )
+        .await
+        .unwrap()
+}
+
+pub async fn cashback(storage: &dyn Storage, name_id: &Address) -> Cashback {
+    storage
+        .get_chain_cashback_by_name_id(&chain_id(), name_id)
+        .await
+        .unwrap()
+        .unwrap()
+}
+
+/// Stores a pending cashback for `alice` and starts sending its payout.
+pub async fn sending(storage: &dyn Storage) -> Cashback {
+    store(storage, &alice(), 1, CashbackStatus::Pending, Some(100)).await;
+    let pending = cashback(storage, &alice()).await;
+
+    let payout = Payout {
+        amount: REFERRAL_AMOUNT - FEE,
+        fee: FEE,
+        currency: &chain_id(),
+    };
+    assert!(storage.start_payout(&pending.id, payout).await.unwrap());
+    assert!(!storage.start_payout(&pending.id, payout).await.unwrap());
+
+    cashback(storage, &alice()).await
+}
+
+/// Stores a pending cashback for `alice` and broadcasts its payout in transaction `n`.
+pub async fn broadcast(storage: &dyn Storage, n: u64) -> Cashback {
+    let sending = sending(storage).await;
+    assert!(storage
+        .update_cashback(&sending.id, &mock_rpc::txid(n))
+        .await
+        .unwrap());
+
+    cashback(storage, &alice()).await
+}
+
+/// The entries of the payout of [`sending`], once it is confirmed.
+pub fn payout_entries() -> Vec<ledger::Entry> {
+    [
+        (
+            EntryKind::Payout,
+            Account::Identity,
+            (REFERRAL_AMOUNT - FEE) as i64,
+        ),
+        (
+            EntryKind::NetworkFee,
+            Account::Network,
+            ledger::NETWORK_FEE as i64,
+        ),
+    ]
+    .into_iter()
+    .flat_map(|(kind, account, amount)| {
+        [
+            ledger::Entry {
+                kind,
+                account: Account::Wallet,
+                amount: -amount,
+            },
+            ledger::Entry {
+                kind,
+                account,
+                amount,
+            },
+        ]
+    })
+    .collect()
+}
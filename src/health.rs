@@ -0,0 +1,271 @@
+//! Tracks whether the daemon of a chain can be relied on for payouts, and backs off when calls
+//! to it keep failing.
+
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use serde::Serialize;
+use tracing::*;
+use vrsc_rpc::json::vrsc::Address;
+
+use crate::{
+    chain::{ChainInfo, ChainRpc},
+    rpc,
+};
+
+/// Consecutive failures after which the circuit opens.
+const FAILURE_THRESHOLD: u32 = 3;
+const INITIAL_BACKOFF: Duration = Duration::from_secs(10);
+const MAX_BACKOFF: Duration = Duration::from_secs(600);
+/// Number of blocks the daemon may be behind its best header and still count as synced.
+const SYNC_TOLERANCE: u64 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum DaemonStatus {
+    Unreachable,
+    /// The daemon is starting up or reindexing and rejects calls.
+    WarmingUp,
+    Syncing {
+        blocks: u64,
+        headers: u64,
+    },
+    Synced {
+        blocks: u64,
+    },
+}
+
+#[derive(Debug)]
+pub struct DaemonHealth {
+    currency_id: Address,
+    status: DaemonStatus,
+    failures: u32,
+    open_until: Option<Instant>,
+}
+
+impl DaemonHealth {
+    pub fn new(currency_id: Address) -> Self {
+        Self {
+            currency_id,
+            status: DaemonStatus::Unreachable,
+            failures: 0,
+            open_until: None,
+        }
+    }
+
+    pub fn status(&self) -> DaemonStatus {
+        self.status
+    }
+
+    /// Asks the daemon for its state. Only errors that are not about the daemon itself, such as
+    /// a timeout of the blocking pool, are returned.
+    pub async fn check(&mut self, rpc: &dyn ChainRpc) -> Result<DaemonStatus> {
+        let status = classify(rpc.blockchain_info().await)?;
+
+        if status != self.status {
+            match status {
+                DaemonStatus::Synced { blocks } => {
+                    info!("{}: daemon synced at height {blocks}", self.currency_id)
+                }
+                other => warn!("{}: daemon is not ready: {other:?}", self.currency_id),
+            }
+        }
+        self.status = status;
+
+        Ok(status)
+    }
+
+    /// Whether calls may be made, false while the circuit is open.
+    pub fn allows_request(&self) -> bool {
+        self.open_until
+            .map_or(true, |open_until| Instant::now() >= open_until)
+    }
+
+    pub fn record_success(&mut self) {
+        if self.failures >= FAILURE_THRESHOLD {
+            info!(
+                "{}: recovered after {} failures",
+                self.currency_id, self.failures
+            );
+        }
+
+        self.failures = 0;
+        self.open_until = None;
+    }
+
+    /// Counts a failure and opens the circuit once there were too many in a row. Only the
+    /// failures up to the one that opens the circuit are logged as errors.
+    pub fn record_failure(&mut self, e: &anyhow::Error) {
+        self.failures += 1;
+
+        if self.failures < FAILURE_THRESHOLD {
+            error!("{}: {e:?}", self.currency_id);
+            return;
+        }
+
+        let backoff = backoff(self.failures);
+        self.open_until = Some(Instant::now() + backoff);
+
+        if self.failures == FAILURE_THRESHOLD {
+            error!(
+                "{}: {e:?}, backing off for {backoff:?} after {} failures",
+                self.currency_id, self.failures
+            );
+        } else {
+            debug!(
+                "{}: still failing ({e:#}), backing off for {backoff:?}",
+                self.currency_id
+            );
+        }
+    }
+}
+
+/// What the answer to `getblockchaininfo` says about the daemon.
+fn classify(info: Result<ChainInfo>) -> Result<DaemonStatus> {
+    match info {
+        Ok(info) if info.headers > info.blocks + SYNC_TOLERANCE => Ok(DaemonStatus::Syncing {
+            blocks: info.blocks,
+            headers: info.headers,
+        }),
+        Ok(info) => Ok(DaemonStatus::Synced {
+            blocks: info.blocks,
+        }),
+        Err(e) if rpc::is_warmup_error(&e) => Ok(DaemonStatus::WarmingUp),
+        Err(e) if rpc::is_transport_error(&e) => Ok(DaemonStatus::Unreachable),
+        Err(e) => Err(e),
+    }
+}
+
+/// How long the circuit stays open after `failures` in a row, doubling from the failure that
+/// opened it.
+fn backoff(failures: u32) -> Duration {
+    let exponent = failures.saturating_sub(FAILURE_THRESHOLD).min(16);
+
+    INITIAL_BACKOFF
+        .saturating_mul(2u32.saturating_pow(exponent))
+        .min(MAX_BACKOFF)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    fn health() -> DaemonHealth {
+        DaemonHealth::new(Address::from_str("i5w5MuNik5NtLcYmNzcvaoixooEebB6MGV").unwrap())
+    }
+
+    fn failure() -> anyhow::Error {
+        anyhow::anyhow!("connection refused")
+    }
+
+    fn rpc_error(error: jsonrpc::Error) -> anyhow::Error {
+        anyhow::Error::new(vrsc_rpc::Error::JsonRpc(error))
+    }
+
+    fn info(blocks: u64, headers: u64) -> Result<ChainInfo> {
+        Ok(ChainInfo { blocks, headers })
+    }
+
+    #[test]
+    fn opens_the_circuit_after_three_failures() {
+        let mut health = health();
+
+        for _ in 1..FAILURE_THRESHOLD {
+            health.record_failure(&failure());
+            assert!(health.allows_request());
+        }
+
+        let before = Instant::now();
+        health.record_failure(&failure());
+        assert!(!health.allows_request());
+
+        let open_until = health.open_until.unwrap();
+        assert!(open_until >= before + INITIAL_BACKOFF);
+        assert!(open_until <= Instant::now() + INITIAL_BACKOFF);
+    }
+
+    #[test]
+    fn lets_a_call_through_once_the_backoff_is_over() {
+        let mut health = health();
+        for _ in 0..FAILURE_THRESHOLD {
+            health.record_failure(&failure());
+        }
+        assert!(!health.allows_request());
+
+        // Half open: the next call is a probe, its failure opens the circuit for longer.
+        health.open_until = Some(Instant::now());
+        assert!(health.allows_request());
+
+        let before = Instant::now();
+        health.record_failure(&failure());
+        assert!(!health.allows_request());
+        assert!(health.open_until.unwrap() >= before + INITIAL_BACKOFF * 2);
+    }
+
+    #[test]
+    fn closes_the_circuit_on_success() {
+        let mut health = health();
+        for _ in 0..FAILURE_THRESHOLD + 2 {
+            health.record_failure(&failure());
+        }
+
+        health.record_success();
+        assert!(health.allows_request());
+        assert_eq!(health.failures, 0);
+
+        // Counting starts over.
+        for _ in 1..FAILURE_THRESHOLD {
+            health.record_failure(&failure());
+        }
+        assert!(health.allows_request());
+    }
+
+    #[test]
+    fn doubles_the_backoff_up_to_the_cap() {
+        assert_eq!(backoff(FAILURE_THRESHOLD), INITIAL_BACKOFF);
+        assert_eq!(backoff(FAILURE_THRESHOLD + 1), INITIAL_BACKOFF * 2);
+        assert_eq!(backoff(FAILURE_THRESHOLD + 5), INITIAL_BACKOFF * 32);
+        assert_eq!(backoff(FAILURE_THRESHOLD + 6), MAX_BACKOFF);
+        assert_eq!(backoff(u32::MAX), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn classifies_the_daemon() {
+        assert_eq!(
+            classify(info(100, 100)).unwrap(),
+            DaemonStatus::Synced { blocks: 100 }
+        );
+        assert_eq!(
+            classify(info(100, 100 + SYNC_TOLERANCE)).unwrap(),
+            DaemonStatus::Synced { blocks: 100 }
+        );
+        assert_eq!(
+            classify(info(100, 200)).unwrap(),
+            DaemonStatus::Syncing {
+                blocks: 100,
+                headers: 200
+            }
+        );
+
+        let warmup = rpc_error(jsonrpc::Error::Rpc(jsonrpc::error::RpcError {
+            code: -28,
+            message: "Loading block index...".to_owned(),
+            data: None,
+        }));
+        assert_eq!(classify(Err(warmup)).unwrap(), DaemonStatus::WarmingUp);
+
+        let unreachable = rpc_error(jsonrpc::Error::Transport(Box::new(std::io::Error::new(
+            std::io::ErrorKind::ConnectionRefused,
+            "connection refused",
+        ))));
+        assert_eq!(
+            classify(Err(unreachable)).unwrap(),
+            DaemonStatus::Unreachable
+        );
+
+        // Errors that are not about the daemon are passed on.
+        assert!(classify(Err(failure())).is_err());
+    }
+}
@@ -0,0 +1,76 @@
+//! The http server for monitoring, the REST API and the admin API.
+
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use axum::{extract::State, http::StatusCode, routing::get, Json, Router};
+use metrics_exporter_prometheus::PrometheusHandle;
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+use tracing::*;
+
+use crate::{
+    admin_api::{self, Rescans},
+    api,
+    config::HttpConfig,
+    readiness::{Readiness, Report},
+    storage::Storage,
+};
+
+#[derive(Clone)]
+pub struct AppState {
+    pub storage: Arc<dyn Storage>,
+    pub metrics: PrometheusHandle,
+    pub readiness: Readiness,
+    pub rescans: Rescans,
+    /// Cancelled when the service shuts down, for work the API starts in the background.
+    pub shutdown: CancellationToken,
+}
+
+/// Serves until `shutdown` is cancelled.
+pub async fn serve(config: HttpConfig, state: AppState, shutdown: CancellationToken) -> Result<()> {
+    let app = Router::new()
+        .route("/metrics", get(metrics))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .nest("/api", api::router());
+
+    let app = if config.admin_tokens.is_empty() {
+        app
+    } else {
+        app.nest("/admin", admin_api::router(config.admin_tokens))
+    }
+    .with_state(state);
+
+    let listener = TcpListener::bind(config.listen_address)
+        .await
+        .with_context(|| format!("failed to listen on {}", config.listen_address))?;
+    info!("http server listening on {}", config.listen_address);
+
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown.cancelled_owned())
+        .await?;
+
+    Ok(())
+}
+
+async fn metrics(State(state): State<AppState>) -> String {
+    state.metrics.render()
+}
+
+/// The process is up and serving requests.
+async fn healthz() -> &'static str {
+    "ok"
+}
+
+/// Whether every component works, with a report of each. Answers 503 when any is degraded.
+async fn readyz(State(state): State<AppState>) -> (StatusCode, Json<Report>) {
+    let report = state.readiness.report(state.storage.as_ref()).await;
+    let status = if report.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (status, Json(report))
+}
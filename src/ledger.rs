@@ -0,0 +1,215 @@
+//! Double-entry ledger of the wallet that pays the cashbacks. Every movement of funds is posted as
+//! a transfer of entries that sum to zero, and reconciling compares the `wallet` account with the
+//! balance the daemon reports.
+
+use anyhow::Result;
+use serde::Serialize;
+use uuid::Uuid;
+use vrsc_rpc::{bitcoin::Txid, json::vrsc::Address};
+
+use crate::{
+    chain::ChainRpc,
+    constants::{Cashback, CashbackStatus},
+    storage::{CashbackFilter, Storage},
+};
+
+/// Budget for the network fee of a payout, withheld from the fee output to the referral.
+pub const NETWORK_FEE: u64 = 20000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Account {
+    /// The wallet the payouts are sent from.
+    Wallet,
+    /// The identities that registered with the referral.
+    Identity,
+    /// The referral identity, which gets the rest of the fee.
+    Referral,
+    /// Fees paid to the miners.
+    Network,
+    /// Where top-ups of the wallet come from.
+    Funding,
+    /// Registrations that pay the referral reward to a referral identity of the wallet.
+    Registrations,
+}
+
+impl Account {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Account::Wallet => "wallet",
+            Account::Identity => "identity",
+            Account::Referral => "referral",
+            Account::Network => "network",
+            Account::Funding => "funding",
+            Account::Registrations => "registrations",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    Payout,
+    FeeOutput,
+    NetworkFee,
+    TopUp,
+    ReferralReward,
+}
+
+impl EntryKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EntryKind::Payout => "payout",
+            EntryKind::FeeOutput => "fee_output",
+            EntryKind::NetworkFee => "network_fee",
+            EntryKind::TopUp => "top_up",
+            EntryKind::ReferralReward => "referral_reward",
+        }
+    }
+}
+
+/// One side of a transfer, in satoshis. Negative amounts leave the account.
+#[derive(Debug, Clone, Copy)]
+pub struct Entry {
+    pub kind: EntryKind,
+    pub account: Account,
+    pub amount: i64,
+}
+
+/// Both entries of moving `amount` from one account to another.
+fn transfer(kind: EntryKind, from: Account, to: Account, amount: u64) -> [Entry; 2] {
+    [
+        Entry {
+            kind,
+            account: from,
+            amount: -(amount as i64),
+        },
+        Entry {
+            kind,
+            account: to,
+            amount: amount as i64,
+        },
+    ]
+}
+
+/// Whether the referral identity belongs to the reconciled wallet, so the referral rewards of
+/// registrations come in to it and the fee outputs of payouts don't leave it. That is when the
+/// identity is the payout address, or without a payout address when the wallet can spend for it.
+pub async fn referral_in_wallet(
+    rpc: &dyn ChainRpc,
+    referral_id: &Address,
+    payout_address: Option<&str>,
+) -> Result<bool> {
+    match payout_address {
+        Some(payout_address) => Ok(payout_address == referral_id.to_string()),
+        None => rpc.can_spend_for(referral_id).await,
+    }
+}
+
+/// The entries of the referral reward a mined registration paid to the wallet, none when the
+/// referral identity is not in the wallet.
+pub fn referral_reward_entries(referral_amount: u64, referral_in_wallet: bool) -> Vec<Entry> {
+    if !referral_in_wallet {
+        return Vec::new();
+    }
+
+    transfer(
+        EntryKind::ReferralReward,
+        Account::Registrations,
+        Account::Wallet,
+        referral_amount,
+    )
+    .to_vec()
+}
+
+/// The entries of a confirmed payout: the cashback, the fee output to the referral and the
+/// network fee the wallet actually paid. The fee output stays in the wallet when the referral
+/// identity is in it, and is left out.
+pub fn payout_entries(
+    cashback: &Cashback,
+    network_fee: u64,
+    referral_in_wallet: bool,
+) -> Vec<Entry> {
+    let amount = cashback.amount.unwrap_or_default();
+    let fee_output = if referral_in_wallet {
+        0
+    } else {
+        cashback.fee.unwrap_or_default().saturating_sub(NETWORK_FEE)
+    };
+
+    [
+        (EntryKind::Payout, Account::Identity, amount),
+        (EntryKind::FeeOutput, Account::Referral, fee_output),
+        (EntryKind::NetworkFee, Account::Network, network_fee),
+    ]
+    .into_iter()
+    .filter(|(_, _, amount)| *amount > 0)
+    .flat_map(|(kind, to, amount)| transfer(kind, Account::Wallet, to, amount))
+    .collect()
+}
+
+/// Records funds that were sent to the wallet from outside.
+pub async fn record_top_up(
+    storage: &dyn Storage,
+    currency_id: &Address,
+    amount: u64,
+    txid: Option<&Txid>,
+    note: Option<&str>,
+) -> Result<Uuid> {
+    let entries = transfer(EntryKind::TopUp, Account::Funding, Account::Wallet, amount);
+
+    storage
+        .post_ledger_transfer(currency_id, &entries, None, txid, note)
+        .await
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Reconciliation {
+    /// Balance of the `wallet` account, in satoshis.
+    pub ledger_balance: i64,
+    /// Balance the daemon reports, including unconfirmed transactions, in satoshis.
+    pub wallet_balance: i64,
+    /// What the wallet has more than the ledger accounts for.
+    pub difference: i64,
+    /// Whether the difference changed since the previous reconciliation.
+    pub changed: bool,
+}
+
+/// Compares the ledger with the balance of `payout_address`, or of the whole wallet, and records
+/// the result when the difference changed. Returns `None` while payouts are being sent or
+/// waiting for confirmations, or registrations are in the mempool, their entries are only
+/// posted once they are mined.
+pub async fn reconcile(
+    storage: &dyn Storage,
+    rpc: &dyn ChainRpc,
+    currency_id: &Address,
+    payout_address: Option<&str>,
+) -> Result<Option<Reconciliation>> {
+    let mempool = CashbackFilter {
+        status: Some(CashbackStatus::Mempool),
+        ..Default::default()
+    };
+    if !storage.get_sending_payouts(currency_id).await?.is_empty()
+        || !storage.get_broadcast_payouts(currency_id).await?.is_empty()
+        || storage.list_cashbacks(currency_id, &mempool, 0, 0).await?.1 > 0
+    {
+        return Ok(None);
+    }
+
+    let ledger_balance = storage.ledger_balance(currency_id, Account::Wallet).await?;
+    let wallet_balance = rpc.wallet_balance(payout_address).await?;
+    let difference = wallet_balance - ledger_balance;
+
+    let previous = storage.last_reconciliation_difference(currency_id).await?;
+    let changed = previous != Some(difference);
+    if changed {
+        storage
+            .insert_reconciliation(currency_id, ledger_balance, wallet_balance)
+            .await?;
+    }
+
+    Ok(Some(Reconciliation {
+        ledger_balance,
+        wallet_balance,
+        difference,
+        changed,
+    }))
+}
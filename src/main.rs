@@ -1,15 +1,24 @@
-use std::{str::FromStr, time::Duration};
+use std::{sync::Arc, time::Duration};
 
+use admin_api::Rescans;
 use anyhow::Result;
+use clap::Parser;
+use cli::{actor, Cli, Command};
 use config::{
     get_configuration,
-    pbaas::{self, pbaas_chain_configs},
+    pbaas::{pbaas_chain_config, pbaas_chain_configs},
+    Config,
 };
 use discord::DiscordMessage;
-use poise::serenity_prelude::futures::{future::join_all, stream::FuturesUnordered};
-use rpc::Client;
-use sqlx::PgPool;
-use tokio::sync::mpsc;
+use http::AppState;
+use readiness::Readiness;
+use storage::Storage;
+use supervisor::Chains;
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::mpsc,
+};
+use tokio_util::sync::CancellationToken;
 use tracing::*;
 use tracing_subscriber::{
     fmt::{self, writer::MakeWriterExt},
@@ -17,231 +26,249 @@ use tracing_subscriber::{
     util::SubscriberInitExt,
     EnvFilter,
 };
-use vrsc_rpc::{
-    bitcoin::Txid,
-    client::{RpcApi, SendCurrencyOutput},
-    json::{
-        vrsc::{Address, Amount},
-        TransactionVout,
-    },
-};
-use zmq::{listen_block_notifications, ZMQMessage};
 
+mod admin;
+mod admin_api;
+mod api;
+mod chain;
+mod checker;
+mod cli;
 mod config;
 mod constants;
-mod database;
 mod discord;
+mod export;
+#[cfg(test)]
+mod fixtures;
+mod health;
+mod http;
+mod ledger;
+#[cfg(test)]
+mod mock_rpc;
+mod postgres;
+mod readiness;
+mod rescan;
 mod rpc;
+mod sqlite;
+mod storage;
+mod supervisor;
+mod telemetry;
 mod zmq;
 
+/// Messages a checker may fall behind on before mempool transactions are dropped and block
+/// notifications wait.
+const ZMQ_QUEUE_CAPACITY: usize = 256;
+/// Messages waiting for Discord before new ones are dropped.
+const DISCORD_QUEUE_CAPACITY: usize = 1024;
+
 #[tokio::main]
 async fn main() -> Result<()> {
     setup_logging()?;
 
+    let cli = Cli::parse();
     let config = get_configuration()?;
-    let pg_url = &config.database.connection_string();
-    let pool = PgPool::connect_lazy(pg_url)?;
-
-    let handles = FuturesUnordered::new();
-
-    let (discord_tx, discord_rx) = mpsc::unbounded_channel::<DiscordMessage>();
-    tokio::spawn(discord::run(config.discord, discord_rx));
-
-    for pbaas_config in pbaas_chain_configs()? {
-        let (tx, rx) = mpsc::unbounded_channel::<ZMQMessage>();
-        let zmq_url = pbaas_config.zmq_block_hash_url.clone();
-
-        let cashback_checker =
-            CashbackChecker::new(pool.clone(), pbaas_config, rx, discord_tx.clone())?;
-
-        handles.push(tokio::spawn(async move {
-            if let Err(e) = cashback_checker.run(tx, zmq_url).await {
-                error!("error: {e:?}");
-            }
-        }));
+    let storage = storage::connect(&config.database).await?;
+
+    match cli.command.unwrap_or(Command::Run) {
+        Command::Run => run(config, storage).await,
+        Command::Migrate => storage.migrate().await,
+        Command::Rescan {
+            chain,
+            from,
+            to,
+            historical,
+        } => {
+            let config = pbaas_chain_config(&chain)?;
+            let report = admin::rescan(
+                storage.as_ref(),
+                config,
+                from,
+                to,
+                historical,
+                &actor(),
+                &CancellationToken::new(),
+            )
+            .await?;
+            println!(
+                "scanned {} blocks, found {} referral(s), stored {} new",
+                report.blocks, report.found, report.stored
+            );
+            Ok(())
+        }
+        Command::ListPending { chain } => cli::list_pending(storage.as_ref(), chain).await,
+        Command::Export {
+            chain,
+            accounting: false,
+            ..
+        } => cli::export(storage.as_ref(), chain).await,
+        Command::Export {
+            chain,
+            accounting: true,
+            format,
+            totals,
+            from_date,
+            to_date,
+            from_height,
+            to_height,
+        } => {
+            let range = storage::PaidRange {
+                from_date,
+                to_date,
+                from_height,
+                to_height,
+            };
+            let rows = export::rows(storage.as_ref(), chain.as_ref(), &range).await?;
+            println!("{}", export::render(format, &rows, totals)?);
+            Ok(())
+        }
+        Command::CheckConfig => cli::check_config(&config, storage.as_ref()).await,
+        Command::Pause { chain, reason } => {
+            admin::pause_payouts(storage.as_ref(), &chain, reason.as_deref(), &actor()).await
+        }
+        Command::Resume { chain } => {
+            admin::resume_payouts(storage.as_ref(), &chain, &actor()).await
+        }
+        Command::Retry { cashback } => {
+            let cashback = admin::retry_cashback(storage.as_ref(), &cashback, &actor()).await?;
+            println!(
+                "cashback {} for {}@ queued for retry",
+                cashback.id, cashback.name
+            );
+            Ok(())
+        }
+        Command::MarkPaid { cashback, txid } => {
+            let cashback = admin::mark_paid(storage.as_ref(), &cashback, &txid, &actor()).await?;
+            println!(
+                "cashback {} for {}@ marked paid",
+                cashback.id, cashback.name
+            );
+            Ok(())
+        }
+        Command::TopUp {
+            chain,
+            amount,
+            txid,
+            note,
+        } => {
+            admin::record_top_up(
+                storage.as_ref(),
+                &chain,
+                amount,
+                txid.as_ref(),
+                note.as_deref(),
+                &actor(),
+            )
+            .await?;
+            println!("top-up of {amount} sats to {chain} recorded");
+            Ok(())
+        }
+        Command::Reconcile { chain } => cli::reconcile(storage.as_ref(), &chain).await,
+        Command::Approve { cashback } => {
+            let cashback = admin::approve_cashback(storage.as_ref(), &cashback, &actor()).await?;
+            println!("cashback {} for {}@ approved", cashback.id, cashback.name);
+            Ok(())
+        }
+        Command::Skip { cashback, reason } => {
+            let cashback =
+                admin::skip_cashback(storage.as_ref(), &cashback, &reason, &actor()).await?;
+            println!("cashback {} for {}@ skipped", cashback.id, cashback.name);
+            Ok(())
+        }
     }
-
-    join_all(handles).await;
-
-    Ok(())
-}
-
-#[allow(unused)]
-#[derive(Debug)]
-pub struct CashbackChecker {
-    pool: PgPool,
-    currency_id: Address,
-    client: Client,
-    referral_id: Address,
-    explorer_url: String,
-    fee: u64,
-    referral_amount: u64,
-    rx: mpsc::UnboundedReceiver<ZMQMessage>,
-    tx: mpsc::UnboundedSender<DiscordMessage>,
 }
 
-impl CashbackChecker {
-    pub fn new(
-        pool: PgPool,
-        config: pbaas::Config,
-        rx: mpsc::UnboundedReceiver<ZMQMessage>,
-        tx: mpsc::UnboundedSender<DiscordMessage>,
-    ) -> Result<Self> {
-        let client: Client = config.clone().try_into()?;
-        let currency_id = config.currency_id.clone();
-        let referral_id = config.referral_currency_id.clone();
-        let explorer_url = config.explorer_url.clone();
-        let referral_amount = config.referral_amount;
-        let fee = config.fee;
-
-        Ok(Self {
-            pool,
-            currency_id,
-            client,
-            referral_id,
-            explorer_url,
-            fee,
-            referral_amount,
-            rx,
-            tx,
-        })
+async fn run(config: Config, storage: Arc<dyn Storage>) -> Result<()> {
+    if config.database.migrate_on_startup {
+        info!("applying database migrations");
+        storage.migrate().await?;
     }
+    storage.check_schema_version().await?;
 
-    #[instrument(level = "trace", skip(self, tx, url), fields(chain = self.currency_id.to_string()))]
-    pub async fn run(mut self, tx: mpsc::UnboundedSender<ZMQMessage>, url: String) -> Result<()> {
-        // Spawn a listener for ZMQ messages
-        tokio::spawn(async move {
-            if let Err(e) = listen_block_notifications(tx, &url).await {
-                error!("{e:?}");
-            }
-        });
-
-        // Receive messages from ZMQ
-        while let Some(message) = self.rx.recv().await {
-            match message {
-                ZMQMessage::NewBlock(block_hash) => {
-                    debug!("getting block for blockhash {}", block_hash);
-
-                    let block = self.client.client.get_block(&block_hash, 2)?;
-
-                    for tx in block.tx {
-                        for vout in tx.vout {
-                            if self.tx_has_referral(&vout).await? {
-                                // store tx in database
-                                // send message to discord
-                            }
-                        }
-                    }
-
-                    self.process_pending().await?;
-                }
-            }
-        }
-
-        Ok(())
+    let shutdown = CancellationToken::new();
+    let deadline = Duration::from_secs(config.shutdown_timeout_secs);
+    if let Err(e) = config.check_shutdown_timeout() {
+        warn!("{e}, payouts in flight may be cut off on shutdown");
     }
 
-    #[instrument(level = "trace", skip(self, vout))]
-    async fn tx_has_referral(&self, vout: &TransactionVout) -> Result<bool> {
-        if let Some(identity_reservation) = &vout.script_pubkey.identity_reservation {
-            debug!("{identity_reservation:#?}");
-            if let Some(referral) = &identity_reservation.referral {
-                let used_referral_address = Address::from_str(&referral)?;
+    let readiness = Readiness::default();
+    let state = AppState {
+        storage: storage.clone(),
+        metrics: telemetry::install()?,
+        readiness: readiness.clone(),
+        rescans: Rescans::default(),
+        shutdown: shutdown.clone(),
+    };
+    let server = http::serve(config.http, state, shutdown.clone());
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            error!("http server: {e:?}");
+        }
+    });
 
-                if self.referral_id == used_referral_address {
-                    trace!("referral used");
+    let (discord_tx, discord_rx) = mpsc::channel::<DiscordMessage>(DISCORD_QUEUE_CAPACITY);
+    let discord = tokio::spawn(discord::run(
+        config.discord,
+        storage.clone(),
+        discord_rx,
+        shutdown.clone(),
+        readiness.clone(),
+    ));
 
-                    database::store_cashback(
-                        &self.pool,
-                        &self.currency_id,
-                        &identity_reservation.nameid,
-                        &identity_reservation.name,
-                    )
-                    .await?;
+    let mut chains = Chains::new(storage.clone(), discord_tx, shutdown.clone(), readiness);
+    chains.reload(pbaas_chain_configs()?);
 
-                    self.tx
-                        .send(DiscordMessage::CashbackInitiated(
-                            self.currency_id.clone(),
-                            (
-                                identity_reservation.name.clone(),
-                                identity_reservation.nameid.clone(),
-                            ),
-                        ))
-                        .unwrap();
+    // SIGHUP reloads the pbaas configs, until the service is told to shut down.
+    let mut hangup = signal(SignalKind::hangup())?;
+    let shutdown_signal = shutdown_signal();
+    tokio::pin!(shutdown_signal);
 
-                    return Ok(true);
+    loop {
+        tokio::select! {
+            result = &mut shutdown_signal => {
+                result?;
+                break;
+            }
+            _ = hangup.recv() => {
+                info!("received SIGHUP, reloading pbaas configs");
+                match pbaas_chain_configs() {
+                    Ok(configs) => chains.reload(configs),
+                    Err(e) => error!("failed to reload pbaas configs, nothing changed: {e:?}"),
                 }
             }
         }
-
-        Ok(false)
     }
 
-    #[instrument(level = "trace", skip(self))]
-    async fn process_pending(&self) -> Result<()> {
-        let pending = database::get_pending_cashbacks(&self.pool).await?;
-        debug!("{pending:#?}");
-        let blockheight = self.client.client.get_blockchain_info()?.blocks;
+    info!("shutting down, waiting up to {deadline:?} for payouts in flight");
+    shutdown.cancel();
 
-        for cashback in pending {
-            let identity_hist = self.client.client.get_identity_history(
-                &cashback.name_id.to_string(),
-                0,
-                99999999,
-            )?;
+    let drained = tokio::time::timeout(deadline, async {
+        chains.join().await;
+        discord.await
+    })
+    .await;
 
-            if (blockheight - identity_hist.blockheight as u64) < 10 {
-                // wait 10 confirmations until payment
-                return Ok(());
-            }
-
-            let tx = self.pool.begin().await?;
-
-            let opid = self.client.client.send_currency(
-                "*",
-                vec![
-                    SendCurrencyOutput {
-                        currency: None,
-                        amount: Amount::from_sat(self.referral_amount - self.fee),
-                        address: cashback.name_id.to_string(),
-                        convertto: None,
-                        via: None,
-                    },
-                    SendCurrencyOutput {
-                        currency: None,
-                        amount: Amount::from_sat(self.fee - 20000),
-                        address: self.referral_id.to_string(),
-                        convertto: None,
-                        via: None,
-                    },
-                ],
-                None,
-                None,
-            )?;
+    match drained {
+        Ok(Ok(Ok(()))) => info!("shut down"),
+        Ok(Ok(Err(e))) => error!("discord: {e:?}"),
+        Ok(Err(e)) => error!("discord panicked: {e:?}"),
+        Err(_) => warn!("shutdown did not finish within {deadline:?}, exiting"),
+    }
 
-            if let Some(txid) = wait_for_sendcurrency_finish(&self.client.client, &opid).await? {
-                database::update_cashback(
-                    &self.pool,
-                    &cashback.currency_id,
-                    &cashback.name_id,
-                    &txid,
-                )
-                .await?;
+    Ok(())
+}
 
-                tx.commit().await?;
+/// Waits for SIGTERM or ctrl-c.
+async fn shutdown_signal() -> Result<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
 
-                self.tx
-                    .send(DiscordMessage::CashbackProcessed(
-                        self.currency_id.clone(),
-                        (cashback.name.clone(), cashback.name_id.clone()),
-                        format!("{}{}", self.explorer_url, txid),
-                    ))
-                    .unwrap();
-            }
+    tokio::select! {
+        _ = terminate.recv() => info!("received SIGTERM"),
+        result = tokio::signal::ctrl_c() => {
+            result?;
+            info!("received ctrl-c");
         }
-
-        Ok(())
     }
+
+    Ok(())
 }
 
 fn setup_logging() -> Result<()> {
@@ -271,31 +298,3 @@ fn setup_logging() -> Result<()> {
 
     Ok(())
 }
-
-async fn wait_for_sendcurrency_finish(
-    client: &vrsc_rpc::client::Client,
-    opid: &str,
-) -> Result<Option<Txid>> {
-    loop {
-        let operation_status = client.z_get_operation_status(vec![&opid])?;
-        if let Some(Some(opstatus)) = operation_status.first() {
-            debug!("op-status: {:#?}", opstatus);
-
-            if ["queued", "executing"].contains(&opstatus.status.as_ref()) {
-                tokio::time::sleep(Duration::from_millis(100)).await;
-                trace!("opid still executing");
-
-                continue;
-            }
-
-            if let Some(txid) = &opstatus.result {
-                trace!(
-                    "there was an operation_status, operation was executed with status: {}",
-                    opstatus.status
-                );
-
-                return Ok(Some(txid.txid));
-            }
-        }
-    }
-}
@@ -0,0 +1,376 @@
+//! A scripted chain behind [`ChainRpc`], for tests. The test mines the blocks, and payouts that
+//! were sent are mined in the next block unless they are dropped first.
+
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+    sync::Mutex,
+};
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use vrsc_rpc::{
+    bitcoin::{BlockHash, Txid},
+    json::vrsc::{Address, Amount},
+};
+
+use crate::chain::{
+    ChainInfo, ChainRpc, IdentityReservation, Operation, Output, Transaction, WalletSend,
+    WalletTransaction,
+};
+
+/// Network fee the wallet reports for every payout, in coins.
+pub const PAYOUT_FEE: f64 = -0.0002;
+
+/// Txids of payouts start here, so they don't collide with the ones a test makes up.
+const PAYOUT_TXIDS: u64 = 1_000_000;
+
+#[derive(Debug)]
+pub struct MockChain {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    /// Height of the first block.
+    base: u64,
+    blocks: Vec<(BlockHash, Vec<Transaction>)>,
+    /// Height each identity was registered at.
+    identities: HashMap<Address, u64>,
+    /// Decoded transactions by their raw bytes.
+    mempool: HashMap<Vec<u8>, Transaction>,
+    sends: Vec<Sent>,
+    /// Height each payout was mined at.
+    mined: HashMap<Txid, u64>,
+    /// Payouts that have been sent but are not in a block yet.
+    unconfirmed: Vec<Txid>,
+    /// Payouts the daemon forgot about, the wallet still knows them.
+    dropped: HashSet<Txid>,
+    /// Dropped payouts the wallet was told to give up on.
+    abandoned: HashSet<Txid>,
+    /// Payouts that lost to a conflicting transaction, by the height it was mined at.
+    conflicted: HashMap<Txid, u64>,
+    wallet_balance: i64,
+    /// Identities the wallet can spend for.
+    wallet_identities: HashSet<Address>,
+    /// Sends are taken by the daemon, but the call times out before the opid comes back.
+    time_out_sends: bool,
+}
+
+/// A `sendcurrency` the checker made.
+#[derive(Debug, Clone)]
+pub struct Sent {
+    pub from: String,
+    pub outputs: Vec<Output>,
+    pub txid: Txid,
+    pub time: DateTime<Utc>,
+}
+
+impl MockChain {
+    /// A chain with a single, empty block at `height`.
+    pub fn new(height: u64) -> Self {
+        Self {
+            inner: Mutex::new(Inner {
+                base: height,
+                blocks: vec![(block_hash(height), Vec::new())],
+                identities: HashMap::new(),
+                mempool: HashMap::new(),
+                sends: Vec::new(),
+                mined: HashMap::new(),
+                unconfirmed: Vec::new(),
+                dropped: HashSet::new(),
+                abandoned: HashSet::new(),
+                conflicted: HashMap::new(),
+                wallet_balance: 0,
+                wallet_identities: HashSet::new(),
+                time_out_sends: false,
+            }),
+        }
+    }
+
+    /// Mines a block with `txs` and the payouts that are waiting. Returns its height.
+    pub fn mine(&self, txs: Vec<Transaction>) -> u64 {
+        let mut inner = self.inner.lock().unwrap();
+        let height = inner.tip() + 1;
+
+        for tx in &txs {
+            for reservation in &tx.reservations {
+                inner.identities.insert(reservation.name_id.clone(), height);
+            }
+        }
+
+        for txid in std::mem::take(&mut inner.unconfirmed) {
+            inner.mined.insert(txid, height);
+        }
+
+        inner.blocks.push((block_hash(height), txs));
+
+        height
+    }
+
+    /// Puts `tx` in the mempool and returns the bytes a `rawtx` notification would carry.
+    pub fn add_to_mempool(&self, tx: Transaction) -> Vec<u8> {
+        let raw = tx.txid.to_string().into_bytes();
+        self.inner.lock().unwrap().mempool.insert(raw.clone(), tx);
+
+        raw
+    }
+
+    /// Forgets the payouts that are not in a block yet, as if they were evicted from the mempool.
+    pub fn drop_unconfirmed(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        let unconfirmed = std::mem::take(&mut inner.unconfirmed);
+        inner.dropped.extend(unconfirmed);
+    }
+
+    /// Mines a block with transactions that spend the same inputs as the payouts that are not in
+    /// a block yet, so those can never be mined. Returns its height.
+    pub fn conflict_unconfirmed(&self) -> u64 {
+        let mut inner = self.inner.lock().unwrap();
+        let height = inner.tip() + 1;
+
+        for txid in std::mem::take(&mut inner.unconfirmed) {
+            inner.conflicted.insert(txid, height);
+        }
+        inner.blocks.push((block_hash(height), Vec::new()));
+
+        height
+    }
+
+    pub fn abandoned(&self) -> HashSet<Txid> {
+        self.inner.lock().unwrap().abandoned.clone()
+    }
+
+    /// Adds a send of `amount` to `address` that was made at `time` and mined in the first
+    /// block, as one made by hand. Returns its txid.
+    pub fn send_by_hand(&self, address: &Address, amount: Amount, time: DateTime<Utc>) -> Txid {
+        let mut inner = self.inner.lock().unwrap();
+        let txid = txid(PAYOUT_TXIDS + inner.sends.len() as u64);
+
+        inner.sends.push(Sent {
+            from: "*".to_owned(),
+            outputs: vec![Output {
+                address: address.clone(),
+                amount,
+            }],
+            txid,
+            time,
+        });
+        let base = inner.base;
+        inner.mined.insert(txid, base);
+
+        txid
+    }
+
+    /// Lets the following sends time out after the daemon took them, as with a slow daemon.
+    pub fn time_out_sends(&self) {
+        self.inner.lock().unwrap().time_out_sends = true;
+    }
+
+    /// Gives the wallet the keys of `identity`.
+    pub fn add_wallet_identity(&self, identity: &Address) {
+        self.inner
+            .lock()
+            .unwrap()
+            .wallet_identities
+            .insert(identity.clone());
+    }
+
+    pub fn set_wallet_balance(&self, balance: i64) {
+        self.inner.lock().unwrap().wallet_balance = balance;
+    }
+
+    pub fn sends(&self) -> Vec<Sent> {
+        self.inner.lock().unwrap().sends.clone()
+    }
+}
+
+impl Inner {
+    fn tip(&self) -> u64 {
+        self.base + self.blocks.len() as u64 - 1
+    }
+}
+
+#[async_trait]
+impl ChainRpc for MockChain {
+    async fn blockchain_info(&self) -> Result<ChainInfo> {
+        let tip = self.inner.lock().unwrap().tip();
+
+        Ok(ChainInfo {
+            blocks: tip,
+            headers: tip,
+        })
+    }
+
+    async fn best_block_hash(&self) -> Result<BlockHash> {
+        let inner = self.inner.lock().unwrap();
+
+        Ok(block_hash(inner.tip()))
+    }
+
+    async fn block_hash(&self, height: u64) -> Result<BlockHash> {
+        let inner = self.inner.lock().unwrap();
+
+        height
+            .checked_sub(inner.base)
+            .and_then(|index| inner.blocks.get(index as usize))
+            .map(|(hash, _)| *hash)
+            .ok_or_else(|| anyhow!("no block at height {height}"))
+    }
+
+    async fn block(&self, hash: &BlockHash) -> Result<Vec<Transaction>> {
+        let inner = self.inner.lock().unwrap();
+
+        inner
+            .blocks
+            .iter()
+            .find(|(block_hash, _)| block_hash == hash)
+            .map(|(_, txs)| txs.clone())
+            .ok_or_else(|| anyhow!("no block {hash}"))
+    }
+
+    async fn decode_raw_transaction(&self, raw_tx: &[u8]) -> Result<Transaction> {
+        let inner = self.inner.lock().unwrap();
+
+        inner
+            .mempool
+            .get(raw_tx)
+            .cloned()
+            .ok_or_else(|| anyhow!("not a transaction in the mempool"))
+    }
+
+    async fn identity_height(&self, name_id: &Address) -> Result<u64> {
+        let inner = self.inner.lock().unwrap();
+
+        inner
+            .identities
+            .get(name_id)
+            .copied()
+            .ok_or_else(|| anyhow!("identity {name_id} not found"))
+    }
+
+    async fn send_currency(&self, from: &str, outputs: &[Output]) -> Result<String> {
+        let mut inner = self.inner.lock().unwrap();
+        let n = inner.sends.len() as u64;
+        let txid = txid(PAYOUT_TXIDS + n);
+
+        inner.sends.push(Sent {
+            from: from.to_owned(),
+            outputs: outputs.to_vec(),
+            txid,
+            time: Utc::now(),
+        });
+        inner.unconfirmed.push(txid);
+
+        if inner.time_out_sends {
+            return Err(anyhow!("sendcurrency timed out"));
+        }
+
+        Ok(format!("opid-{n}"))
+    }
+
+    async fn operation_status(&self, opid: &str) -> Result<Option<Operation>> {
+        let inner = self.inner.lock().unwrap();
+
+        Ok(opid
+            .strip_prefix("opid-")
+            .and_then(|n| n.parse::<usize>().ok())
+            .and_then(|n| inner.sends.get(n))
+            .map(|send| Operation::Success(send.txid)))
+    }
+
+    async fn wallet_transaction(&self, txid: &Txid) -> Result<WalletTransaction> {
+        let inner = self.inner.lock().unwrap();
+
+        let confirmations = match (inner.mined.get(txid), inner.conflicted.get(txid)) {
+            (Some(height), _) => (inner.tip() + 1 - height) as i64,
+            (None, Some(height)) => -((inner.tip() + 1 - height) as i64),
+            (None, None) if inner.unconfirmed.contains(txid) || inner.dropped.contains(txid) => 0,
+            (None, None) => return Err(anyhow!("{txid} is not a wallet transaction")),
+        };
+
+        Ok(WalletTransaction {
+            txid: *txid,
+            confirmations,
+            fee: Some(PAYOUT_FEE),
+        })
+    }
+
+    async fn wallet_sends(&self, address: &Address) -> Result<Vec<WalletSend>> {
+        let inner = self.inner.lock().unwrap();
+
+        Ok(inner
+            .sends
+            .iter()
+            .filter(|send| {
+                !inner.abandoned.contains(&send.txid) && !inner.conflicted.contains_key(&send.txid)
+            })
+            .flat_map(|send| {
+                send.outputs
+                    .iter()
+                    .filter(|output| output.address == *address)
+                    .map(|output| WalletSend {
+                        txid: send.txid,
+                        amount: output.amount,
+                        time: send.time,
+                    })
+            })
+            .collect())
+    }
+
+    async fn can_spend_for(&self, identity: &Address) -> Result<bool> {
+        Ok(self
+            .inner
+            .lock()
+            .unwrap()
+            .wallet_identities
+            .contains(identity))
+    }
+
+    async fn abandon_transaction(&self, txid: &Txid) -> Result<()> {
+        let mut inner = self.inner.lock().unwrap();
+
+        if !inner.dropped.contains(txid) {
+            return Err(anyhow!("{txid} is not eligible for abandonment"));
+        }
+        inner.abandoned.insert(*txid);
+
+        Ok(())
+    }
+
+    async fn has_transaction(&self, txid: &Txid) -> Result<bool> {
+        let inner = self.inner.lock().unwrap();
+
+        Ok(inner.mined.contains_key(txid) || inner.unconfirmed.contains(txid))
+    }
+
+    async fn wallet_balance(&self, _address: Option<&str>) -> Result<i64> {
+        Ok(self.inner.lock().unwrap().wallet_balance)
+    }
+}
+
+pub fn txid(n: u64) -> Txid {
+    Txid::from_str(&format!("{n:064x}")).unwrap()
+}
+
+fn block_hash(height: u64) -> BlockHash {
+    BlockHash::from_str(&format!("{height:064x}")).unwrap()
+}
+
+/// A transaction that registers `name` with `referral`.
+pub fn registration(
+    n: u64,
+    name: &str,
+    name_id: &Address,
+    referral: Option<&Address>,
+) -> Transaction {
+    Transaction {
+        txid: txid(n),
+        reservations: vec![IdentityReservation {
+            name: name.to_owned(),
+            name_id: name_id.clone(),
+            referral: referral.cloned(),
+        }],
+    }
+}
@@ -0,0 +1,894 @@
+//! The Postgres backend, which needs the `pg_uuidv7` extension.
+
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use sqlx::{migrate::Migrator, PgConnection, PgPool};
+use uuid::Uuid;
+use vrsc_rpc::{
+    bitcoin::{BlockHash, Txid},
+    json::vrsc::Address,
+};
+
+use crate::{
+    config::DbConfig,
+    constants::{AuditAction, AuditEntry, Cashback, CashbackStatus},
+    ledger::{self, Account},
+    storage::{self, CashbackFilter, PaidRange, Payout, Registration, Storage},
+};
+
+pub static MIGRATOR: Migrator = sqlx::migrate!();
+
+#[derive(Debug, Clone)]
+pub struct Postgres {
+    pool: PgPool,
+}
+
+impl Postgres {
+    pub fn connect(config: &DbConfig) -> Result<Self> {
+        let pool = PgPool::connect_lazy(&config.connection_string())?;
+
+        Ok(Self { pool })
+    }
+}
+
+/// A row of `cashbacks`, shared with the sqlite backend.
+#[derive(Debug, sqlx::FromRow)]
+pub struct DbCashback {
+    pub id: Uuid,
+    pub currency_id: String,
+    pub name_id: String,
+    pub name_str: String,
+    pub referral_id: Option<String>,
+    pub txid: Option<String>,
+    pub opid: Option<String>,
+    pub sending_at: Option<DateTime<Utc>>,
+    pub status: String,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    pub skip_reason: Option<String>,
+    pub amount: Option<i64>,
+    pub fee: Option<i64>,
+    pub payout_currency: Option<String>,
+    pub registration_txid: Option<String>,
+    pub detected_block_height: Option<i64>,
+    pub detected_block_hash: Option<String>,
+    pub paid_at: Option<DateTime<Utc>>,
+    pub paid_manually: bool,
+    pub payout_block_height: Option<i64>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl TryFrom<DbCashback> for Cashback {
+    type Error = sqlx::Error;
+
+    fn try_from(value: DbCashback) -> Result<Self, Self::Error> {
+        Ok(Self {
+            id: value.id,
+            currency_id: Address::from_str(&value.currency_id)
+                .map_err(|e| sqlx::Error::Decode(e.into()))?,
+            name_id: Address::from_str(&value.name_id)
+                .map_err(|e| sqlx::Error::Decode(e.into()))?,
+            name: value.name_str,
+            referral_id: value
+                .referral_id
+                .map(|referral_id| Address::from_str(&referral_id))
+                .transpose()
+                .map_err(|e| sqlx::Error::Decode(e.into()))?,
+            txid: value
+                .txid
+                .map(|txid_str| Txid::from_str(&txid_str))
+                .transpose()
+                .map_err(|e| sqlx::Error::Decode(e.into()))?,
+            opid: value.opid,
+            sending_at: value.sending_at,
+            status: CashbackStatus::try_from(value.status)
+                .map_err(|e| sqlx::Error::Decode(e.into()))?,
+            attempts: value.attempts,
+            last_error: value.last_error,
+            skip_reason: value.skip_reason,
+            amount: value.amount.map(|amount| amount as u64),
+            fee: value.fee.map(|fee| fee as u64),
+            payout_currency: value
+                .payout_currency
+                .map(|currency| Address::from_str(&currency))
+                .transpose()
+                .map_err(|e| sqlx::Error::Decode(e.into()))?,
+            registration_txid: value
+                .registration_txid
+                .map(|txid_str| Txid::from_str(&txid_str))
+                .transpose()
+                .map_err(|e| sqlx::Error::Decode(e.into()))?,
+            detected_block_height: value.detected_block_height.map(|height| height as u64),
+            detected_block_hash: value
+                .detected_block_hash
+                .map(|hash| BlockHash::from_str(&hash))
+                .transpose()
+                .map_err(|e| sqlx::Error::Decode(e.into()))?,
+            paid_at: value.paid_at,
+            paid_manually: value.paid_manually,
+            payout_block_height: value.payout_block_height.map(|height| height as u64),
+            created_at: value.created_at,
+            updated_at: value.updated_at,
+        })
+    }
+}
+
+#[async_trait]
+impl Storage for Postgres {
+    async fn migrate(&self) -> Result<()> {
+        MIGRATOR.run(&self.pool).await?;
+
+        Ok(())
+    }
+
+    async fn check_schema_version(&self) -> Result<()> {
+        let expected = MIGRATOR.iter().map(|migration| migration.version).max();
+        let applied =
+            sqlx::query_scalar!("SELECT MAX(version) FROM _sqlx_migrations WHERE success")
+                .fetch_one(&self.pool)
+                .await
+                .context("failed to read the schema version, has the database been migrated?")?;
+
+        if applied != expected {
+            bail!(
+                "database schema is at version {:?}, this binary expects {:?}",
+                applied,
+                expected
+            );
+        }
+
+        Ok(())
+    }
+
+    async fn store_cashback(
+        &self,
+        currency_id: &Address,
+        name_id: &Address,
+        name: &str,
+        status: CashbackStatus,
+        registration: Registration<'_>,
+        entries: &[ledger::Entry],
+    ) -> Result<bool> {
+        let mut tx = self.pool.begin().await?;
+
+        let id = sqlx::query_scalar!(
+            "INSERT INTO cashbacks (currency_id, name_id, name_str, status, referral_id,
+                registration_txid, detected_block_height, detected_block_hash)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            ON CONFLICT (currency_id, name_id) DO UPDATE
+            SET status = EXCLUDED.status,
+                referral_id = EXCLUDED.referral_id,
+                registration_txid = EXCLUDED.registration_txid,
+                detected_block_height = EXCLUDED.detected_block_height,
+                detected_block_hash = EXCLUDED.detected_block_hash
+            WHERE cashbacks.status = 'mempool' AND EXCLUDED.status <> 'mempool'
+            RETURNING id",
+            currency_id.to_string(),
+            name_id.to_string(),
+            name,
+            status.as_str(),
+            registration.referral_id.to_string(),
+            registration.txid.to_string(),
+            registration.block.map(|(height, _)| height as i64),
+            registration.block.map(|(_, hash)| hash.to_string())
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        let Some(id) = id else {
+            return Ok(false);
+        };
+
+        if !entries.is_empty() {
+            insert_ledger_entries(
+                &mut tx,
+                currency_id,
+                entries,
+                Some(&id),
+                Some(registration.txid),
+                None,
+            )
+            .await?;
+        }
+        tx.commit().await?;
+
+        Ok(true)
+    }
+
+    async fn delete_stale_mempool_cashbacks(
+        &self,
+        currency_id: &Address,
+        seen_before: DateTime<Utc>,
+    ) -> Result<u64> {
+        let result = sqlx::query!(
+            "DELETE FROM cashbacks
+            WHERE currency_id = $1 AND status = 'mempool' AND created_at < $2",
+            currency_id.to_string(),
+            seen_before
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    async fn start_payout(&self, id: &Uuid, payout: Payout<'_>) -> Result<bool> {
+        let result = sqlx::query!(
+            "UPDATE cashbacks
+            SET status = 'sending', txid = NULL, opid = NULL, last_error = NULL, amount = $2,
+                fee = $3, payout_currency = $4, paid_at = NULL, payout_block_height = NULL,
+                sending_at = now()
+            WHERE id = $1 AND status = 'pending'",
+            id,
+            payout.amount as i64,
+            payout.fee as i64,
+            payout.currency.to_string()
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn set_payout_opid(&self, id: &Uuid, opid: &str) -> Result<()> {
+        sqlx::query!(
+            "UPDATE cashbacks
+            SET opid = $2
+            WHERE id = $1 AND status = 'sending'",
+            id,
+            opid
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn update_cashback(&self, id: &Uuid, txid: &Txid) -> Result<bool> {
+        let result = sqlx::query!(
+            "UPDATE cashbacks
+            SET txid = $2, status = 'broadcast', last_error = NULL
+            WHERE id = $1 AND status = 'sending'",
+            id,
+            txid.to_string()
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn record_payout_error(&self, id: &Uuid, error: &str) -> Result<()> {
+        sqlx::query!(
+            "UPDATE cashbacks
+            SET last_error = $2
+            WHERE id = $1 AND status = 'sending'",
+            id,
+            error
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn get_sending_payouts(&self, currency_id: &Address) -> Result<Vec<Cashback>> {
+        let rows = sqlx::query_as!(
+            DbCashback,
+            "SELECT id, currency_id, name_id, name_str, referral_id, txid, opid, sending_at, status, attempts, last_error, skip_reason,
+                amount, fee, payout_currency, registration_txid, detected_block_height,
+                detected_block_hash, paid_at, paid_manually, payout_block_height, created_at, updated_at
+            FROM cashbacks
+            WHERE currency_id = $1 AND status = 'sending'",
+            currency_id.to_string()
+        )
+        .try_map(Cashback::try_from)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows)
+    }
+
+    async fn get_broadcast_payouts(&self, currency_id: &Address) -> Result<Vec<Cashback>> {
+        let rows = sqlx::query_as!(
+            DbCashback,
+            "SELECT id, currency_id, name_id, name_str, referral_id, txid, opid, sending_at, status, attempts, last_error, skip_reason,
+                amount, fee, payout_currency, registration_txid, detected_block_height,
+                detected_block_hash, paid_at, paid_manually, payout_block_height, created_at, updated_at
+            FROM cashbacks
+            WHERE currency_id = $1 AND status = 'broadcast'",
+            currency_id.to_string()
+        )
+        .try_map(Cashback::try_from)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows)
+    }
+
+    async fn confirm_payout(
+        &self,
+        cashback: &Cashback,
+        height: u64,
+        entries: &[ledger::Entry],
+    ) -> Result<bool> {
+        let mut tx = self.pool.begin().await?;
+
+        let result = sqlx::query!(
+            "UPDATE cashbacks
+            SET status = 'paid', paid_at = now(), payout_block_height = $2
+            WHERE id = $1 AND status = 'broadcast'",
+            cashback.id,
+            height as i64
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Ok(false);
+        }
+
+        insert_ledger_entries(
+            &mut tx,
+            &cashback.currency_id,
+            entries,
+            Some(&cashback.id),
+            cashback.txid.as_ref(),
+            None,
+        )
+        .await?;
+        tx.commit().await?;
+
+        Ok(true)
+    }
+
+    async fn get_pending_cashbacks(&self, currency_id: &Address) -> Result<Vec<Cashback>> {
+        let rows = sqlx::query_as!(
+            DbCashback,
+            "SELECT id, currency_id, name_id, name_str, referral_id, txid, opid, sending_at, status, attempts, last_error, skip_reason,
+                amount, fee, payout_currency, registration_txid, detected_block_height,
+                detected_block_hash, paid_at, paid_manually, payout_block_height, created_at, updated_at
+            FROM cashbacks
+            WHERE currency_id = $1 AND status = 'pending'",
+            currency_id.to_string()
+        )
+        .try_map(Cashback::try_from)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows)
+    }
+
+    async fn get_cashback(&self, id: &Uuid) -> Result<Option<Cashback>> {
+        let row = sqlx::query_as!(
+            DbCashback,
+            "SELECT id, currency_id, name_id, name_str, referral_id, txid, opid, sending_at, status, attempts, last_error, skip_reason,
+                amount, fee, payout_currency, registration_txid, detected_block_height,
+                detected_block_hash, paid_at, paid_manually, payout_block_height, created_at, updated_at
+            FROM cashbacks
+            WHERE id = $1",
+            id
+        )
+        .try_map(Cashback::try_from)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row)
+    }
+
+    async fn get_cashbacks_by_name_id(&self, name_id: &Address) -> Result<Vec<Cashback>> {
+        let rows = sqlx::query_as!(
+            DbCashback,
+            "SELECT id, currency_id, name_id, name_str, referral_id, txid, opid, sending_at, status, attempts, last_error, skip_reason,
+                amount, fee, payout_currency, registration_txid, detected_block_height,
+                detected_block_hash, paid_at, paid_manually, payout_block_height, created_at, updated_at
+            FROM cashbacks
+            WHERE name_id = $1
+            ORDER BY created_at, id",
+            name_id.to_string()
+        )
+        .try_map(Cashback::try_from)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows)
+    }
+
+    async fn mark_cashback_failed(&self, id: &Uuid, error: &str) -> Result<()> {
+        sqlx::query!(
+            "UPDATE cashbacks
+            SET status = 'failed', attempts = attempts + 1, last_error = $2
+            WHERE id = $1 AND status = 'sending'",
+            id,
+            error
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn mark_payout_dropped(&self, id: &Uuid, error: &str) -> Result<bool> {
+        let result = sqlx::query!(
+            "UPDATE cashbacks
+            SET status = 'failed', attempts = attempts + 1, last_error = $2
+            WHERE id = $1 AND status = 'broadcast'",
+            id,
+            error
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn retry_cashback(&self, id: &Uuid) -> Result<bool> {
+        let result = sqlx::query!(
+            "UPDATE cashbacks
+            SET status = 'pending', last_error = NULL, skip_reason = NULL
+            WHERE id = $1 AND status IN ('failed', 'skipped', 'ineligible_historical')",
+            id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn approve_cashback(&self, id: &Uuid) -> Result<bool> {
+        let result = sqlx::query!(
+            "UPDATE cashbacks
+            SET status = 'pending'
+            WHERE id = $1 AND status = 'ineligible_historical'",
+            id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn mark_cashback_paid(&self, id: &Uuid, txid: &Txid) -> Result<bool> {
+        let result = sqlx::query!(
+            "UPDATE cashbacks
+            SET status = 'paid', txid = $2, last_error = NULL, amount = NULL, fee = NULL,
+                payout_currency = NULL, paid_at = now(), paid_manually = TRUE,
+                payout_block_height = NULL
+            WHERE id = $1 AND status NOT IN ('paid', 'sending', 'broadcast')",
+            id,
+            txid.to_string()
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn skip_cashback(&self, id: &Uuid, reason: &str) -> Result<bool> {
+        let result = sqlx::query!(
+            "UPDATE cashbacks
+            SET status = 'skipped', skip_reason = $2
+            WHERE id = $1 AND status NOT IN ('paid', 'sending', 'broadcast')",
+            id,
+            reason
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn insert_audit(
+        &self,
+        actor: &str,
+        action: AuditAction,
+        currency_id: Option<&Address>,
+        cashback_id: Option<&Uuid>,
+        detail: Option<&str>,
+    ) -> Result<()> {
+        sqlx::query!(
+            "INSERT INTO audit_log (actor, action, currency_id, cashback_id, detail)
+                VALUES ($1, $2, $3, $4, $5)",
+            actor,
+            action.as_str(),
+            currency_id.map(|id| id.to_string()),
+            cashback_id.copied(),
+            detail
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn set_payouts_paused(
+        &self,
+        currency_id: &Address,
+        paused: bool,
+        reason: Option<&str>,
+    ) -> Result<()> {
+        sqlx::query!(
+            "INSERT INTO chain_state (currency_id, payouts_paused, paused_reason)
+                VALUES ($1, $2, $3)
+            ON CONFLICT (currency_id) DO UPDATE
+            SET payouts_paused = EXCLUDED.payouts_paused, paused_reason = EXCLUDED.paused_reason",
+            currency_id.to_string(),
+            paused,
+            reason
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn payouts_paused(&self, currency_id: &Address) -> Result<bool> {
+        let paused = sqlx::query_scalar!(
+            "SELECT payouts_paused
+            FROM chain_state
+            WHERE currency_id = $1",
+            currency_id.to_string()
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(paused.unwrap_or(false))
+    }
+
+    async fn get_cashbacks(&self, currency_id: Option<&Address>) -> Result<Vec<Cashback>> {
+        let rows = sqlx::query_as!(
+            DbCashback,
+            "SELECT id, currency_id, name_id, name_str, referral_id, txid, opid, sending_at, status, attempts, last_error, skip_reason,
+                amount, fee, payout_currency, registration_txid, detected_block_height,
+                detected_block_hash, paid_at, paid_manually, payout_block_height, created_at, updated_at
+            FROM cashbacks
+            WHERE $1::text IS NULL OR currency_id = $1
+            ORDER BY created_at",
+            currency_id.map(|id| id.to_string())
+        )
+        .try_map(Cashback::try_from)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows)
+    }
+
+    async fn get_cashback_by_name(
+        &self,
+        currency_id: &Address,
+        name: &str,
+    ) -> Result<Option<Cashback>> {
+        let row = sqlx::query_as!(
+            DbCashback,
+            "SELECT id, currency_id, name_id, name_str, referral_id, txid, opid, sending_at, status, attempts, last_error, skip_reason,
+                amount, fee, payout_currency, registration_txid, detected_block_height,
+                detected_block_hash, paid_at, paid_manually, payout_block_height, created_at, updated_at
+            FROM cashbacks
+            WHERE currency_id = $1 AND LOWER(name_str) = LOWER($2)",
+            currency_id.to_string(),
+            name
+        )
+        .try_map(Cashback::try_from)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row)
+    }
+
+    async fn get_chain_cashback_by_name_id(
+        &self,
+        currency_id: &Address,
+        name_id: &Address,
+    ) -> Result<Option<Cashback>> {
+        let row = sqlx::query_as!(
+            DbCashback,
+            "SELECT id, currency_id, name_id, name_str, referral_id, txid, opid, sending_at, status, attempts, last_error, skip_reason,
+                amount, fee, payout_currency, registration_txid, detected_block_height,
+                detected_block_hash, paid_at, paid_manually, payout_block_height, created_at, updated_at
+            FROM cashbacks
+            WHERE currency_id = $1 AND name_id = $2",
+            currency_id.to_string(),
+            name_id.to_string()
+        )
+        .try_map(Cashback::try_from)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row)
+    }
+
+    async fn get_paid_cashbacks(
+        &self,
+        currency_id: Option<&Address>,
+        range: &PaidRange,
+    ) -> Result<Vec<Cashback>> {
+        let rows = sqlx::query_as!(
+            DbCashback,
+            "SELECT id, currency_id, name_id, name_str, referral_id, txid, opid, sending_at, status, attempts, last_error, skip_reason,
+                amount, fee, payout_currency, registration_txid, detected_block_height,
+                detected_block_hash, paid_at, paid_manually, payout_block_height, created_at, updated_at
+            FROM cashbacks
+            WHERE status = 'paid'
+                AND ($1::text IS NULL OR currency_id = $1)
+                AND ($2::timestamptz IS NULL OR paid_at >= $2)
+                AND ($3::timestamptz IS NULL OR paid_at < $3)
+                AND ($4::bigint IS NULL OR payout_block_height >= $4)
+                AND ($5::bigint IS NULL OR payout_block_height < $5)
+            ORDER BY created_at",
+            currency_id.map(|id| id.to_string()),
+            range.from_date,
+            range.to_date,
+            range.from_height.map(|height| height as i64),
+            range.to_height.map(|height| height as i64)
+        )
+        .try_map(Cashback::try_from)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows)
+    }
+
+    async fn list_cashbacks(
+        &self,
+        currency_id: &Address,
+        filter: &CashbackFilter,
+        limit: i64,
+        offset: i64,
+    ) -> Result<(Vec<Cashback>, i64)> {
+        let status = filter.status.map(|status| status.as_str());
+
+        let rows = sqlx::query_as!(
+            DbCashback,
+            "SELECT id, currency_id, name_id, name_str, referral_id, txid, opid, sending_at, status, attempts, last_error, skip_reason,
+                amount, fee, payout_currency, registration_txid, detected_block_height,
+                detected_block_hash, paid_at, paid_manually, payout_block_height, created_at, updated_at
+            FROM cashbacks
+            WHERE currency_id = $1
+                AND ($2::text IS NULL OR status = $2)
+                AND ($3::timestamptz IS NULL OR created_at >= $3)
+                AND ($4::timestamptz IS NULL OR created_at < $4)
+            ORDER BY created_at DESC, id DESC
+            LIMIT $5 OFFSET $6",
+            currency_id.to_string(),
+            status,
+            filter.from,
+            filter.to,
+            limit,
+            offset
+        )
+        .try_map(Cashback::try_from)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let total = sqlx::query_scalar!(
+            r#"SELECT COUNT(*) AS "count!"
+            FROM cashbacks
+            WHERE currency_id = $1
+                AND ($2::text IS NULL OR status = $2)
+                AND ($3::timestamptz IS NULL OR created_at >= $3)
+                AND ($4::timestamptz IS NULL OR created_at < $4)"#,
+            currency_id.to_string(),
+            status,
+            filter.from,
+            filter.to
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok((rows, total))
+    }
+
+    async fn count_cashbacks_by_status(&self) -> Result<Vec<(Address, CashbackStatus, i64)>> {
+        let rows = sqlx::query!(
+            r#"SELECT currency_id, status, COUNT(*) AS "count!"
+            FROM cashbacks
+            GROUP BY currency_id, status
+            ORDER BY currency_id, status"#
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.into_iter()
+            .map(|row| {
+                Ok((
+                    Address::from_str(&row.currency_id)?,
+                    CashbackStatus::try_from(row.status)?,
+                    row.count,
+                ))
+            })
+            .collect()
+    }
+
+    async fn list_audit_log(
+        &self,
+        currency_id: Option<&Address>,
+        limit: i64,
+        offset: i64,
+    ) -> Result<(Vec<AuditEntry>, i64)> {
+        let currency_id = currency_id.map(|id| id.to_string());
+
+        let rows = sqlx::query!(
+            "SELECT id, actor, action, currency_id, cashback_id, detail, created_at
+            FROM audit_log
+            WHERE $1::text IS NULL OR currency_id = $1
+            ORDER BY created_at DESC, id DESC
+            LIMIT $2 OFFSET $3",
+            currency_id,
+            limit,
+            offset
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let entries = rows
+            .into_iter()
+            .map(|row| {
+                Ok(AuditEntry {
+                    id: row.id,
+                    actor: row.actor,
+                    action: row.action,
+                    currency_id: row
+                        .currency_id
+                        .map(|currency_id| Address::from_str(&currency_id))
+                        .transpose()?,
+                    cashback_id: row.cashback_id,
+                    detail: row.detail,
+                    created_at: row.created_at,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let total = sqlx::query_scalar!(
+            r#"SELECT COUNT(*) AS "count!"
+            FROM audit_log
+            WHERE $1::text IS NULL OR currency_id = $1"#,
+            currency_id
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok((entries, total))
+    }
+
+    async fn ping(&self) -> Result<()> {
+        sqlx::query!("SELECT 1 AS ping")
+            .fetch_one(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn get_scan_cursor(&self, currency_id: &Address) -> Result<Option<u64>> {
+        let height = sqlx::query_scalar!(
+            "SELECT last_scanned_height
+            FROM chain_state
+            WHERE currency_id = $1",
+            currency_id.to_string()
+        )
+        .fetch_optional(&self.pool)
+        .await?
+        .flatten();
+
+        Ok(height.map(|height| height as u64))
+    }
+
+    async fn set_scan_cursor(&self, currency_id: &Address, height: u64) -> Result<()> {
+        sqlx::query!(
+            "INSERT INTO chain_state (currency_id, last_scanned_height)
+                VALUES ($1, $2)
+            ON CONFLICT (currency_id) DO UPDATE
+            SET last_scanned_height = EXCLUDED.last_scanned_height",
+            currency_id.to_string(),
+            height as i64
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn post_ledger_transfer(
+        &self,
+        currency_id: &Address,
+        entries: &[ledger::Entry],
+        cashback_id: Option<&Uuid>,
+        txid: Option<&Txid>,
+        note: Option<&str>,
+    ) -> Result<Uuid> {
+        let mut conn = self.pool.acquire().await?;
+
+        insert_ledger_entries(&mut conn, currency_id, entries, cashback_id, txid, note).await
+    }
+
+    async fn ledger_balance(&self, currency_id: &Address, account: Account) -> Result<i64> {
+        let balance = sqlx::query_scalar!(
+            r#"SELECT COALESCE(SUM(amount), 0)::bigint AS "balance!"
+            FROM ledger_entries
+            WHERE currency_id = $1 AND account = $2"#,
+            currency_id.to_string(),
+            account.as_str()
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(balance)
+    }
+
+    async fn last_reconciliation_difference(&self, currency_id: &Address) -> Result<Option<i64>> {
+        let difference = sqlx::query_scalar!(
+            "SELECT difference
+            FROM reconciliations
+            WHERE currency_id = $1
+            ORDER BY created_at DESC, id DESC
+            LIMIT 1",
+            currency_id.to_string()
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(difference)
+    }
+
+    async fn insert_reconciliation(
+        &self,
+        currency_id: &Address,
+        ledger_balance: i64,
+        wallet_balance: i64,
+    ) -> Result<()> {
+        sqlx::query!(
+            "INSERT INTO reconciliations (currency_id, ledger_balance, wallet_balance, difference)
+                VALUES ($1, $2, $3, $3 - $2)",
+            currency_id.to_string(),
+            ledger_balance,
+            wallet_balance
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+}
+
+async fn insert_ledger_entries(
+    conn: &mut PgConnection,
+    currency_id: &Address,
+    entries: &[ledger::Entry],
+    cashback_id: Option<&Uuid>,
+    txid: Option<&Txid>,
+    note: Option<&str>,
+) -> Result<Uuid> {
+    storage::check_balanced(currency_id, entries)?;
+
+    let transfer_id = sqlx::query_scalar!(r#"SELECT uuid_generate_v7() AS "id!""#)
+        .fetch_one(&mut *conn)
+        .await?;
+
+    sqlx::query!(
+        "INSERT INTO ledger_entries
+            (currency_id, transfer_id, kind, account, amount, cashback_id, txid, note)
+        SELECT $1, $2, entry.kind, entry.account, entry.amount, $6, $7, $8
+        FROM UNNEST($3::text[], $4::text[], $5::bigint[]) AS entry(kind, account, amount)",
+        currency_id.to_string(),
+        transfer_id,
+        &entries
+            .iter()
+            .map(|entry| entry.kind.as_str().to_owned())
+            .collect::<Vec<_>>(),
+        &entries
+            .iter()
+            .map(|entry| entry.account.as_str().to_owned())
+            .collect::<Vec<_>>(),
+        &entries.iter().map(|entry| entry.amount).collect::<Vec<_>>(),
+        cashback_id,
+        txid.map(|txid| txid.to_string()),
+        note
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(transfer_id)
+}
@@ -0,0 +1,205 @@
+//! What `/readyz` reports: whether the database, Discord and, per chain, the daemon and ZMQ can
+//! be relied on. The checkers and Discord keep it up to date, the database is asked on every
+//! request.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use poise::serenity_prelude::{ConnectionStage, ShardManager};
+use serde::Serialize;
+use vrsc_rpc::json::vrsc::Address;
+
+use crate::{health::DaemonStatus, storage::Storage};
+
+const DATABASE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Default)]
+pub struct Readiness {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Default)]
+struct Inner {
+    chains: HashMap<Address, ChainState>,
+    shard_manager: Option<Arc<ShardManager>>,
+}
+
+#[derive(Debug, Clone)]
+struct ChainState {
+    daemon: DaemonStatus,
+    backing_off: bool,
+    /// How long ZMQ may be quiet, `None` for chains that poll for blocks.
+    zmq_stale_after: Option<Duration>,
+    /// The last ZMQ message, or when the chain was registered if none arrived yet.
+    last_zmq_message: Instant,
+}
+
+impl Readiness {
+    pub fn register(&self, currency_id: Address, zmq_stale_after: Option<Duration>) {
+        self.inner.lock().unwrap().chains.insert(
+            currency_id,
+            ChainState {
+                daemon: DaemonStatus::Unreachable,
+                backing_off: false,
+                zmq_stale_after,
+                last_zmq_message: Instant::now(),
+            },
+        );
+    }
+
+    pub fn unregister(&self, currency_id: &Address) {
+        self.inner.lock().unwrap().chains.remove(currency_id);
+    }
+
+    /// Records the last known state of the daemon of a chain, and whether calls to it are held
+    /// back because they kept failing.
+    pub fn set_daemon(&self, currency_id: &Address, daemon: DaemonStatus, backing_off: bool) {
+        if let Some(chain) = self.inner.lock().unwrap().chains.get_mut(currency_id) {
+            chain.daemon = daemon;
+            chain.backing_off = backing_off;
+        }
+    }
+
+    pub fn zmq_message(&self, currency_id: &Address) {
+        if let Some(chain) = self.inner.lock().unwrap().chains.get_mut(currency_id) {
+            chain.last_zmq_message = Instant::now();
+        }
+    }
+
+    pub fn set_shard_manager(&self, shard_manager: Arc<ShardManager>) {
+        self.inner.lock().unwrap().shard_manager = Some(shard_manager);
+    }
+
+    pub async fn report(&self, storage: &dyn Storage) -> Report {
+        let (chains, shard_manager) = {
+            let inner = self.inner.lock().unwrap();
+            (inner.chains.clone(), inner.shard_manager.clone())
+        };
+
+        let database = match tokio::time::timeout(DATABASE_TIMEOUT, storage.ping()).await {
+            Ok(Ok(())) => Component::ok(),
+            Ok(Err(e)) => Component::degraded(format!("{e:#}")),
+            Err(_) => Component::degraded(format!("no answer within {DATABASE_TIMEOUT:?}")),
+        };
+
+        let discord = match shard_manager {
+            Some(shard_manager) => {
+                let runners = shard_manager.runners.lock().await;
+                let disconnected = runners
+                    .iter()
+                    .filter(|(_, runner)| runner.stage != ConnectionStage::Connected)
+                    .map(|(id, runner)| format!("shard {id} is {:?}", runner.stage))
+                    .collect::<Vec<_>>();
+
+                if runners.is_empty() {
+                    Component::degraded("no shards are running".to_owned())
+                } else if disconnected.is_empty() {
+                    Component::ok()
+                } else {
+                    Component::degraded(disconnected.join(", "))
+                }
+            }
+            None => Component::degraded("not started".to_owned()),
+        };
+
+        let mut chain_reports = BTreeMap::new();
+        for (currency_id, chain) in chains {
+            let payouts = match storage.payouts_paused(&currency_id).await {
+                Ok(true) => Payouts::Paused,
+                Ok(false) => Payouts::Active,
+                Err(_) => Payouts::Unknown,
+            };
+
+            let zmq = chain.zmq_stale_after.map(|stale_after| {
+                let age = chain.last_zmq_message.elapsed();
+
+                Zmq {
+                    ok: age < stale_after,
+                    last_message_age_secs: age.as_secs(),
+                }
+            });
+
+            let ok = matches!(chain.daemon, DaemonStatus::Synced { .. })
+                && !chain.backing_off
+                && zmq.as_ref().map_or(true, |zmq| zmq.ok);
+
+            chain_reports.insert(
+                currency_id.to_string(),
+                ChainReport {
+                    ok,
+                    rpc: chain.daemon,
+                    rpc_backing_off: chain.backing_off,
+                    zmq,
+                    payouts,
+                },
+            );
+        }
+
+        Report {
+            ready: database.ok && discord.ok && chain_reports.values().all(|chain| chain.ok),
+            database,
+            discord,
+            chains: chain_reports,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub ready: bool,
+    database: Component,
+    discord: Component,
+    chains: BTreeMap<String, ChainReport>,
+}
+
+#[derive(Debug, Serialize)]
+struct Component {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl Component {
+    fn ok() -> Self {
+        Self {
+            ok: true,
+            error: None,
+        }
+    }
+
+    fn degraded(error: String) -> Self {
+        Self {
+            ok: false,
+            error: Some(error),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ChainReport {
+    ok: bool,
+    rpc: DaemonStatus,
+    rpc_backing_off: bool,
+    /// Left out for chains that poll for blocks.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    zmq: Option<Zmq>,
+    /// Paused payouts are an admin decision, they don't make the chain degraded.
+    payouts: Payouts,
+}
+
+#[derive(Debug, Serialize)]
+struct Zmq {
+    ok: bool,
+    last_message_age_secs: u64,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Payouts {
+    Active,
+    Paused,
+    Unknown,
+}
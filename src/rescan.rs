@@ -0,0 +1,120 @@
+//! Walks a range of blocks on a chain and stores the referrals that were used in it, for blocks
+//! that were mined while the service was not running.
+
+use anyhow::{bail, ensure, Result};
+use tokio_util::sync::CancellationToken;
+use tracing::*;
+
+use crate::{
+    chain::ChainRpc,
+    checker::find_referral,
+    config::pbaas,
+    constants::CashbackStatus,
+    ledger,
+    rpc::Client,
+    storage::{Registration, Storage},
+};
+
+/// Number of blocks between progress reports.
+const PROGRESS_INTERVAL: u64 = 1000;
+
+#[derive(Debug, Default)]
+pub struct RescanReport {
+    pub blocks: u64,
+    /// Referrals found in the range, including the ones that were already known.
+    pub found: usize,
+    /// Referrals that were not known yet and have been stored.
+    pub stored: usize,
+}
+
+/// Scans blocks `from..=to`. Referrals that are not known yet are stored as pending, with the
+/// referral reward they paid to the wallet, or as ineligible if `historical` is set so they are
+/// not paid out automatically. Historical rewards came in before the ledger was opened, and are
+/// not posted. Stops between blocks once `shutdown` is cancelled, what was stored until then is
+/// kept.
+#[instrument(level = "trace", skip(storage, config, shutdown), fields(chain = config.currency_id.to_string()))]
+pub async fn rescan(
+    storage: &dyn Storage,
+    config: pbaas::Config,
+    from: u64,
+    to: u64,
+    historical: bool,
+    shutdown: &CancellationToken,
+) -> Result<RescanReport> {
+    ensure!(from <= to, "--from ({from}) must not be above --to ({to})");
+
+    let currency_id = config.currency_id.clone();
+    let referral_id = config.referral_currency_id.clone();
+    let referral_amount = config.referral_amount;
+    let payout_address = config.payout_address.clone();
+    let client: Client = config.try_into()?;
+    let (status, entries) = if historical {
+        (CashbackStatus::IneligibleHistorical, Vec::new())
+    } else {
+        let in_wallet =
+            ledger::referral_in_wallet(&client, &referral_id, payout_address.as_deref()).await?;
+        (
+            CashbackStatus::Pending,
+            ledger::referral_reward_entries(referral_amount, in_wallet),
+        )
+    };
+    let total = to - from + 1;
+    let mut report = RescanReport::default();
+
+    info!("rescanning {total} blocks ({from} to {to}) on {currency_id}");
+
+    for height in from..=to {
+        if shutdown.is_cancelled() {
+            bail!(
+                "rescan of {currency_id} stopped at height {height} for shutdown, {} of {total} blocks scanned",
+                report.blocks
+            );
+        }
+
+        let block_hash = client.block_hash(height).await?;
+
+        for tx in client.block(&block_hash).await? {
+            let registration = Registration {
+                txid: &tx.txid,
+                referral_id: &referral_id,
+                block: Some((height, &block_hash)),
+            };
+
+            for reservation in &tx.reservations {
+                if let Some((name, name_id)) = find_referral(reservation, &referral_id) {
+                    report.found += 1;
+
+                    let stored = storage
+                        .store_cashback(
+                            &currency_id,
+                            &name_id,
+                            &name,
+                            status,
+                            registration,
+                            &entries,
+                        )
+                        .await?;
+
+                    if stored {
+                        info!("stored missed referral for {name}@ ({name_id}) at height {height}");
+                        report.stored += 1;
+                    } else {
+                        debug!("{name}@ ({name_id}) is already known");
+                    }
+                }
+            }
+        }
+
+        report.blocks += 1;
+        if report.blocks % PROGRESS_INTERVAL == 0 {
+            info!(
+                "rescanned {}/{total} blocks (height {height}), {} referrals found, {} stored",
+                report.blocks, report.found, report.stored
+            );
+        }
+    }
+
+    info!("rescan of {currency_id} done: {report:?}");
+
+    Ok(report)
+}
@@ -1,42 +1,830 @@
-use anyhow::Result;
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    path::Path,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use chrono::DateTime;
 use secrecy::{ExposeSecret, Secret};
-use vrsc_rpc::json::vrsc::Address;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tracing::*;
+use vrsc_rpc::{
+    bitcoin::{BlockHash, Txid},
+    client::{RpcApi, SendCurrencyOutput},
+    json::{
+        vrsc::{Address, Amount},
+        TransactionVout,
+    },
+};
 
-use crate::config::pbaas;
+use crate::{
+    chain::{
+        ChainInfo, ChainRpc, IdentityReservation, Operation, Output, Transaction, WalletSend,
+        WalletTransaction,
+    },
+    config::pbaas::{self, RpcEndpoint},
+    telemetry,
+};
 
-#[derive(Debug)]
+/// Async facade over the blocking `vrsc_rpc` client. Every call runs on tokio's blocking thread
+/// pool, so a slow daemon does not stall the runtime shared with Discord and the other chains.
+///
+/// When an endpoint becomes unreachable, the call fails and the next call goes to the next
+/// configured endpoint. Calls are never retried on another endpoint themselves, a `sendcurrency`
+/// that reached the daemon before the connection dropped must not be sent twice. The status of a
+/// `sendcurrency` is always asked from the endpoint that took it, the other daemons don't know
+/// the operation.
+#[derive(Debug, Clone)]
 pub struct Client {
     pub currency_id: Address,
-    pub client: vrsc_rpc::client::Client,
+    endpoints: Arc<Vec<RpcEndpoint>>,
+    active: Arc<Mutex<Connection>>,
+    /// The endpoint each `sendcurrency` went to, by its opid, until the operation has finished.
+    operations: Arc<Mutex<HashMap<String, usize>>>,
+    timeout: Duration,
+}
+
+#[derive(Debug, Default)]
+struct Connection {
+    index: usize,
+    client: Option<Arc<vrsc_rpc::client::Client>>,
 }
 
 impl Client {
     pub fn new(
         currency_id: Address,
-        rpc_port: u16,
-        rpc_user: String,
-        rpc_password: Secret<String>,
+        endpoints: Vec<RpcEndpoint>,
+        timeout: Duration,
     ) -> Result<Self> {
+        if endpoints.is_empty() {
+            bail!("{currency_id}: no rpc endpoints configured");
+        }
+
         Ok(Self {
             currency_id,
-            client: vrsc_rpc::client::Client::rpc(vrsc_rpc::Auth::UserPass(
-                format!("http://localhost:{}", rpc_port),
-                rpc_user,
-                rpc_password.expose_secret().to_owned(),
-            ))?,
+            endpoints: Arc::new(endpoints),
+            active: Arc::new(Mutex::new(Connection::default())),
+            operations: Arc::new(Mutex::new(HashMap::new())),
+            timeout,
+        })
+    }
+
+    /// Runs `f` against the daemon and waits at most the configured timeout for it to return.
+    ///
+    /// A call that times out is not aborted, it keeps its blocking thread until the underlying
+    /// HTTP request finishes.
+    pub async fn call<T, E, F>(&self, method: &'static str, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        E: std::error::Error + Send + Sync + 'static,
+        F: FnOnce(&vrsc_rpc::client::Client) -> Result<T, E> + Send + 'static,
+    {
+        let index = self.active.lock().unwrap().index;
+
+        self.call_on(index, method, f).await
+    }
+
+    /// Like [`Self::call`], against the endpoint at `index` whether it is the active one or not.
+    async fn call_on<T, E, F>(&self, index: usize, method: &'static str, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        E: std::error::Error + Send + Sync + 'static,
+        F: FnOnce(&vrsc_rpc::client::Client) -> Result<T, E> + Send + 'static,
+    {
+        let client = self.connection(index).await?;
+        let started = Instant::now();
+        let handle = tokio::task::spawn_blocking(move || f(&client));
+
+        let joined = tokio::time::timeout(self.timeout, handle).await;
+        telemetry::rpc_call(&self.currency_id, method, started.elapsed());
+
+        let result = match joined {
+            Ok(joined) => joined.with_context(|| format!("{method} panicked"))?,
+            Err(_) => {
+                self.fail_over(index);
+                bail!("{method} timed out after {:?}", self.timeout);
+            }
+        };
+
+        result.map_err(|e| {
+            let e = anyhow::Error::new(e);
+            if is_transport_error(&e) {
+                self.fail_over(index);
+            }
+
+            e.context(format!("{method} failed"))
         })
     }
+
+    /// Returns a client of the endpoint at `index`. The client of the active endpoint is kept,
+    /// other endpoints are connected to for every call.
+    async fn connection(&self, index: usize) -> Result<Arc<vrsc_rpc::client::Client>> {
+        {
+            let active = self.active.lock().unwrap();
+            if active.index == index {
+                if let Some(client) = &active.client {
+                    return Ok(Arc::clone(client));
+                }
+            }
+        }
+
+        // Connecting reads the cookie file and may build a blocking http client, so it runs on
+        // the blocking pool as well.
+        let endpoint = self.endpoints[index].clone();
+        let timeout = self.timeout;
+        let client = tokio::task::spawn_blocking(move || connect(&endpoint, timeout))
+            .await
+            .context("connecting panicked")??;
+        let client = Arc::new(client);
+
+        let mut active = self.active.lock().unwrap();
+        if active.index == index {
+            active.client = Some(Arc::clone(&client));
+        }
+
+        Ok(client)
+    }
+
+    /// Moves to the next endpoint, unless another call already did.
+    fn fail_over(&self, index: usize) {
+        let mut active = self.active.lock().unwrap();
+        if active.index != index {
+            return;
+        }
+
+        active.index = (index + 1) % self.endpoints.len();
+        active.client = None;
+
+        if self.endpoints.len() > 1 {
+            warn!(
+                "{}: rpc endpoint {} unreachable, switching to {}",
+                self.currency_id, self.endpoints[index].url, self.endpoints[active.index].url
+            );
+        }
+    }
 }
 
 impl TryFrom<pbaas::Config> for Client {
     type Error = anyhow::Error;
 
     fn try_from(value: pbaas::Config) -> Result<Self, Self::Error> {
-        Ok(Self::new(
+        let endpoints = value.rpc_endpoints()?;
+
+        Self::new(
             value.currency_id,
-            value.rpc_port,
-            value.rpc_user,
-            Secret::new(value.rpc_password),
-        )?)
+            endpoints,
+            Duration::from_secs(value.rpc_timeout_secs),
+        )
+    }
+}
+
+#[async_trait]
+impl ChainRpc for Client {
+    async fn blockchain_info(&self) -> Result<ChainInfo> {
+        let info = self
+            .call("getblockchaininfo", |client| client.get_blockchain_info())
+            .await?;
+
+        Ok(ChainInfo {
+            blocks: info.blocks,
+            headers: info.headers,
+        })
+    }
+
+    async fn best_block_hash(&self) -> Result<BlockHash> {
+        self.call("getbestblockhash", |client| client.get_best_block_hash())
+            .await
+    }
+
+    async fn block_hash(&self, height: u64) -> Result<BlockHash> {
+        self.call("getblockhash", move |client| client.get_block_hash(height))
+            .await
+    }
+
+    async fn block(&self, hash: &BlockHash) -> Result<Vec<Transaction>> {
+        let hash = *hash;
+        let block = self
+            .call("getblock", move |client| client.get_block(&hash, 2))
+            .await?;
+
+        block
+            .tx
+            .iter()
+            .map(|tx| transaction(tx.txid, &tx.vout))
+            .collect()
+    }
+
+    async fn decode_raw_transaction(&self, raw_tx: &[u8]) -> Result<Transaction> {
+        let hex = raw_tx
+            .iter()
+            .map(|byte| format!("{:02x}", *byte))
+            .collect::<String>();
+
+        let tx: DecodedTransaction = self
+            .call("decoderawtransaction", move |client| {
+                client.call("decoderawtransaction", &[hex.into()])
+            })
+            .await?;
+
+        transaction(tx.txid, &tx.vout)
+    }
+
+    async fn identity_height(&self, name_id: &Address) -> Result<u64> {
+        let name_id = name_id.to_string();
+        let history = self
+            .call("getidentityhistory", move |client| {
+                client.get_identity_history(&name_id, 0, 99999999)
+            })
+            .await?;
+
+        Ok(history.blockheight as u64)
+    }
+
+    async fn send_currency(&self, from: &str, outputs: &[Output]) -> Result<String> {
+        let from = from.to_owned();
+        let outputs = outputs
+            .iter()
+            .map(|output| SendCurrencyOutput {
+                currency: None,
+                amount: output.amount,
+                address: output.address.to_string(),
+                convertto: None,
+                via: None,
+            })
+            .collect::<Vec<_>>();
+
+        let index = self.active.lock().unwrap().index;
+        let opid = self
+            .call_on(index, "sendcurrency", move |client| {
+                client.send_currency(&from, outputs, None, None)
+            })
+            .await?;
+        self.operations.lock().unwrap().insert(opid.clone(), index);
+
+        Ok(opid)
+    }
+
+    async fn operation_status(&self, opid: &str) -> Result<Option<Operation>> {
+        // An operation that was started before a restart is asked from the active endpoint.
+        let pinned = self.operations.lock().unwrap().get(opid).copied();
+        let index = pinned.unwrap_or_else(|| self.active.lock().unwrap().index);
+
+        let owned = opid.to_owned();
+        let statuses = self
+            .call_on(index, "z_getoperationstatus", move |client| {
+                client.z_get_operation_status(vec![&owned])
+            })
+            .await?;
+
+        let operation = match statuses.into_iter().next() {
+            Some(Some(status)) => {
+                debug!("op-status: {:#?}", status);
+
+                Some(
+                    if ["queued", "executing"].contains(&status.status.as_ref()) {
+                        Operation::Executing
+                    } else if let Some(result) = &status.result {
+                        Operation::Success(result.txid)
+                    } else {
+                        Operation::Failed(status.status)
+                    },
+                )
+            }
+            _ => None,
+        };
+
+        if operation != Some(Operation::Executing) {
+            self.operations.lock().unwrap().remove(opid);
+        }
+
+        Ok(operation)
+    }
+
+    async fn wallet_transaction(&self, txid: &Txid) -> Result<WalletTransaction> {
+        let txid = txid.to_string();
+        self.call("gettransaction", move |client| {
+            client.call("gettransaction", &[txid.into()])
+        })
+        .await
+    }
+
+    async fn wallet_sends(&self, address: &Address) -> Result<Vec<WalletSend>> {
+        let transactions: Vec<ListedTransaction> = self
+            .call("listtransactions", |client| {
+                client.call("listtransactions", &["*".into(), 1000.into()])
+            })
+            .await?;
+
+        let address = address.to_string();
+        transactions
+            .into_iter()
+            .filter(|tx| {
+                tx.category == "send"
+                    && tx.address.as_deref() == Some(address.as_str())
+                    && !tx.abandoned
+                    && tx.confirmations >= 0
+            })
+            .map(|tx| {
+                Ok(WalletSend {
+                    txid: tx.txid,
+                    amount: Amount::from_btc(-tx.amount)?,
+                    time: DateTime::from_timestamp(tx.time, 0)
+                        .ok_or_else(|| anyhow!("invalid time {} of {}", tx.time, tx.txid))?,
+                })
+            })
+            .collect()
+    }
+
+    async fn can_spend_for(&self, identity: &Address) -> Result<bool> {
+        let identity = identity.to_string();
+        let found: WalletIdentity = self
+            .call("getidentity", move |client| {
+                client.call("getidentity", &[identity.into()])
+            })
+            .await?;
+
+        Ok(found.canspendfor)
+    }
+
+    async fn abandon_transaction(&self, txid: &Txid) -> Result<()> {
+        let txid = txid.to_string();
+        self.call("abandontransaction", move |client| {
+            client.call::<serde_json::Value>("abandontransaction", &[txid.into()])
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    async fn has_transaction(&self, txid: &Txid) -> Result<bool> {
+        let txid = txid.to_string();
+        let found = self
+            .call("getrawtransaction", move |client| {
+                client.call::<serde_json::Value>("getrawtransaction", &[txid.into()])
+            })
+            .await;
+
+        match found {
+            Ok(_) => Ok(true),
+            Err(e) if is_unknown_tx_error(&e) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn wallet_balance(&self, address: Option<&str>) -> Result<i64> {
+        let balance: f64 = match address {
+            Some(address) => {
+                let address = address.to_owned();
+                self.call("z_getbalance", move |client| {
+                    client.call("z_getbalance", &[address.into(), 0.into()])
+                })
+                .await?
+            }
+            None => {
+                self.call("getbalance", |client| {
+                    client.call("getbalance", &["*".into(), 0.into()])
+                })
+                .await?
+            }
+        };
+
+        Ok(Amount::from_btc(balance)?.to_sat() as i64)
+    }
+}
+
+/// The part of a `getidentity` result that tells whether the identity is in the wallet.
+#[derive(Debug, Deserialize)]
+struct WalletIdentity {
+    #[serde(default)]
+    canspendfor: bool,
+}
+
+/// An entry of `listtransactions`, one per output of a wallet transaction.
+#[derive(Debug, Deserialize)]
+struct ListedTransaction {
+    address: Option<String>,
+    category: String,
+    txid: Txid,
+    /// In coins, negative for a send.
+    amount: f64,
+    /// Unix time the wallet made the transaction at.
+    time: i64,
+    confirmations: i64,
+    #[serde(default)]
+    abandoned: bool,
+}
+
+/// The part of a `decoderawtransaction` result that is needed to find referrals.
+#[derive(Debug, Deserialize)]
+struct DecodedTransaction {
+    txid: Txid,
+    vout: Vec<TransactionVout>,
+}
+
+fn transaction(txid: Txid, vout: &[TransactionVout]) -> Result<Transaction> {
+    let reservations = vout
+        .iter()
+        .filter_map(|vout| vout.script_pubkey.identity_reservation.as_ref())
+        .map(|reservation| {
+            Ok(IdentityReservation {
+                name: reservation.name.clone(),
+                name_id: reservation.nameid.clone(),
+                referral: reservation
+                    .referral
+                    .as_deref()
+                    .map(Address::from_str)
+                    .transpose()?,
+            })
+        })
+        .collect::<Result<_>>()?;
+
+    Ok(Transaction { txid, reservations })
+}
+
+/// The settings this service needs from the conf file that the daemon writes to its data
+/// directory.
+#[derive(Debug, Default)]
+pub struct DaemonConf {
+    pub rpc_user: Option<String>,
+    pub rpc_password: Option<Secret<String>>,
+    pub rpc_port: Option<u16>,
+    pub zmq_block_hash_url: Option<String>,
+    pub zmq_raw_tx_url: Option<String>,
+}
+
+impl DaemonConf {
+    /// Reads `<data_dir>/<dir name>.conf`, e.g. `~/.komodo/VRSC/VRSC.conf` or
+    /// `~/.verus/pbaas/<chain id>/<chain id>.conf`.
+    pub fn from_data_dir(data_dir: &Path) -> Result<Self> {
+        let name = data_dir
+            .file_name()
+            .ok_or_else(|| anyhow!("{} is not a data directory", data_dir.display()))?;
+        let path = data_dir.join(format!("{}.conf", name.to_string_lossy()));
+        let contents = fs::read_to_string(&path)
+            .with_context(|| format!("failed to read daemon conf {}", path.display()))?;
+
+        Self::parse(&name.to_string_lossy(), &contents)
+            .with_context(|| format!("invalid daemon conf {}", path.display()))
+    }
+
+    /// Parses the conf file of chain `name` the way the daemon does: everything after a `#` is
+    /// a comment, values may be quoted and the first of duplicate keys wins.
+    fn parse(name: &str, contents: &str) -> Result<Self> {
+        let mut conf = Self::default();
+
+        for line in contents.lines() {
+            let line = line.split_once('#').map_or(line, |(line, _)| line).trim();
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            let value = value.trim();
+            let value = value
+                .strip_prefix('"')
+                .and_then(|value| value.strip_suffix('"'))
+                .unwrap_or(value)
+                .to_owned();
+
+            match key.trim() {
+                "rpcuser" if conf.rpc_user.is_none() => conf.rpc_user = Some(value),
+                "rpcpassword" if conf.rpc_password.is_none() => {
+                    conf.rpc_password = Some(Secret::new(value))
+                }
+                "rpcport" if conf.rpc_port.is_none() => {
+                    conf.rpc_port = Some(value.parse().context("invalid rpcport")?)
+                }
+                "zmqpubhashblock" if conf.zmq_block_hash_url.is_none() => {
+                    conf.zmq_block_hash_url = Some(value)
+                }
+                "zmqpubrawtx" if conf.zmq_raw_tx_url.is_none() => conf.zmq_raw_tx_url = Some(value),
+                _ => {}
+            }
+        }
+
+        conf.rpc_port = conf.rpc_port.or_else(|| default_rpc_port(name));
+
+        Ok(conf)
+    }
+}
+
+/// The port the daemon of chain `name` listens on when its conf file doesn't set `rpcport`.
+fn default_rpc_port(name: &str) -> Option<u16> {
+    match name {
+        "VRSC" => Some(27486),
+        "vrsctest" => Some(18843),
+        _ => None,
+    }
+}
+
+/// Whether the error means the daemon could not be reached, as opposed to the daemon returning
+/// an error.
+pub fn is_transport_error(e: &anyhow::Error) -> bool {
+    matches!(
+        e.downcast_ref::<vrsc_rpc::Error>(),
+        Some(vrsc_rpc::Error::JsonRpc(jsonrpc::Error::Transport(_)))
+    )
+}
+
+/// Whether the daemon answered the call with an error, so it did not act on it.
+pub fn is_rpc_error(e: &anyhow::Error) -> bool {
+    matches!(
+        e.downcast_ref::<vrsc_rpc::Error>(),
+        Some(vrsc_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(_)))
+    )
+}
+
+/// Whether the daemon rejected the call because it is still starting up or reindexing.
+pub fn is_warmup_error(e: &anyhow::Error) -> bool {
+    // RPC_IN_WARMUP
+    matches!(
+        e.downcast_ref::<vrsc_rpc::Error>(),
+        Some(vrsc_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(rpc_error))) if rpc_error.code == -28
+    )
+}
+
+/// Whether the daemon doesn't know the transaction that was asked for, neither in a block nor in
+/// the mempool.
+pub fn is_unknown_tx_error(e: &anyhow::Error) -> bool {
+    // RPC_INVALID_ADDRESS_OR_KEY
+    matches!(
+        e.downcast_ref::<vrsc_rpc::Error>(),
+        Some(vrsc_rpc::Error::JsonRpc(jsonrpc::Error::Rpc(rpc_error))) if rpc_error.code == -5
+    )
+}
+
+fn connect(endpoint: &RpcEndpoint, timeout: Duration) -> Result<vrsc_rpc::client::Client> {
+    let (user, password) = credentials(endpoint)?;
+
+    if endpoint.url.starts_with("https://") {
+        let transport = HttpsTransport::new(endpoint, user, password, timeout)?;
+
+        return Ok(vrsc_rpc::client::Client::from_jsonrpc(
+            jsonrpc::Client::with_transport(transport),
+        ));
+    }
+
+    Ok(vrsc_rpc::client::Client::rpc(vrsc_rpc::Auth::UserPass(
+        endpoint.url.clone(),
+        user,
+        password,
+    ))?)
+}
+
+/// Reads the credentials of an endpoint. The cookie file is read again on every reconnect, as
+/// the daemon writes a new one each time it starts.
+fn credentials(endpoint: &RpcEndpoint) -> Result<(String, String)> {
+    if let Some(cookie_file) = &endpoint.cookie_file {
+        let cookie = fs::read_to_string(cookie_file)
+            .with_context(|| format!("failed to read cookie file {}", cookie_file.display()))?;
+
+        return cookie
+            .trim()
+            .split_once(':')
+            .map(|(user, password)| (user.to_owned(), password.to_owned()))
+            .ok_or_else(|| anyhow!("{} is not a valid cookie file", cookie_file.display()));
+    }
+
+    match (&endpoint.user, &endpoint.password) {
+        (Some(user), Some(password)) => Ok((user.clone(), password.expose_secret().clone())),
+        _ => bail!(
+            "{}: set either a user and password or a cookie file",
+            endpoint.url
+        ),
+    }
+}
+
+/// JSON-RPC transport for daemons behind TLS, which the `vrsc_rpc` http transport does not
+/// support.
+struct HttpsTransport {
+    client: reqwest::blocking::Client,
+    url: String,
+    user: String,
+    password: String,
+}
+
+impl HttpsTransport {
+    fn new(
+        endpoint: &RpcEndpoint,
+        user: String,
+        password: String,
+        timeout: Duration,
+    ) -> Result<Self> {
+        let mut builder = reqwest::blocking::Client::builder().timeout(timeout);
+
+        if let Some(tls_ca) = &endpoint.tls_ca {
+            let pem = fs::read(tls_ca)
+                .with_context(|| format!("failed to read tls ca {}", tls_ca.display()))?;
+            builder = builder.add_root_certificate(reqwest::Certificate::from_pem(&pem)?);
+        }
+
+        Ok(Self {
+            client: builder.build()?,
+            url: endpoint.url.clone(),
+            user,
+            password,
+        })
+    }
+
+    fn post<B: Serialize + ?Sized, R: DeserializeOwned>(
+        &self,
+        body: &B,
+    ) -> Result<R, jsonrpc::Error> {
+        let response = self
+            .client
+            .post(&self.url)
+            .basic_auth(&self.user, Some(&self.password))
+            .json(body)
+            .send()
+            .map_err(|e| jsonrpc::Error::Transport(Box::new(e)))?;
+
+        // The daemon answers a call that failed with a 500 or 404 and the error in the body, so
+        // the status is not looked at. Only a body that is not a response is a transport error.
+        response
+            .json()
+            .map_err(|e| jsonrpc::Error::Transport(Box::new(e)))
+    }
+}
+
+impl jsonrpc::Transport for HttpsTransport {
+    fn send_request(&self, req: jsonrpc::Request) -> Result<jsonrpc::Response, jsonrpc::Error> {
+        self.post(&req)
+    }
+
+    fn send_batch(
+        &self,
+        reqs: &[jsonrpc::Request],
+    ) -> Result<Vec<jsonrpc::Response>, jsonrpc::Error> {
+        self.post(reqs)
+    }
+
+    fn fmt_target(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.url)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    use super::*;
+
+    /// Answers a single JSON-RPC request with `status` and `error`, the way the daemon answers a
+    /// call that failed. Returns the url it listens on.
+    fn serve_error(status: &'static str, code: i64, message: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                if let Some((name, value)) = line.split_once(':') {
+                    if name.eq_ignore_ascii_case("content-length") {
+                        content_length = value.trim().parse().unwrap();
+                    }
+                }
+            }
+            let mut request = vec![0; content_length];
+            reader.read_exact(&mut request).unwrap();
+            let request: serde_json::Value = serde_json::from_slice(&request).unwrap();
+
+            let body = serde_json::json!({
+                "result": null,
+                "error": { "code": code, "message": message },
+                "id": request["id"],
+            })
+            .to_string();
+            write!(
+                reader.get_mut(),
+                "HTTP/1.1 {status}\r\ncontent-type: application/json\r\ncontent-length: {}\r\n\r\n{body}",
+                body.len()
+            )
+            .unwrap();
+        });
+
+        url
+    }
+
+    fn call(url: String, method: &str) -> anyhow::Error {
+        let endpoint = RpcEndpoint {
+            url,
+            user: None,
+            password: None,
+            cookie_file: None,
+            tls_ca: None,
+        };
+        let transport = HttpsTransport::new(
+            &endpoint,
+            "user".into(),
+            "password".into(),
+            Duration::from_secs(5),
+        )
+        .unwrap();
+        let client =
+            vrsc_rpc::client::Client::from_jsonrpc(jsonrpc::Client::with_transport(transport));
+
+        anyhow::Error::new(client.call::<serde_json::Value>(method, &[]).unwrap_err())
+    }
+
+    #[test]
+    fn reads_errors_the_daemon_answers_with_a_500() {
+        let e = call(
+            serve_error("500 Internal Server Error", -6, "Insufficient funds"),
+            "sendcurrency",
+        );
+
+        assert!(is_rpc_error(&e));
+        assert!(!is_transport_error(&e));
+    }
+
+    #[test]
+    fn reads_errors_the_daemon_answers_with_a_404() {
+        let e = call(
+            serve_error(
+                "404 Not Found",
+                -5,
+                "No such mempool or blockchain transaction",
+            ),
+            "getrawtransaction",
+        );
+
+        assert!(is_unknown_tx_error(&e));
+        assert!(!is_transport_error(&e));
+    }
+
+    #[test]
+    fn reads_warmup_errors() {
+        let e = call(
+            serve_error("500 Internal Server Error", -28, "Loading block index..."),
+            "getblockchaininfo",
+        );
+
+        assert!(is_warmup_error(&e));
+    }
+
+    #[test]
+    fn parses_quoted_values_and_comments() {
+        let conf = DaemonConf::parse(
+            "VRSC",
+            "# written by the daemon\n\
+            rpcuser=\"verus\"\n\
+            rpcpassword = \"secret\" # keep this safe\n\
+            rpcport=27486\n\
+            #zmqpubhashblock=tcp://127.0.0.1:1111\n\
+            zmqpubrawtx=tcp://127.0.0.1:2222\n",
+        )
+        .unwrap();
+
+        assert_eq!(conf.rpc_user.as_deref(), Some("verus"));
+        assert_eq!(
+            conf.rpc_password
+                .as_ref()
+                .map(|password| password.expose_secret().as_str()),
+            Some("secret")
+        );
+        assert_eq!(conf.rpc_port, Some(27486));
+        assert_eq!(conf.zmq_block_hash_url, None);
+        assert_eq!(conf.zmq_raw_tx_url.as_deref(), Some("tcp://127.0.0.1:2222"));
+    }
+
+    #[test]
+    fn keeps_the_first_of_duplicate_keys() {
+        let conf = DaemonConf::parse(
+            "VRSC",
+            "rpcuser=first\nrpcport=1111\nrpcuser=second\nrpcport=2222\n",
+        )
+        .unwrap();
+
+        assert_eq!(conf.rpc_user.as_deref(), Some("first"));
+        assert_eq!(conf.rpc_port, Some(1111));
+    }
+
+    #[test]
+    fn falls_back_to_the_default_port_of_the_chain() {
+        let conf = |name| DaemonConf::parse(name, "rpcuser=verus\n").unwrap().rpc_port;
+
+        assert_eq!(conf("VRSC"), Some(27486));
+        assert_eq!(conf("vrsctest"), Some(18843));
+        assert_eq!(conf("f315367528394674d45277e369629605a1c3ce9f"), None);
+    }
+
+    #[test]
+    fn rejects_an_invalid_port() {
+        assert!(DaemonConf::parse("VRSC", "rpcport=verus\n").is_err());
     }
 }
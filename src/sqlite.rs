@@ -0,0 +1,1198 @@
+//! The sqlite backend, an embedded database in a single file. Queries are checked at runtime,
+//! the query macros only check against the Postgres schema, so the tests at the bottom run them
+//! against an in-memory database.
+
+use std::{str::FromStr, time::Duration};
+
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use sqlx::{
+    migrate::Migrator,
+    query::QueryAs,
+    sqlite::{SqliteArguments, SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions},
+    SqliteConnection, SqlitePool,
+};
+use uuid::Uuid;
+use vrsc_rpc::{bitcoin::Txid, json::vrsc::Address};
+
+use crate::{
+    config::DbConfig,
+    constants::{AuditAction, AuditEntry, Cashback, CashbackStatus},
+    ledger::{self, Account},
+    postgres::DbCashback,
+    storage::{self, CashbackFilter, PaidRange, Payout, Registration, Storage},
+};
+
+pub static MIGRATOR: Migrator = sqlx::migrate!("./migrations_sqlite");
+
+/// Prepends the columns of [`DbCashback`] to the rest of a query on `cashbacks`.
+macro_rules! select_cashbacks {
+    ($rest:literal) => {
+        concat!(
+            "SELECT id, currency_id, name_id, name_str, referral_id, txid, opid, sending_at, status,
+                attempts, last_error, skip_reason, amount, fee, payout_currency, registration_txid,
+                detected_block_height, detected_block_hash, paid_at, paid_manually, payout_block_height,
+                created_at, updated_at
+            FROM cashbacks ",
+            $rest
+        )
+    };
+}
+
+type CashbackQuery<'q> = QueryAs<'q, sqlx::Sqlite, DbCashback, SqliteArguments<'q>>;
+
+#[derive(Debug, Clone)]
+pub struct Sqlite {
+    pool: SqlitePool,
+}
+
+impl Sqlite {
+    pub async fn connect(config: &DbConfig) -> Result<Self> {
+        let path = config
+            .path
+            .as_ref()
+            .ok_or_else(|| anyhow!("`path` must be set for the sqlite backend"))?;
+
+        let options = SqliteConnectOptions::new()
+            .filename(path)
+            .create_if_missing(true)
+            .journal_mode(SqliteJournalMode::Wal)
+            .busy_timeout(Duration::from_secs(5));
+        let pool = SqlitePoolOptions::new()
+            .connect_with(options)
+            .await
+            .with_context(|| format!("failed to open {}", path.display()))?;
+
+        Ok(Self { pool })
+    }
+
+    /// A migrated database that lives as long as the pool, for tests.
+    #[cfg(test)]
+    pub async fn in_memory() -> Result<Self> {
+        // Every connection to `:memory:` opens a database of its own, so the pool keeps a single
+        // connection open for good.
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .connect_with(SqliteConnectOptions::from_str("sqlite::memory:")?)
+            .await?;
+
+        let storage = Self { pool };
+        storage.migrate().await?;
+
+        Ok(storage)
+    }
+
+    async fn fetch_cashbacks(&self, query: CashbackQuery<'_>) -> Result<Vec<Cashback>> {
+        let rows = query.fetch_all(&self.pool).await?;
+
+        Ok(rows
+            .into_iter()
+            .map(Cashback::try_from)
+            .collect::<Result<_, _>>()?)
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct DbAuditEntry {
+    id: Uuid,
+    actor: String,
+    action: String,
+    currency_id: Option<String>,
+    cashback_id: Option<Uuid>,
+    detail: Option<String>,
+    created_at: DateTime<Utc>,
+}
+
+#[async_trait]
+impl Storage for Sqlite {
+    async fn migrate(&self) -> Result<()> {
+        MIGRATOR.run(&self.pool).await?;
+
+        Ok(())
+    }
+
+    async fn check_schema_version(&self) -> Result<()> {
+        let expected = MIGRATOR.iter().map(|migration| migration.version).max();
+        let applied: Option<i64> =
+            sqlx::query_scalar("SELECT MAX(version) FROM _sqlx_migrations WHERE success")
+                .fetch_one(&self.pool)
+                .await
+                .context("failed to read the schema version, has the database been migrated?")?;
+
+        if applied != expected {
+            bail!(
+                "database schema is at version {:?}, this binary expects {:?}",
+                applied,
+                expected
+            );
+        }
+
+        Ok(())
+    }
+
+    async fn ping(&self) -> Result<()> {
+        sqlx::query("SELECT 1").fetch_one(&self.pool).await?;
+
+        Ok(())
+    }
+
+    async fn store_cashback(
+        &self,
+        currency_id: &Address,
+        name_id: &Address,
+        name: &str,
+        status: CashbackStatus,
+        registration: Registration<'_>,
+        entries: &[ledger::Entry],
+    ) -> Result<bool> {
+        let now = Utc::now();
+        let mut tx = self.pool.begin().await?;
+
+        let id: Option<Uuid> = sqlx::query_scalar(
+            "INSERT INTO cashbacks (id, currency_id, name_id, name_str, status, referral_id,
+                registration_txid, detected_block_height, detected_block_hash, created_at,
+                updated_at)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?10)
+            ON CONFLICT (currency_id, name_id) DO UPDATE
+            SET status = excluded.status,
+                referral_id = excluded.referral_id,
+                registration_txid = excluded.registration_txid,
+                detected_block_height = excluded.detected_block_height,
+                detected_block_hash = excluded.detected_block_hash,
+                updated_at = excluded.updated_at
+            WHERE cashbacks.status = 'mempool' AND excluded.status <> 'mempool'
+            RETURNING id",
+        )
+        .bind(Uuid::now_v7())
+        .bind(currency_id.to_string())
+        .bind(name_id.to_string())
+        .bind(name)
+        .bind(status.as_str())
+        .bind(registration.referral_id.to_string())
+        .bind(registration.txid.to_string())
+        .bind(registration.block.map(|(height, _)| height as i64))
+        .bind(registration.block.map(|(_, hash)| hash.to_string()))
+        .bind(now)
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        let Some(id) = id else {
+            return Ok(false);
+        };
+
+        if !entries.is_empty() {
+            insert_ledger_entries(
+                &mut tx,
+                currency_id,
+                entries,
+                Some(&id),
+                Some(registration.txid),
+                None,
+            )
+            .await?;
+        }
+        tx.commit().await?;
+
+        Ok(true)
+    }
+
+    async fn delete_stale_mempool_cashbacks(
+        &self,
+        currency_id: &Address,
+        seen_before: DateTime<Utc>,
+    ) -> Result<u64> {
+        let result = sqlx::query(
+            "DELETE FROM cashbacks
+            WHERE currency_id = ?1 AND status = 'mempool' AND created_at < ?2",
+        )
+        .bind(currency_id.to_string())
+        .bind(seen_before)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    async fn start_payout(&self, id: &Uuid, payout: Payout<'_>) -> Result<bool> {
+        let result = sqlx::query(
+            "UPDATE cashbacks
+            SET status = 'sending', txid = NULL, opid = NULL, last_error = NULL, amount = ?2,
+                fee = ?3, payout_currency = ?4, paid_at = NULL, payout_block_height = NULL,
+                sending_at = ?5, updated_at = ?5
+            WHERE id = ?1 AND status = 'pending'",
+        )
+        .bind(*id)
+        .bind(payout.amount as i64)
+        .bind(payout.fee as i64)
+        .bind(payout.currency.to_string())
+        .bind(Utc::now())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn set_payout_opid(&self, id: &Uuid, opid: &str) -> Result<()> {
+        sqlx::query(
+            "UPDATE cashbacks
+            SET opid = ?2, updated_at = ?3
+            WHERE id = ?1 AND status = 'sending'",
+        )
+        .bind(*id)
+        .bind(opid)
+        .bind(Utc::now())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn update_cashback(&self, id: &Uuid, txid: &Txid) -> Result<bool> {
+        let result = sqlx::query(
+            "UPDATE cashbacks
+            SET txid = ?2, status = 'broadcast', last_error = NULL, updated_at = ?3
+            WHERE id = ?1 AND status = 'sending'",
+        )
+        .bind(*id)
+        .bind(txid.to_string())
+        .bind(Utc::now())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn record_payout_error(&self, id: &Uuid, error: &str) -> Result<()> {
+        sqlx::query(
+            "UPDATE cashbacks
+            SET last_error = ?2, updated_at = ?3
+            WHERE id = ?1 AND status = 'sending'",
+        )
+        .bind(*id)
+        .bind(error)
+        .bind(Utc::now())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn get_sending_payouts(&self, currency_id: &Address) -> Result<Vec<Cashback>> {
+        self.fetch_cashbacks(
+            sqlx::query_as(select_cashbacks!(
+                "WHERE currency_id = ?1 AND status = 'sending'"
+            ))
+            .bind(currency_id.to_string()),
+        )
+        .await
+    }
+
+    async fn get_broadcast_payouts(&self, currency_id: &Address) -> Result<Vec<Cashback>> {
+        self.fetch_cashbacks(
+            sqlx::query_as(select_cashbacks!(
+                "WHERE currency_id = ?1 AND status = 'broadcast'"
+            ))
+            .bind(currency_id.to_string()),
+        )
+        .await
+    }
+
+    async fn confirm_payout(
+        &self,
+        cashback: &Cashback,
+        height: u64,
+        entries: &[ledger::Entry],
+    ) -> Result<bool> {
+        let now = Utc::now();
+        let mut tx = self.pool.begin().await?;
+
+        let result = sqlx::query(
+            "UPDATE cashbacks
+            SET status = 'paid', paid_at = ?3, payout_block_height = ?2, updated_at = ?3
+            WHERE id = ?1 AND status = 'broadcast'",
+        )
+        .bind(cashback.id)
+        .bind(height as i64)
+        .bind(now)
+        .execute(&mut *tx)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Ok(false);
+        }
+
+        insert_ledger_entries(
+            &mut tx,
+            &cashback.currency_id,
+            entries,
+            Some(&cashback.id),
+            cashback.txid.as_ref(),
+            None,
+        )
+        .await?;
+        tx.commit().await?;
+
+        Ok(true)
+    }
+
+    async fn get_pending_cashbacks(&self, currency_id: &Address) -> Result<Vec<Cashback>> {
+        self.fetch_cashbacks(
+            sqlx::query_as(select_cashbacks!(
+                "WHERE currency_id = ?1 AND status = 'pending'"
+            ))
+            .bind(currency_id.to_string()),
+        )
+        .await
+    }
+
+    async fn get_cashback(&self, id: &Uuid) -> Result<Option<Cashback>> {
+        let mut rows = self
+            .fetch_cashbacks(sqlx::query_as(select_cashbacks!("WHERE id = ?1")).bind(*id))
+            .await?;
+
+        Ok(rows.pop())
+    }
+
+    async fn get_cashbacks_by_name_id(&self, name_id: &Address) -> Result<Vec<Cashback>> {
+        self.fetch_cashbacks(
+            sqlx::query_as(select_cashbacks!(
+                "WHERE name_id = ?1 ORDER BY created_at, id"
+            ))
+            .bind(name_id.to_string()),
+        )
+        .await
+    }
+
+    async fn get_cashback_by_name(
+        &self,
+        currency_id: &Address,
+        name: &str,
+    ) -> Result<Option<Cashback>> {
+        let mut rows = self
+            .fetch_cashbacks(
+                sqlx::query_as(select_cashbacks!(
+                    "WHERE currency_id = ?1 AND LOWER(name_str) = LOWER(?2)"
+                ))
+                .bind(currency_id.to_string())
+                .bind(name.to_owned()),
+            )
+            .await?;
+
+        Ok(rows.pop())
+    }
+
+    async fn get_chain_cashback_by_name_id(
+        &self,
+        currency_id: &Address,
+        name_id: &Address,
+    ) -> Result<Option<Cashback>> {
+        let mut rows = self
+            .fetch_cashbacks(
+                sqlx::query_as(select_cashbacks!("WHERE currency_id = ?1 AND name_id = ?2"))
+                    .bind(currency_id.to_string())
+                    .bind(name_id.to_string()),
+            )
+            .await?;
+
+        Ok(rows.pop())
+    }
+
+    async fn get_cashbacks(&self, currency_id: Option<&Address>) -> Result<Vec<Cashback>> {
+        self.fetch_cashbacks(
+            sqlx::query_as(select_cashbacks!(
+                "WHERE ?1 IS NULL OR currency_id = ?1 ORDER BY created_at"
+            ))
+            .bind(currency_id.map(|id| id.to_string())),
+        )
+        .await
+    }
+
+    async fn get_paid_cashbacks(
+        &self,
+        currency_id: Option<&Address>,
+        range: &PaidRange,
+    ) -> Result<Vec<Cashback>> {
+        self.fetch_cashbacks(
+            sqlx::query_as(select_cashbacks!(
+                "WHERE status = 'paid'
+                    AND (?1 IS NULL OR currency_id = ?1)
+                    AND (?2 IS NULL OR paid_at >= ?2)
+                    AND (?3 IS NULL OR paid_at < ?3)
+                    AND (?4 IS NULL OR payout_block_height >= ?4)
+                    AND (?5 IS NULL OR payout_block_height < ?5)
+                ORDER BY created_at"
+            ))
+            .bind(currency_id.map(|id| id.to_string()))
+            .bind(range.from_date)
+            .bind(range.to_date)
+            .bind(range.from_height.map(|height| height as i64))
+            .bind(range.to_height.map(|height| height as i64)),
+        )
+        .await
+    }
+
+    async fn list_cashbacks(
+        &self,
+        currency_id: &Address,
+        filter: &CashbackFilter,
+        limit: i64,
+        offset: i64,
+    ) -> Result<(Vec<Cashback>, i64)> {
+        let status = filter.status.map(|status| status.as_str());
+
+        let rows = self
+            .fetch_cashbacks(
+                sqlx::query_as(select_cashbacks!(
+                    "WHERE currency_id = ?1
+                        AND (?2 IS NULL OR status = ?2)
+                        AND (?3 IS NULL OR created_at >= ?3)
+                        AND (?4 IS NULL OR created_at < ?4)
+                    ORDER BY created_at DESC, id DESC
+                    LIMIT ?5 OFFSET ?6"
+                ))
+                .bind(currency_id.to_string())
+                .bind(status)
+                .bind(filter.from)
+                .bind(filter.to)
+                .bind(limit)
+                .bind(offset),
+            )
+            .await?;
+
+        let total: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*)
+            FROM cashbacks
+            WHERE currency_id = ?1
+                AND (?2 IS NULL OR status = ?2)
+                AND (?3 IS NULL OR created_at >= ?3)
+                AND (?4 IS NULL OR created_at < ?4)",
+        )
+        .bind(currency_id.to_string())
+        .bind(status)
+        .bind(filter.from)
+        .bind(filter.to)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok((rows, total))
+    }
+
+    async fn count_cashbacks_by_status(&self) -> Result<Vec<(Address, CashbackStatus, i64)>> {
+        let rows: Vec<(String, String, i64)> = sqlx::query_as(
+            "SELECT currency_id, status, COUNT(*)
+            FROM cashbacks
+            GROUP BY currency_id, status
+            ORDER BY currency_id, status",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.into_iter()
+            .map(|(currency_id, status, count)| {
+                Ok((
+                    Address::from_str(&currency_id)?,
+                    CashbackStatus::try_from(status)?,
+                    count,
+                ))
+            })
+            .collect()
+    }
+
+    async fn mark_cashback_failed(&self, id: &Uuid, error: &str) -> Result<()> {
+        sqlx::query(
+            "UPDATE cashbacks
+            SET status = 'failed', attempts = attempts + 1, last_error = ?2, updated_at = ?3
+            WHERE id = ?1 AND status = 'sending'",
+        )
+        .bind(*id)
+        .bind(error)
+        .bind(Utc::now())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn mark_payout_dropped(&self, id: &Uuid, error: &str) -> Result<bool> {
+        let result = sqlx::query(
+            "UPDATE cashbacks
+            SET status = 'failed', attempts = attempts + 1, last_error = ?2, updated_at = ?3
+            WHERE id = ?1 AND status = 'broadcast'",
+        )
+        .bind(*id)
+        .bind(error)
+        .bind(Utc::now())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn retry_cashback(&self, id: &Uuid) -> Result<bool> {
+        let result = sqlx::query(
+            "UPDATE cashbacks
+            SET status = 'pending', last_error = NULL, skip_reason = NULL, updated_at = ?2
+            WHERE id = ?1 AND status IN ('failed', 'skipped', 'ineligible_historical')",
+        )
+        .bind(*id)
+        .bind(Utc::now())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn approve_cashback(&self, id: &Uuid) -> Result<bool> {
+        let result = sqlx::query(
+            "UPDATE cashbacks
+            SET status = 'pending', updated_at = ?2
+            WHERE id = ?1 AND status = 'ineligible_historical'",
+        )
+        .bind(*id)
+        .bind(Utc::now())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn mark_cashback_paid(&self, id: &Uuid, txid: &Txid) -> Result<bool> {
+        let result = sqlx::query(
+            "UPDATE cashbacks
+            SET status = 'paid', txid = ?2, last_error = NULL, amount = NULL, fee = NULL,
+                payout_currency = NULL, paid_at = ?3, paid_manually = TRUE,
+                payout_block_height = NULL, updated_at = ?3
+            WHERE id = ?1 AND status NOT IN ('paid', 'sending', 'broadcast')",
+        )
+        .bind(*id)
+        .bind(txid.to_string())
+        .bind(Utc::now())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn skip_cashback(&self, id: &Uuid, reason: &str) -> Result<bool> {
+        let result = sqlx::query(
+            "UPDATE cashbacks
+            SET status = 'skipped', skip_reason = ?2, updated_at = ?3
+            WHERE id = ?1 AND status NOT IN ('paid', 'sending', 'broadcast')",
+        )
+        .bind(*id)
+        .bind(reason)
+        .bind(Utc::now())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn insert_audit(
+        &self,
+        actor: &str,
+        action: AuditAction,
+        currency_id: Option<&Address>,
+        cashback_id: Option<&Uuid>,
+        detail: Option<&str>,
+    ) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO audit_log (id, actor, action, currency_id, cashback_id, detail, created_at)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        )
+        .bind(Uuid::now_v7())
+        .bind(actor)
+        .bind(action.as_str())
+        .bind(currency_id.map(|id| id.to_string()))
+        .bind(cashback_id.copied())
+        .bind(detail)
+        .bind(Utc::now())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn list_audit_log(
+        &self,
+        currency_id: Option<&Address>,
+        limit: i64,
+        offset: i64,
+    ) -> Result<(Vec<AuditEntry>, i64)> {
+        let currency_id = currency_id.map(|id| id.to_string());
+
+        let rows: Vec<DbAuditEntry> = sqlx::query_as(
+            "SELECT id, actor, action, currency_id, cashback_id, detail, created_at
+            FROM audit_log
+            WHERE ?1 IS NULL OR currency_id = ?1
+            ORDER BY created_at DESC, id DESC
+            LIMIT ?2 OFFSET ?3",
+        )
+        .bind(&currency_id)
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let entries = rows
+            .into_iter()
+            .map(|row| {
+                Ok(AuditEntry {
+                    id: row.id,
+                    actor: row.actor,
+                    action: row.action,
+                    currency_id: row
+                        .currency_id
+                        .map(|currency_id| Address::from_str(&currency_id))
+                        .transpose()?,
+                    cashback_id: row.cashback_id,
+                    detail: row.detail,
+                    created_at: row.created_at,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let total: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*)
+            FROM audit_log
+            WHERE ?1 IS NULL OR currency_id = ?1",
+        )
+        .bind(&currency_id)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok((entries, total))
+    }
+
+    async fn set_payouts_paused(
+        &self,
+        currency_id: &Address,
+        paused: bool,
+        reason: Option<&str>,
+    ) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO chain_state (currency_id, payouts_paused, paused_reason, created_at,
+                updated_at)
+                VALUES (?1, ?2, ?3, ?4, ?4)
+            ON CONFLICT (currency_id) DO UPDATE
+            SET payouts_paused = excluded.payouts_paused, paused_reason = excluded.paused_reason,
+                updated_at = excluded.updated_at",
+        )
+        .bind(currency_id.to_string())
+        .bind(paused)
+        .bind(reason)
+        .bind(Utc::now())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn payouts_paused(&self, currency_id: &Address) -> Result<bool> {
+        let paused: Option<bool> =
+            sqlx::query_scalar("SELECT payouts_paused FROM chain_state WHERE currency_id = ?1")
+                .bind(currency_id.to_string())
+                .fetch_optional(&self.pool)
+                .await?;
+
+        Ok(paused.unwrap_or(false))
+    }
+
+    async fn get_scan_cursor(&self, currency_id: &Address) -> Result<Option<u64>> {
+        let height: Option<Option<i64>> = sqlx::query_scalar(
+            "SELECT last_scanned_height FROM chain_state WHERE currency_id = ?1",
+        )
+        .bind(currency_id.to_string())
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(height.flatten().map(|height| height as u64))
+    }
+
+    async fn set_scan_cursor(&self, currency_id: &Address, height: u64) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO chain_state (currency_id, last_scanned_height, created_at, updated_at)
+                VALUES (?1, ?2, ?3, ?3)
+            ON CONFLICT (currency_id) DO UPDATE
+            SET last_scanned_height = excluded.last_scanned_height,
+                updated_at = excluded.updated_at",
+        )
+        .bind(currency_id.to_string())
+        .bind(height as i64)
+        .bind(Utc::now())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn post_ledger_transfer(
+        &self,
+        currency_id: &Address,
+        entries: &[ledger::Entry],
+        cashback_id: Option<&Uuid>,
+        txid: Option<&Txid>,
+        note: Option<&str>,
+    ) -> Result<Uuid> {
+        let mut tx = self.pool.begin().await?;
+        let transfer_id =
+            insert_ledger_entries(&mut tx, currency_id, entries, cashback_id, txid, note).await?;
+        tx.commit().await?;
+
+        Ok(transfer_id)
+    }
+
+    async fn ledger_balance(&self, currency_id: &Address, account: Account) -> Result<i64> {
+        let balance: i64 = sqlx::query_scalar(
+            "SELECT COALESCE(SUM(amount), 0)
+            FROM ledger_entries
+            WHERE currency_id = ?1 AND account = ?2",
+        )
+        .bind(currency_id.to_string())
+        .bind(account.as_str())
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(balance)
+    }
+
+    async fn last_reconciliation_difference(&self, currency_id: &Address) -> Result<Option<i64>> {
+        let difference = sqlx::query_scalar(
+            "SELECT difference
+            FROM reconciliations
+            WHERE currency_id = ?1
+            ORDER BY created_at DESC, id DESC
+            LIMIT 1",
+        )
+        .bind(currency_id.to_string())
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(difference)
+    }
+
+    async fn insert_reconciliation(
+        &self,
+        currency_id: &Address,
+        ledger_balance: i64,
+        wallet_balance: i64,
+    ) -> Result<()> {
+        sqlx::query(
+            "INSERT INTO reconciliations
+                (id, currency_id, ledger_balance, wallet_balance, difference, created_at)
+                VALUES (?1, ?2, ?3, ?4, ?4 - ?3, ?5)",
+        )
+        .bind(Uuid::now_v7())
+        .bind(currency_id.to_string())
+        .bind(ledger_balance)
+        .bind(wallet_balance)
+        .bind(Utc::now())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+}
+
+async fn insert_ledger_entries(
+    conn: &mut SqliteConnection,
+    currency_id: &Address,
+    entries: &[ledger::Entry],
+    cashback_id: Option<&Uuid>,
+    txid: Option<&Txid>,
+    note: Option<&str>,
+) -> Result<Uuid> {
+    storage::check_balanced(currency_id, entries)?;
+
+    let transfer_id = Uuid::now_v7();
+    let created_at = Utc::now();
+
+    for entry in entries {
+        sqlx::query(
+            "INSERT INTO ledger_entries
+                (id, currency_id, transfer_id, kind, account, amount, cashback_id, txid, note,
+                created_at)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+        )
+        .bind(Uuid::now_v7())
+        .bind(currency_id.to_string())
+        .bind(transfer_id)
+        .bind(entry.kind.as_str())
+        .bind(entry.account.as_str())
+        .bind(entry.amount)
+        .bind(cashback_id.copied())
+        .bind(txid.map(|txid| txid.to_string()))
+        .bind(note)
+        .bind(created_at)
+        .execute(&mut *conn)
+        .await?;
+    }
+
+    Ok(transfer_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{fixtures::*, ledger::EntryKind, mock_rpc};
+
+    #[tokio::test]
+    async fn stores_mempool_registrations_until_they_are_mined() {
+        let storage = Sqlite::in_memory().await.unwrap();
+
+        assert!(store(&storage, &alice(), 1, CashbackStatus::Mempool, None).await);
+        assert!(!store(&storage, &alice(), 1, CashbackStatus::Mempool, None).await);
+
+        let seen = cashback(&storage, &alice()).await;
+        assert_eq!(seen.status, CashbackStatus::Mempool);
+        assert_eq!(seen.detected_block_height, None);
+        assert!(storage
+            .get_pending_cashbacks(&chain_id())
+            .await
+            .unwrap()
+            .is_empty());
+
+        assert!(store(&storage, &alice(), 1, CashbackStatus::Pending, Some(100)).await);
+        assert!(!store(&storage, &alice(), 1, CashbackStatus::Pending, Some(100)).await);
+
+        let mined = cashback(&storage, &alice()).await;
+        assert_eq!(mined.id, seen.id);
+        assert_eq!(mined.status, CashbackStatus::Pending);
+        assert_eq!(mined.registration_txid, Some(mock_rpc::txid(1)));
+        assert_eq!(mined.detected_block_height, Some(100));
+        assert_eq!(mined.detected_block_hash, Some(block_hash(100)));
+
+        let pending = storage.get_pending_cashbacks(&chain_id()).await.unwrap();
+        assert_eq!(pending.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn holds_a_payout_while_it_is_being_sent() {
+        let storage = Sqlite::in_memory().await.unwrap();
+
+        let sending = sending(&storage).await;
+        assert_eq!(sending.status, CashbackStatus::Sending);
+        assert_eq!(sending.amount, Some(9_000_000));
+        assert!(storage
+            .get_pending_cashbacks(&chain_id())
+            .await
+            .unwrap()
+            .is_empty());
+
+        storage
+            .set_payout_opid(&sending.id, "opid-0")
+            .await
+            .unwrap();
+        storage
+            .record_payout_error(&sending.id, "sendcurrency timed out")
+            .await
+            .unwrap();
+
+        let unknown = storage.get_sending_payouts(&chain_id()).await.unwrap();
+        assert_eq!(unknown.len(), 1);
+        assert_eq!(unknown[0].opid.as_deref(), Some("opid-0"));
+        assert_eq!(
+            unknown[0].last_error.as_deref(),
+            Some("sendcurrency timed out")
+        );
+        // Still the time the payout started, the send can't be older.
+        assert!(sending.sending_at.is_some());
+        assert_eq!(unknown[0].sending_at, sending.sending_at);
+
+        // Whether it was sent is not known, so it can't be paid again.
+        assert!(!storage.retry_cashback(&sending.id).await.unwrap());
+        assert!(!storage.skip_cashback(&sending.id, "no").await.unwrap());
+
+        storage
+            .mark_cashback_failed(&sending.id, "rejected")
+            .await
+            .unwrap();
+        let failed = cashback(&storage, &alice()).await;
+        assert_eq!(failed.status, CashbackStatus::Failed);
+        assert_eq!(failed.attempts, 1);
+        assert!(!storage
+            .update_cashback(&failed.id, &mock_rpc::txid(2))
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn confirms_a_broadcast_payout_once() {
+        let storage = Sqlite::in_memory().await.unwrap();
+
+        let broadcast = broadcast(&storage, 2).await;
+        assert_eq!(broadcast.status, CashbackStatus::Broadcast);
+        assert_eq!(broadcast.txid, Some(mock_rpc::txid(2)));
+        assert_eq!(broadcast.amount, Some(9_000_000));
+        assert_eq!(broadcast.fee, Some(1_000_000));
+        assert_eq!(broadcast.payout_currency, Some(chain_id()));
+        assert!(storage
+            .get_pending_cashbacks(&chain_id())
+            .await
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            storage
+                .get_broadcast_payouts(&chain_id())
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+
+        let entries = payout_entries();
+        assert!(storage
+            .confirm_payout(&broadcast, 110, &entries)
+            .await
+            .unwrap());
+        assert!(!storage
+            .confirm_payout(&broadcast, 110, &entries)
+            .await
+            .unwrap());
+
+        let paid = cashback(&storage, &alice()).await;
+        assert_eq!(paid.status, CashbackStatus::Paid);
+        assert_eq!(paid.payout_block_height, Some(110));
+        assert!(paid.paid_at.is_some());
+        assert!(storage
+            .get_broadcast_payouts(&chain_id())
+            .await
+            .unwrap()
+            .is_empty());
+
+        // Posted once.
+        assert_eq!(
+            storage
+                .ledger_balance(&chain_id(), Account::Wallet)
+                .await
+                .unwrap(),
+            -9_020_000
+        );
+    }
+
+    #[tokio::test]
+    async fn fails_a_dropped_payout_for_a_retry() {
+        let storage = Sqlite::in_memory().await.unwrap();
+        let broadcast = broadcast(&storage, 2).await;
+
+        assert!(storage
+            .mark_payout_dropped(&broadcast.id, "dropped")
+            .await
+            .unwrap());
+        assert!(!storage
+            .mark_payout_dropped(&broadcast.id, "dropped")
+            .await
+            .unwrap());
+
+        let failed = cashback(&storage, &alice()).await;
+        assert_eq!(failed.status, CashbackStatus::Failed);
+        assert_eq!(failed.attempts, 1);
+        assert_eq!(failed.last_error.as_deref(), Some("dropped"));
+
+        // A dropped payout can't be confirmed any more.
+        assert!(!storage
+            .confirm_payout(&broadcast, 110, &payout_entries())
+            .await
+            .unwrap());
+
+        assert!(storage.retry_cashback(&failed.id).await.unwrap());
+        assert!(!storage.retry_cashback(&failed.id).await.unwrap());
+
+        let retried = cashback(&storage, &alice()).await;
+        assert_eq!(retried.status, CashbackStatus::Pending);
+        assert_eq!(retried.last_error, None);
+        assert_eq!(retried.attempts, 1);
+    }
+
+    #[tokio::test]
+    async fn keeps_a_balanced_ledger() {
+        let storage = Sqlite::in_memory().await.unwrap();
+
+        let unbalanced = [ledger::Entry {
+            kind: EntryKind::TopUp,
+            account: Account::Wallet,
+            amount: 100,
+        }];
+        assert!(storage
+            .post_ledger_transfer(&chain_id(), &unbalanced, None, None, None)
+            .await
+            .is_err());
+
+        ledger::record_top_up(&storage, &chain_id(), 50_000_000, None, Some("initial"))
+            .await
+            .unwrap();
+        let broadcast = broadcast(&storage, 2).await;
+        storage
+            .confirm_payout(&broadcast, 110, &payout_entries())
+            .await
+            .unwrap();
+
+        for (account, expected) in [
+            (Account::Wallet, 40_980_000),
+            (Account::Funding, -50_000_000),
+            (Account::Identity, 9_000_000),
+            (Account::Network, 20_000),
+        ] {
+            let balance = storage.ledger_balance(&chain_id(), account).await.unwrap();
+            assert_eq!(balance, expected, "{}", account.as_str());
+        }
+        assert_eq!(
+            storage
+                .ledger_balance(&bob(), Account::Wallet)
+                .await
+                .unwrap(),
+            0
+        );
+
+        assert_eq!(
+            storage
+                .last_reconciliation_difference(&chain_id())
+                .await
+                .unwrap(),
+            None
+        );
+        storage
+            .insert_reconciliation(&chain_id(), 40_980_000, 41_000_000)
+            .await
+            .unwrap();
+        storage
+            .insert_reconciliation(&chain_id(), 40_980_000, 40_980_000)
+            .await
+            .unwrap();
+        assert_eq!(
+            storage
+                .last_reconciliation_difference(&chain_id())
+                .await
+                .unwrap(),
+            Some(0)
+        );
+    }
+
+    #[tokio::test]
+    async fn lists_cashbacks_by_status_and_time() {
+        let storage = Sqlite::in_memory().await.unwrap();
+
+        for (n, name_id) in [alice(), bob(), chain_id()].iter().enumerate() {
+            store(
+                &storage,
+                name_id,
+                n as u64,
+                CashbackStatus::Pending,
+                Some(100),
+            )
+            .await;
+            // Apart in time, so the range below splits them.
+            tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        }
+        let skipped = cashback(&storage, &bob()).await;
+        storage.skip_cashback(&skipped.id, "spam").await.unwrap();
+
+        let (all, total) = storage
+            .list_cashbacks(&chain_id(), &CashbackFilter::default(), 2, 0)
+            .await
+            .unwrap();
+        assert_eq!(total, 3);
+        assert_eq!(
+            all.iter()
+                .map(|cashback| cashback.name_id.clone())
+                .collect::<Vec<_>>(),
+            vec![chain_id(), bob()]
+        );
+
+        let filter = CashbackFilter {
+            status: Some(CashbackStatus::Pending),
+            ..Default::default()
+        };
+        let (pending, total) = storage
+            .list_cashbacks(&chain_id(), &filter, 10, 0)
+            .await
+            .unwrap();
+        assert_eq!(total, 2);
+        assert!(pending
+            .iter()
+            .all(|cashback| cashback.status == CashbackStatus::Pending));
+
+        let newest = cashback(&storage, &chain_id()).await;
+        let filter = CashbackFilter {
+            from: Some(skipped.created_at),
+            to: Some(newest.created_at),
+            ..Default::default()
+        };
+        let (range, total) = storage
+            .list_cashbacks(&chain_id(), &filter, 10, 0)
+            .await
+            .unwrap();
+        assert_eq!(total, 1);
+        assert_eq!(range[0].id, skipped.id);
+    }
+
+    #[tokio::test]
+    async fn exports_payouts_in_a_range_of_blocks() {
+        let storage = Sqlite::in_memory().await.unwrap();
+
+        store(&storage, &bob(), 1, CashbackStatus::Pending, Some(100)).await;
+        let broadcast = broadcast(&storage, 2).await;
+        storage
+            .confirm_payout(&broadcast, 110, &payout_entries())
+            .await
+            .unwrap();
+
+        let paid = |from_height, to_height| {
+            let range = PaidRange {
+                from_height,
+                to_height,
+                ..Default::default()
+            };
+            let storage = storage.clone();
+            async move { storage.get_paid_cashbacks(None, &range).await.unwrap() }
+        };
+
+        let all = paid(None, None).await;
+        assert_eq!(all.len(), 1);
+        assert_eq!(all[0].name_id, alice());
+        assert_eq!(all[0].referral_id, Some(referral_id()));
+        assert_eq!(paid(Some(110), Some(111)).await.len(), 1);
+        assert!(paid(Some(111), None).await.is_empty());
+        assert!(paid(None, Some(110)).await.is_empty());
+
+        assert!(storage
+            .get_paid_cashbacks(Some(&alice()), &PaidRange::default())
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn marks_a_payout_made_by_hand_as_manual() {
+        let storage = Sqlite::in_memory().await.unwrap();
+
+        let sending = sending(&storage).await;
+        assert!(!storage
+            .mark_cashback_paid(&sending.id, &mock_rpc::txid(2))
+            .await
+            .unwrap());
+        storage
+            .mark_cashback_failed(&sending.id, "rejected")
+            .await
+            .unwrap();
+
+        assert!(storage
+            .mark_cashback_paid(&sending.id, &mock_rpc::txid(2))
+            .await
+            .unwrap());
+        assert!(!storage
+            .mark_cashback_paid(&sending.id, &mock_rpc::txid(2))
+            .await
+            .unwrap());
+
+        let paid = cashback(&storage, &alice()).await;
+        assert_eq!(paid.status, CashbackStatus::Paid);
+        assert!(paid.paid_manually);
+        assert!(paid.paid_at.is_some());
+        assert_eq!(paid.amount, None);
+        assert_eq!(paid.fee, None);
+    }
+}
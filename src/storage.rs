@@ -0,0 +1,267 @@
+//! Persistence of cashbacks, operator actions and the ledger, behind a trait so the service can
+//! run on Postgres or on an embedded SQLite database. `[database] backend` selects one.
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+use vrsc_rpc::{
+    bitcoin::{BlockHash, Txid},
+    json::vrsc::Address,
+};
+
+use crate::{
+    config::{DbBackend, DbConfig},
+    constants::{AuditAction, AuditEntry, Cashback, CashbackStatus},
+    ledger::{self, Account},
+    postgres::Postgres,
+    sqlite::Sqlite,
+};
+
+/// The transaction and block a registration was found in.
+#[derive(Debug, Clone, Copy)]
+pub struct Registration<'a> {
+    pub txid: &'a Txid,
+    /// The identity whose referral the registration used.
+    pub referral_id: &'a Address,
+    /// Height and hash of the block, `None` while the registration is in the mempool.
+    pub block: Option<(u64, &'a BlockHash)>,
+}
+
+/// What is sent for a cashback, recorded before it is sent so later config changes don't alter
+/// the history.
+#[derive(Debug, Clone, Copy)]
+pub struct Payout<'a> {
+    /// Sent to the identity, in satoshis.
+    pub amount: u64,
+    /// Withheld from the referral reward, including the network fee, in satoshis.
+    pub fee: u64,
+    pub currency: &'a Address,
+}
+
+/// Narrows down the cashbacks of a chain. `from` is inclusive, `to` exclusive.
+#[derive(Debug, Default)]
+pub struct CashbackFilter {
+    pub status: Option<CashbackStatus>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+}
+
+/// Limits paid cashbacks to a period and/or a range of payout blocks. `from` bounds are inclusive,
+/// `to` bounds exclusive.
+#[derive(Debug, Clone, Default)]
+pub struct PaidRange {
+    pub from_date: Option<DateTime<Utc>>,
+    pub to_date: Option<DateTime<Utc>>,
+    pub from_height: Option<u64>,
+    pub to_height: Option<u64>,
+}
+
+#[async_trait]
+pub trait Storage: std::fmt::Debug + Send + Sync {
+    /// Applies the migrations embedded in this binary.
+    async fn migrate(&self) -> Result<()>;
+
+    /// Fails if the latest applied migration is not the latest migration embedded in this
+    /// binary.
+    async fn check_schema_version(&self) -> Result<()>;
+
+    async fn ping(&self) -> Result<()>;
+
+    /// Stores a cashback with `status`, and posts `entries` as a transfer of it in the same
+    /// transaction. Returns false if the identity was already known on this chain, in which case
+    /// nothing is changed, unless it was only seen in the mempool so far.
+    async fn store_cashback(
+        &self,
+        currency_id: &Address,
+        name_id: &Address,
+        name: &str,
+        status: CashbackStatus,
+        registration: Registration<'_>,
+        entries: &[ledger::Entry],
+    ) -> Result<bool>;
+
+    /// Deletes the cashbacks of registrations that were seen in the mempool before `seen_before`
+    /// and have not been mined since. Returns how many were deleted.
+    async fn delete_stale_mempool_cashbacks(
+        &self,
+        currency_id: &Address,
+        seen_before: DateTime<Utc>,
+    ) -> Result<u64>;
+
+    /// Moves a pending cashback to `sending` with what is about to be sent, before it is sent.
+    /// Returns false if the cashback was no longer pending.
+    async fn start_payout(&self, id: &Uuid, payout: Payout<'_>) -> Result<bool>;
+
+    /// Records the wallet operation that sends the payout of a cashback in `sending`.
+    async fn set_payout_opid(&self, id: &Uuid, opid: &str) -> Result<()>;
+
+    /// Records the transaction of a payout that was sent. It stays `broadcast` until it is
+    /// confirmed. Returns false if the cashback was not being sent.
+    async fn update_cashback(&self, id: &Uuid, txid: &Txid) -> Result<bool>;
+
+    /// Records why it is not known whether the payout of a cashback in `sending` was sent. It
+    /// stays `sending` until the checker finds out.
+    async fn record_payout_error(&self, id: &Uuid, error: &str) -> Result<()>;
+
+    /// Cashbacks whose payout is being sent, or was being sent when its outcome became unknown.
+    async fn get_sending_payouts(&self, currency_id: &Address) -> Result<Vec<Cashback>>;
+
+    /// Cashbacks whose payout has been sent but is not confirmed yet.
+    async fn get_broadcast_payouts(&self, currency_id: &Address) -> Result<Vec<Cashback>>;
+
+    /// Marks a broadcast payout as paid, mined at `height`, and posts its ledger entries.
+    /// Returns false if the cashback was no longer waiting for its payout to confirm.
+    async fn confirm_payout(
+        &self,
+        cashback: &Cashback,
+        height: u64,
+        entries: &[ledger::Entry],
+    ) -> Result<bool>;
+
+    async fn get_pending_cashbacks(&self, currency_id: &Address) -> Result<Vec<Cashback>>;
+
+    async fn get_cashback(&self, id: &Uuid) -> Result<Option<Cashback>>;
+
+    /// The cashbacks of an identity on every chain, oldest first.
+    async fn get_cashbacks_by_name_id(&self, name_id: &Address) -> Result<Vec<Cashback>>;
+
+    /// Looks up the cashback of an identity on a chain by its name, without the `@`. Names are
+    /// not case sensitive.
+    async fn get_cashback_by_name(
+        &self,
+        currency_id: &Address,
+        name: &str,
+    ) -> Result<Option<Cashback>>;
+
+    async fn get_chain_cashback_by_name_id(
+        &self,
+        currency_id: &Address,
+        name_id: &Address,
+    ) -> Result<Option<Cashback>>;
+
+    /// All cashbacks of a chain, or of every chain, oldest first.
+    async fn get_cashbacks(&self, currency_id: Option<&Address>) -> Result<Vec<Cashback>>;
+
+    /// Paid cashbacks of a chain, or of every chain, that were paid in `range`. Cashbacks paid
+    /// before the payout time and block were recorded only match an unbounded range.
+    async fn get_paid_cashbacks(
+        &self,
+        currency_id: Option<&Address>,
+        range: &PaidRange,
+    ) -> Result<Vec<Cashback>>;
+
+    /// Returns a page of the cashbacks of a chain, newest first, and the number of cashbacks
+    /// that match the filter.
+    async fn list_cashbacks(
+        &self,
+        currency_id: &Address,
+        filter: &CashbackFilter,
+        limit: i64,
+        offset: i64,
+    ) -> Result<(Vec<Cashback>, i64)>;
+
+    /// Number of cashbacks per chain and status.
+    async fn count_cashbacks_by_status(&self) -> Result<Vec<(Address, CashbackStatus, i64)>>;
+
+    /// Marks a payout in `sending` that is known not to have been sent as failed. Failed
+    /// cashbacks are not picked up again until an operator retries them.
+    async fn mark_cashback_failed(&self, id: &Uuid, error: &str) -> Result<()>;
+
+    /// Marks a broadcast payout that was dropped or conflicted as failed, so an operator can
+    /// retry it. Returns false if the cashback was no longer waiting for its payout to confirm.
+    async fn mark_payout_dropped(&self, id: &Uuid, error: &str) -> Result<bool>;
+
+    /// Moves a failed, skipped or historical cashback back to pending. Returns false if the
+    /// cashback was in another state.
+    async fn retry_cashback(&self, id: &Uuid) -> Result<bool>;
+
+    /// Releases a cashback that a rescan stored as historical for payout. Returns false if the
+    /// cashback was in another state.
+    async fn approve_cashback(&self, id: &Uuid) -> Result<bool>;
+
+    /// Records a payout that was made outside of this service. What was sent is not known, so
+    /// the amounts of an earlier attempt are cleared and the cashback is flagged as paid
+    /// manually. Returns false if the cashback was already paid, or its payout is being sent or
+    /// waits for confirmations.
+    async fn mark_cashback_paid(&self, id: &Uuid, txid: &Txid) -> Result<bool>;
+
+    /// Returns false if the cashback was already paid or its payout is being sent or waiting for
+    /// confirmations.
+    async fn skip_cashback(&self, id: &Uuid, reason: &str) -> Result<bool>;
+
+    async fn insert_audit(
+        &self,
+        actor: &str,
+        action: AuditAction,
+        currency_id: Option<&Address>,
+        cashback_id: Option<&Uuid>,
+        detail: Option<&str>,
+    ) -> Result<()>;
+
+    /// Returns a page of the audit log, newest first, and the number of entries that match.
+    async fn list_audit_log(
+        &self,
+        currency_id: Option<&Address>,
+        limit: i64,
+        offset: i64,
+    ) -> Result<(Vec<AuditEntry>, i64)>;
+
+    async fn set_payouts_paused(
+        &self,
+        currency_id: &Address,
+        paused: bool,
+        reason: Option<&str>,
+    ) -> Result<()>;
+
+    async fn payouts_paused(&self, currency_id: &Address) -> Result<bool>;
+
+    /// Returns the height of the last block that was scanned for referrals on this chain.
+    async fn get_scan_cursor(&self, currency_id: &Address) -> Result<Option<u64>>;
+
+    async fn set_scan_cursor(&self, currency_id: &Address, height: u64) -> Result<()>;
+
+    /// Posts the entries of one transfer, which must sum to zero. Returns the id of the
+    /// transfer.
+    async fn post_ledger_transfer(
+        &self,
+        currency_id: &Address,
+        entries: &[ledger::Entry],
+        cashback_id: Option<&Uuid>,
+        txid: Option<&Txid>,
+        note: Option<&str>,
+    ) -> Result<Uuid>;
+
+    /// Sum of the entries of an account, in satoshis.
+    async fn ledger_balance(&self, currency_id: &Address, account: Account) -> Result<i64>;
+
+    async fn last_reconciliation_difference(&self, currency_id: &Address) -> Result<Option<i64>>;
+
+    async fn insert_reconciliation(
+        &self,
+        currency_id: &Address,
+        ledger_balance: i64,
+        wallet_balance: i64,
+    ) -> Result<()>;
+}
+
+/// Opens the configured database. Postgres connects lazily, the SQLite file is created when it
+/// does not exist.
+pub async fn connect(config: &DbConfig) -> Result<Arc<dyn Storage>> {
+    Ok(match config.backend {
+        DbBackend::Postgres => Arc::new(Postgres::connect(config)?),
+        DbBackend::Sqlite => Arc::new(Sqlite::connect(config).await?),
+    })
+}
+
+/// Fails when the entries of a transfer don't sum to zero.
+pub fn check_balanced(currency_id: &Address, entries: &[ledger::Entry]) -> Result<()> {
+    let sum = entries.iter().map(|entry| entry.amount).sum::<i64>();
+    if sum != 0 {
+        anyhow::bail!("ledger entries of {currency_id} don't balance, they sum to {sum}");
+    }
+
+    Ok(())
+}
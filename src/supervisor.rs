@@ -0,0 +1,293 @@
+//! Keeps the checker of a chain running. A checker that fails or panics is restarted with
+//! backoff, together with its listeners, and Discord is alerted when it keeps failing.
+//!
+//! [`Chains`] starts and stops the supervisors as the pbaas configs are reloaded.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use poise::serenity_prelude::futures::future::join_all;
+use tokio::{
+    sync::{mpsc, watch},
+    task::JoinHandle,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::*;
+use vrsc_rpc::json::vrsc::Address;
+
+use crate::{
+    checker::CashbackChecker,
+    config::pbaas,
+    discord::DiscordMessage,
+    readiness::Readiness,
+    rpc::Client,
+    storage::Storage,
+    telemetry,
+    zmq::{BlockSource, ZMQMessage},
+    ZMQ_QUEUE_CAPACITY,
+};
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+/// A checker that ran at least this long is considered to have recovered, the next failure
+/// starts the backoff over.
+const HEALTHY_RUN: Duration = Duration::from_secs(600);
+/// Consecutive failures after which Discord is alerted.
+const ALERT_THRESHOLD: u32 = 3;
+
+/// The supervisors of the configured chains, by currency id.
+pub struct Chains {
+    storage: Arc<dyn Storage>,
+    discord_tx: mpsc::Sender<DiscordMessage>,
+    shutdown: CancellationToken,
+    readiness: Readiness,
+    running: HashMap<Address, Chain>,
+    /// Supervisors that were told to stop but may still be finishing a payout.
+    stopping: HashMap<Address, JoinHandle<()>>,
+}
+
+struct Chain {
+    config: watch::Sender<pbaas::Config>,
+    /// Stops this chain only, a child of the shutdown token.
+    stop: CancellationToken,
+    handle: JoinHandle<()>,
+}
+
+impl Chains {
+    pub fn new(
+        storage: Arc<dyn Storage>,
+        discord_tx: mpsc::Sender<DiscordMessage>,
+        shutdown: CancellationToken,
+        readiness: Readiness,
+    ) -> Self {
+        Self {
+            storage,
+            discord_tx,
+            shutdown,
+            readiness,
+            running: HashMap::new(),
+            stopping: HashMap::new(),
+        }
+    }
+
+    /// Brings the running chains in line with `configs`: chains without a config are stopped,
+    /// new ones are started and changed ones are restarted, unless only their reward amounts
+    /// changed. Those are picked up by the running checker. Chains whose config did not change
+    /// are left alone.
+    pub fn reload(&mut self, configs: Vec<pbaas::Config>) {
+        let mut configs_by_chain = HashMap::new();
+        // A chain with an invalid config keeps running with the config it has.
+        let mut rejected = HashSet::new();
+
+        for config in configs {
+            if let Err(e) = config.check_rewards() {
+                error!("{e}, not loading this config");
+                rejected.insert(config.currency_id);
+                continue;
+            }
+
+            if let Some(previous) = configs_by_chain.insert(config.currency_id.clone(), config) {
+                warn!(
+                    "{}: configured more than once, using the last config",
+                    previous.currency_id
+                );
+            }
+        }
+
+        let removed = self
+            .running
+            .keys()
+            .filter(|currency_id| {
+                !configs_by_chain.contains_key(*currency_id) && !rejected.contains(*currency_id)
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for currency_id in removed {
+            info!("{currency_id}: config removed, stopping");
+            self.stop(&currency_id);
+        }
+
+        for (currency_id, config) in configs_by_chain {
+            match self.running.get(&currency_id) {
+                None => {
+                    info!("{currency_id}: starting");
+                    self.start(config);
+                }
+                Some(chain) if chain.config.borrow().differs_beyond_rewards(&config) => {
+                    info!("{currency_id}: config changed, restarting");
+                    self.stop(&currency_id);
+                    self.start(config);
+                }
+                Some(chain) => {
+                    chain.config.send_if_modified(|current| {
+                        if current.referral_amount == config.referral_amount
+                            && current.fee == config.fee
+                        {
+                            return false;
+                        }
+
+                        info!(
+                            "{currency_id}: referral_amount {} -> {}, fee {} -> {}",
+                            current.referral_amount,
+                            config.referral_amount,
+                            current.fee,
+                            config.fee
+                        );
+                        *current = config;
+
+                        true
+                    });
+                }
+            }
+        }
+    }
+
+    fn start(&mut self, config: pbaas::Config) {
+        let currency_id = config.currency_id.clone();
+        let zmq_stale_after = match BlockSource::from(&config) {
+            BlockSource::Zmq { stale_after, .. } => Some(stale_after),
+            BlockSource::Poll { .. } => None,
+        };
+        self.readiness
+            .register(currency_id.clone(), zmq_stale_after);
+
+        let (config_tx, config_rx) = watch::channel(config);
+        let stop = self.shutdown.child_token();
+
+        let supervisor = supervise(
+            self.storage.clone(),
+            config_rx,
+            self.discord_tx.clone(),
+            stop.clone(),
+            self.readiness.clone(),
+        );
+
+        // A previous supervisor of this chain may still be finishing a payout, two checkers
+        // for the same chain could pay the same cashback.
+        let handle = match self.stopping.remove(&currency_id) {
+            Some(previous) => tokio::spawn(async move {
+                let _ = previous.await;
+                supervisor.await
+            }),
+            None => tokio::spawn(supervisor),
+        };
+
+        self.running.insert(
+            currency_id,
+            Chain {
+                config: config_tx,
+                stop,
+                handle,
+            },
+        );
+    }
+
+    fn stop(&mut self, currency_id: &Address) {
+        if let Some(chain) = self.running.remove(currency_id) {
+            self.readiness.unregister(currency_id);
+            chain.stop.cancel();
+            self.stopping.insert(currency_id.clone(), chain.handle);
+        }
+    }
+
+    /// Waits for every supervisor to return, after the shutdown token was cancelled.
+    pub async fn join(self) {
+        // The checkers hold the other senders, Discord flushes its queue once they are gone.
+        drop(self.discord_tx);
+
+        let handles = self
+            .running
+            .into_values()
+            .map(|chain| chain.handle)
+            .chain(self.stopping.into_values());
+
+        join_all(handles).await;
+    }
+}
+
+#[instrument(level = "trace", skip_all, fields(chain = config.borrow().currency_id.to_string()))]
+pub async fn supervise(
+    storage: Arc<dyn Storage>,
+    config: watch::Receiver<pbaas::Config>,
+    discord_tx: mpsc::Sender<DiscordMessage>,
+    shutdown: CancellationToken,
+    readiness: Readiness,
+) {
+    let currency_id = config.borrow().currency_id.clone();
+    let mut backoff = INITIAL_BACKOFF;
+    let mut failures = 0u32;
+    let mut restarts = 0u32;
+
+    loop {
+        let started = Instant::now();
+
+        // A fresh channel per run, so nothing of a failed run is left in the queue.
+        let (tx, rx) = mpsc::channel::<ZMQMessage>(ZMQ_QUEUE_CAPACITY);
+        let block_source = BlockSource::from(&*config.borrow());
+
+        let client = Client::try_from(config.borrow().clone());
+
+        // Spawned so a panic ends up here as an error instead of taking the chain down silently.
+        let result = match client {
+            Ok(client) => {
+                let checker = CashbackChecker::new(
+                    storage.clone(),
+                    Arc::new(client),
+                    config.clone(),
+                    rx,
+                    discord_tx.clone(),
+                    readiness.clone(),
+                );
+
+                match tokio::spawn(checker.run(tx, block_source, shutdown.clone())).await {
+                    Ok(result) => result,
+                    Err(e) => Err(anyhow::Error::new(e).context("checker panicked")),
+                }
+            }
+            Err(e) => Err(e),
+        };
+
+        if shutdown.is_cancelled() {
+            if let Err(e) = result {
+                error!("{currency_id}: checker failed while shutting down: {e:?}");
+            }
+
+            return;
+        }
+
+        let error = match result {
+            Ok(()) => anyhow::anyhow!("checker stopped"),
+            Err(e) => e,
+        };
+
+        if started.elapsed() >= HEALTHY_RUN {
+            backoff = INITIAL_BACKOFF;
+            failures = 0;
+        }
+        failures += 1;
+        restarts += 1;
+
+        error!("{currency_id}: checker failed, restarting in {backoff:?}: {error:?}");
+        telemetry::checker_restarted(&currency_id);
+
+        if failures == ALERT_THRESHOLD {
+            let alert =
+                DiscordMessage::ChainFailing(currency_id.clone(), restarts, format!("{error:#}"));
+
+            if let Err(e) = discord_tx.try_send(alert) {
+                warn!("failed to queue alert for discord: {e}");
+                telemetry::discord_message_dropped();
+            }
+        }
+
+        tokio::select! {
+            _ = shutdown.cancelled() => return,
+            _ = tokio::time::sleep(backoff) => {}
+        }
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
@@ -0,0 +1,111 @@
+//! Metrics of the cashback pipeline, recorded through the `metrics` facade and served in the
+//! Prometheus format on `/metrics`.
+
+use std::time::Duration;
+
+use anyhow::Result;
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use vrsc_rpc::json::vrsc::Address;
+
+const RPC_DURATION: &str = "cashback_rpc_duration_seconds";
+const RPC_DURATION_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+const UPKEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Installs the Prometheus recorder. Metrics recorded before this are lost.
+pub fn install() -> Result<PrometheusHandle> {
+    let handle = PrometheusBuilder::new()
+        .set_buckets_for_metric(Matcher::Full(RPC_DURATION.to_owned()), RPC_DURATION_BUCKETS)?
+        .install_recorder()?;
+
+    // Without the exporter's own http listener, histograms have to be drained by hand.
+    let upkeep = handle.clone();
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(UPKEEP_INTERVAL);
+        loop {
+            ticker.tick().await;
+            upkeep.run_upkeep();
+        }
+    });
+
+    Ok(handle)
+}
+
+/// A block was scanned for referrals.
+pub fn block_scanned(chain: &Address, height: u64) {
+    metrics::counter!("cashback_blocks_scanned_total", "chain" => chain.to_string()).increment(1);
+    metrics::gauge!("cashback_last_scanned_height", "chain" => chain.to_string())
+        .set(height as f64);
+}
+
+/// A registration that used the referral was seen for the first time.
+pub fn referral_detected(chain: &Address) {
+    metrics::counter!("cashback_referrals_detected_total", "chain" => chain.to_string())
+        .increment(1);
+}
+
+/// A cashback of `amount` satoshis was sent to the registrant.
+pub fn cashback_paid(chain: &Address, amount: u64) {
+    metrics::counter!("cashback_paid_total", "chain" => chain.to_string()).increment(1);
+    metrics::counter!("cashback_paid_amount_sats_total", "chain" => chain.to_string())
+        .increment(amount);
+}
+
+/// Number of cashbacks waiting to be paid.
+pub fn set_pending_cashbacks(chain: &Address, pending: usize) {
+    metrics::gauge!("cashback_pending", "chain" => chain.to_string()).set(pending as f64);
+}
+
+/// A payout failed and the cashback was marked failed.
+pub fn payout_failed(chain: &Address) {
+    metrics::counter!("cashback_payout_failures_total", "chain" => chain.to_string()).increment(1);
+}
+
+/// What the wallet has more than the ledger accounts for, in satoshis.
+pub fn set_ledger_difference(chain: &Address, difference: i64) {
+    metrics::gauge!("cashback_ledger_difference_sats", "chain" => chain.to_string())
+        .set(difference as f64);
+}
+
+/// How long a call to the daemon took, including calls that failed or timed out.
+pub fn rpc_call(chain: &Address, method: &'static str, duration: Duration) {
+    metrics::histogram!(RPC_DURATION, "chain" => chain.to_string(), "method" => method)
+        .record(duration.as_secs_f64());
+}
+
+/// A ZMQ socket is reconnected after it failed or stalled.
+pub fn zmq_reconnected(chain: &Address) {
+    metrics::counter!("cashback_zmq_reconnects_total", "chain" => chain.to_string()).increment(1);
+}
+
+/// Number of notifications waiting for a checker.
+pub fn set_zmq_queue_depth(chain: &Address, depth: usize) {
+    metrics::gauge!("cashback_zmq_queue_depth", "chain" => chain.to_string()).set(depth as f64);
+}
+
+/// A mempool transaction was dropped because the checker could not keep up.
+pub fn zmq_message_dropped(chain: &Address) {
+    metrics::counter!("cashback_zmq_dropped_total", "chain" => chain.to_string()).increment(1);
+}
+
+/// Number of messages waiting to be sent to Discord.
+pub fn set_discord_queue_depth(depth: usize) {
+    metrics::gauge!("cashback_discord_queue_depth").set(depth as f64);
+}
+
+/// A message was dropped because Discord could not keep up.
+pub fn discord_message_dropped() {
+    metrics::counter!("cashback_discord_dropped_total").increment(1);
+}
+
+/// A message could not be delivered to the Discord channel of a chain.
+pub fn discord_delivery_failed(chain: &Address) {
+    metrics::counter!("cashback_discord_delivery_failures_total", "chain" => chain.to_string())
+        .increment(1);
+}
+
+/// A checker stopped and was restarted by its supervisor.
+pub fn checker_restarted(chain: &Address) {
+    metrics::counter!("cashback_checker_restarts_total", "chain" => chain.to_string()).increment(1);
+}
@@ -1,43 +1,281 @@
-use std::str::FromStr;
+use std::{str::FromStr, sync::Arc, time::Duration};
 
 use anyhow::Result;
 use poise::serenity_prelude::futures::StreamExt;
-use tokio::sync::mpsc::UnboundedSender;
+use tmq::Multipart;
+use tokio::sync::mpsc::{error::TrySendError, Sender};
+use tokio_util::sync::CancellationToken;
 use tracing::*;
-use vrsc_rpc::bitcoin::BlockHash;
+use vrsc_rpc::{bitcoin::BlockHash, json::vrsc::Address};
 
-#[instrument(level = "trace")]
+use crate::{chain::ChainRpc, config::pbaas, telemetry};
+
+const INITIAL_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+/// Where a checker learns about new blocks from.
+#[derive(Debug, Clone)]
+pub enum BlockSource {
+    Zmq {
+        url: String,
+        /// The socket is considered stalled when no block arrives for this long.
+        stale_after: Duration,
+        /// How often the tip is checked while the socket is stalled.
+        poll_interval: Duration,
+    },
+    /// Polls `getbestblockhash`, for chains without ZMQ.
+    Poll { interval: Duration },
+}
+
+impl From<&pbaas::Config> for BlockSource {
+    fn from(config: &pbaas::Config) -> Self {
+        let poll_interval = Duration::from_secs(config.poll_interval_secs);
+
+        match &config.zmq_block_hash_url {
+            Some(url) => BlockSource::Zmq {
+                url: url.clone(),
+                stale_after: Duration::from_secs(config.block_time_secs * config.zmq_stale_blocks),
+                poll_interval,
+            },
+            None => BlockSource::Poll {
+                interval: poll_interval,
+            },
+        }
+    }
+}
+
+pub async fn listen(
+    tx: Sender<ZMQMessage>,
+    source: BlockSource,
+    chain: Address,
+    rpc: Arc<dyn ChainRpc>,
+    shutdown: CancellationToken,
+) -> Result<()> {
+    match source {
+        BlockSource::Zmq {
+            url,
+            stale_after,
+            poll_interval,
+        } => {
+            listen_block_notifications(tx, &url, chain, stale_after, poll_interval, shutdown).await
+        }
+        BlockSource::Poll { interval } => {
+            poll_block_notifications(tx, chain, rpc, interval, shutdown).await
+        }
+    }
+}
+
+/// Listens for `hashblock` notifications, reconnecting with backoff when the socket fails.
+///
+/// If no block arrives within `stale_after`, the socket is reconnected and the checker is told
+/// to check the tip itself, which it keeps being told every `poll_interval` until notifications
+/// arrive again. Returns once `shutdown` is cancelled.
+#[instrument(level = "trace", skip(tx, shutdown))]
 pub async fn listen_block_notifications(
-    tx: UnboundedSender<ZMQMessage>,
+    tx: Sender<ZMQMessage>,
     url: &str,
-    // chain: Address,
+    chain: Address,
+    stale_after: Duration,
+    poll_interval: Duration,
+    shutdown: CancellationToken,
+) -> Result<()> {
+    let mut delay = INITIAL_RECONNECT_DELAY;
+    let mut stale = false;
+
+    loop {
+        match tmq::subscribe(&tmq::Context::new())
+            .connect(url)
+            .and_then(|socket| socket.subscribe(b"hashblock"))
+        {
+            Ok(mut socket) => {
+                info!("listening on {url}");
+
+                loop {
+                    let timeout = if stale { poll_interval } else { stale_after };
+
+                    let next = tokio::select! {
+                        _ = shutdown.cancelled() => return Ok(()),
+                        next = tokio::time::timeout(timeout, socket.next()) => next,
+                    };
+
+                    match next {
+                        Ok(Some(Ok(msg))) => {
+                            delay = INITIAL_RECONNECT_DELAY;
+
+                            if let Some(block_hash) = block_hash(msg)? {
+                                if stale {
+                                    info!("block notifications on {url} resumed");
+                                    stale = false;
+                                }
+
+                                tx.send(ZMQMessage::NewBlock(block_hash)).await?;
+                            } else {
+                                error!("not a valid message!");
+                            }
+                        }
+                        Ok(Some(Err(e))) => {
+                            warn!("socket error on {url}: {e}");
+                            break;
+                        }
+                        Ok(None) => {
+                            warn!("socket on {url} closed");
+                            break;
+                        }
+                        Err(_) => {
+                            tx.send(ZMQMessage::Stale).await?;
+
+                            if !stale {
+                                warn!(
+                                    "no block notification on {url} for {timeout:?}, reconnecting"
+                                );
+                                stale = true;
+                                break;
+                            }
+                        }
+                    }
+                }
+            }
+            Err(e) => warn!("failed to subscribe to {url}: {e}"),
+        }
+
+        telemetry::zmq_reconnected(&chain);
+
+        tokio::select! {
+            _ = shutdown.cancelled() => return Ok(()),
+            _ = tokio::time::sleep(delay) => {}
+        }
+        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+    }
+}
+
+/// Sends a notification whenever the best block hash of the daemon changes.
+#[instrument(level = "trace", skip(tx, rpc, shutdown), fields(chain = chain.to_string()))]
+pub async fn poll_block_notifications(
+    tx: Sender<ZMQMessage>,
+    chain: Address,
+    rpc: Arc<dyn ChainRpc>,
+    interval: Duration,
+    shutdown: CancellationToken,
 ) -> Result<()> {
-    let mut socket = tmq::subscribe(&tmq::Context::new())
-        .connect(url.as_ref())?
-        .subscribe(b"hash")?;
+    let mut best_block_hash = None;
+    let mut ticker = tokio::time::interval(interval);
 
-    info!("listening on {url}");
+    info!("polling for new blocks every {interval:?}");
 
     loop {
-        if let Some(Ok(msg)) = socket.next().await {
-            if let Some(hash) = msg.into_iter().nth(1) {
-                let block_hash = hash
-                    .iter()
-                    .map(|byte| format!("{:02x}", *byte))
-                    .collect::<Vec<_>>()
-                    .join("");
-
-                tx.send(ZMQMessage::NewBlock(BlockHash::from_str(&block_hash)?))?;
-            } else {
-                error!("not a valid message!");
+        tokio::select! {
+            _ = shutdown.cancelled() => return Ok(()),
+            _ = ticker.tick() => {}
+        }
+
+        match rpc.best_block_hash().await {
+            Ok(block_hash) if best_block_hash != Some(block_hash) => {
+                best_block_hash = Some(block_hash);
+                tx.send(ZMQMessage::NewBlock(block_hash)).await?;
             }
-        } else {
-            error!("no correct message received");
+            Ok(_) => {}
+            // The checker reports an unhealthy daemon, no need to repeat that every interval.
+            Err(e) => debug!("failed to poll best block hash: {e:#}"),
         }
     }
 }
 
+/// Listens for `rawtx` notifications of transactions entering the mempool, reconnecting with
+/// backoff when the socket fails. The mempool can be quiet for long, so there is no stall
+/// detection.
+#[instrument(level = "trace", skip(tx, shutdown))]
+pub async fn listen_tx_notifications(
+    tx: Sender<ZMQMessage>,
+    url: &str,
+    chain: Address,
+    shutdown: CancellationToken,
+) -> Result<()> {
+    let mut delay = INITIAL_RECONNECT_DELAY;
+
+    loop {
+        match tmq::subscribe(&tmq::Context::new())
+            .connect(url)
+            .and_then(|socket| socket.subscribe(b"rawtx"))
+        {
+            Ok(mut socket) => {
+                info!("listening for mempool transactions on {url}");
+
+                loop {
+                    let msg = tokio::select! {
+                        _ = shutdown.cancelled() => return Ok(()),
+                        msg = socket.next() => match msg {
+                            Some(msg) => msg,
+                            None => break,
+                        },
+                    };
+
+                    match msg {
+                        Ok(msg) => {
+                            delay = INITIAL_RECONNECT_DELAY;
+
+                            let mut frames = msg.into_iter();
+                            if frames.next().as_deref() != Some(b"rawtx".as_slice()) {
+                                continue;
+                            }
+
+                            if let Some(raw_tx) = frames.next() {
+                                // Mempool detection is best effort, drop transactions instead of
+                                // holding up the socket when the checker is busy.
+                                match tx.try_send(ZMQMessage::RawTx(raw_tx.to_vec())) {
+                                    Ok(()) => {}
+                                    Err(TrySendError::Full(_)) => {
+                                        trace!("checker is busy, dropping mempool transaction");
+                                        telemetry::zmq_message_dropped(&chain);
+                                    }
+                                    Err(e @ TrySendError::Closed(_)) => return Err(e.into()),
+                                }
+                            }
+                        }
+                        Err(e) => {
+                            warn!("socket error on {url}: {e}");
+                            break;
+                        }
+                    }
+                }
+            }
+            Err(e) => warn!("failed to subscribe to {url}: {e}"),
+        }
+
+        telemetry::zmq_reconnected(&chain);
+
+        tokio::select! {
+            _ = shutdown.cancelled() => return Ok(()),
+            _ = tokio::time::sleep(delay) => {}
+        }
+        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+    }
+}
+
+fn block_hash(msg: Multipart) -> Result<Option<BlockHash>> {
+    let mut frames = msg.into_iter();
+
+    if frames.next().as_deref() != Some(b"hashblock".as_slice()) {
+        return Ok(None);
+    }
+
+    if let Some(hash) = frames.next() {
+        let block_hash = hash
+            .iter()
+            .map(|byte| format!("{:02x}", *byte))
+            .collect::<Vec<_>>()
+            .join("");
+
+        return Ok(Some(BlockHash::from_str(&block_hash)?));
+    }
+
+    Ok(None)
+}
+
 #[derive(Debug)]
 pub enum ZMQMessage {
     NewBlock(BlockHash),
+    /// No block notification arrived for a while, the tip should be checked.
+    Stale,
+    /// A serialized transaction that entered the mempool.
+    RawTx(Vec<u8>),
 }